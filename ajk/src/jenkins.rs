@@ -1,28 +1,49 @@
 use anyhow::{anyhow, Result};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
 use hyper::{Body, Client, Request, Uri};
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
 use hyper_tls::HttpsConnector;
+use jk::jenkins::locale::{self, Transcoder};
+use jk::jenkins::socks;
+use jk::jenkins::Code;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_socks::TargetAddr;
 use hyper::body::HttpBody as _;
 
 type AResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 pub async fn run(cfg: Server, args: Vec<String>) -> AResult<i32> {
     let uuid = uuid::Uuid::new_v4();
-    let mut connector = ProxyConnector::new(HttpsConnector::new())?;
+    let resolver = Resolver::new(&cfg)?;
+    if let Some(socks) = SocksConnector::new(&cfg, resolver.clone())? {
+        let client = Client::builder().build(HttpsConnector::new_with_connector(socks));
+        return recv(client, &cfg, uuid, &args).await;
+    }
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    http.enforce_http(false);
+    let mut connector = ProxyConnector::new(HttpsConnector::new_with_connector(http))?;
     if let Some(ref proxy_url) = cfg.proxy {
         let proxy_uri = proxy_url.parse()?;
         connector.add_proxy(Proxy::new(Intercept::All, proxy_uri));
     }
     let client = Client::builder().build(connector);
-    Ok(recv(client, &cfg, uuid, &args).await?)
+    recv(client, &cfg, uuid, &args).await
 }
 
 fn request(cfg: &Server, uuid: &uuid::Uuid) -> Result<hyper::http::request::Builder> {
     let uri = Uri::from_maybe_shared(format!("{}/{}", cfg.url, "cli?remoting=false"))?;
-    Ok(Request::builder()
+    let builder = Request::builder()
         .method("POST")
         .uri(uri)
         .header(
@@ -34,12 +55,156 @@ fn request(cfg: &Server, uuid: &uuid::Uuid) -> Result<hyper::http::request::Buil
         )
         .header("Session", format!("{}", uuid))
         .header("Content-Type", "application/octet-stream")
-        .header("Transfer-encoding", "chunked"))
+        .header("Transfer-encoding", "chunked");
+    Ok(cfg.headers.iter().fold(builder, |builder, (name, value)| {
+        builder.header(name, value)
+    }))
+}
+
+/// Resolves hosts with getaddrinfo, but for the host of jenkins when
+/// `resolve` gives its address.
+#[derive(Clone)]
+struct Resolver {
+    pinned: Option<(String, IpAddr)>,
+    gai: GaiResolver,
+}
+
+impl Resolver {
+    fn new(cfg: &Server) -> Result<Self> {
+        let uri: Uri = cfg.url.parse()?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("no host in {}", cfg.url))?;
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        Ok(Resolver {
+            pinned: cfg
+                .resolved(host, port)?
+                .map(|addr| (host.to_string(), addr)),
+            gai: GaiResolver::new(),
+        })
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.gai.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        if let Some((host, addr)) = &self.pinned {
+            if host.eq_ignore_ascii_case(name.as_str()) {
+                // the port is set by the connector
+                let addrs = vec![SocketAddr::new(*addr, 0)];
+                return Box::pin(async move { Ok(addrs.into_iter()) });
+            }
+        }
+        let addrs = self.gai.call(name);
+        Box::pin(async move { Ok(addrs.await?.collect::<Vec<_>>().into_iter()) })
+    }
+}
+
+/// Connects through a SOCKS5 proxy, resolving hosts locally for
+/// `socks5://` urls and leaving it to the proxy for `socks5h://` ones.
+#[derive(Clone)]
+struct SocksConnector {
+    /// `HOST:PORT` of the proxy
+    proxy: String,
+    credentials: Option<(String, String)>,
+    remote_dns: bool,
+    resolver: Resolver,
+}
+
+impl SocksConnector {
+    /// The connector for the proxy of `cfg`, if it is a SOCKS5 one.
+    fn new(cfg: &Server, resolver: Resolver) -> Result<Option<Self>> {
+        let proxy: Uri = match &cfg.proxy {
+            Some(proxy) => proxy.parse()?,
+            None => return Ok(None),
+        };
+        let remote_dns = match proxy.scheme_str() {
+            Some("socks5") => false,
+            Some("socks5h") => true,
+            _ => return Ok(None),
+        };
+        let authority = proxy
+            .authority()
+            .ok_or_else(|| anyhow!("no host in proxy {}", proxy))?;
+        let credentials = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => Some(match userinfo.split_once(':') {
+                Some((username, password)) => (socks::decode(username)?, socks::decode(password)?),
+                None => (socks::decode(userinfo)?, String::new()),
+            }),
+            None => None,
+        };
+        Ok(Some(SocksConnector {
+            proxy: format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(1080)
+            ),
+            credentials,
+            remote_dns,
+            resolver,
+        }))
+    }
 }
 
+impl Service<Uri> for SocksConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = AResult<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<AResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let host = uri.host().ok_or_else(|| anyhow!("no host in {}", uri))?;
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+            let target = if connector.remote_dns {
+                TargetAddr::Domain(host.into(), port)
+            } else {
+                let addr = connector
+                    .resolver
+                    .clone()
+                    .call(host.parse()?)
+                    .await?
+                    .next()
+                    .ok_or_else(|| anyhow!("no address found for {}", host))?;
+                TargetAddr::Ip(SocketAddr::new(addr.ip(), port))
+            };
+            let stream = match &connector.credentials {
+                Some((username, password)) => {
+                    Socks5Stream::connect_with_password(
+                        connector.proxy.as_str(),
+                        target,
+                        username,
+                        password,
+                    )
+                    .await?
+                }
+                None => Socks5Stream::connect(connector.proxy.as_str(), target).await?,
+            };
+            Ok(stream.into_inner())
+        })
+    }
+}
 
 
-async fn recv<T>(client: Client<T>, cfg: &Server, uuid: uuid::Uuid, args: &Vec<String>) -> AResult<i32>
+
+async fn recv<T>(client: Client<T>, cfg: &Server, uuid: uuid::Uuid, args: &[String]) -> AResult<i32>
 where
     T: 'static + hyper::client::connect::Connect + Send + Sync + Clone,
 {
@@ -49,16 +214,16 @@ where
     let mut resp = client.request(req).await?;
 
     let scfg = cfg.clone();
-    let sargs = args.clone();
-    let (client_input, client_output) = tokio::io::duplex(1024);
+    let sargs = args.to_vec();
 
     let sender = tokio::spawn(async move {
         send(client.clone(), &scfg, uuid, &sargs).await
     });
 
+    let from = encoding(cfg)?;
     let (server_output, server_input) = tokio::io::duplex(1024);
-    let decoder = tokio::spawn(async {
-        read_all_frame(server_output).await
+    let decoder = tokio::spawn(async move {
+        read_all_frame(server_output, from).await
     });
     let copier = tokio::spawn(async move {
         copy_body(resp.body_mut(), server_input).await
@@ -77,19 +242,38 @@ async fn copy_body(body: &mut hyper::Body, mut input: tokio::io::DuplexStream) -
     Ok(())
 }
 
-async fn read_all_frame(mut output: tokio::io::DuplexStream) -> AResult<i32> {
+async fn read_all_frame(
+    mut output: tokio::io::DuplexStream,
+    from: &'static encoding_rs::Encoding,
+) -> AResult<i32> {
     let mut buf : [u8; 4] = [0; 4];
     output.read_exact(&mut buf[0..1]).await?;
     let mut stdout = tokio::io::stdout();
+    let mut transcoder = Transcoder::new(from, locale::local_encoding());
     loop {
         let f = read_frame(&mut output).await?;
         match &f.op {
             Code::Stderr | Code::Stdout => {
-                stdout.write_all(&f.data).await?;
+                let data = match &mut transcoder {
+                    Some(transcoder) => transcoder.convert(f.op, &f.data, false),
+                    None => f.data,
+                };
+                stdout.write_all(&data).await?;
                 stdout.flush().await?;
             }
             Code::Exit => {
-                let exit_code = i32::from_be_bytes(f.data[0..4].try_into()?);
+                let code = f
+                    .data
+                    .get(0..4)
+                    .ok_or_else(|| anyhow!("exit frame too short"))?;
+                let exit_code = i32::from_be_bytes(code.try_into()?);
+                // the characters the output ended in the middle of
+                if let Some(transcoder) = &mut transcoder {
+                    for op in [Code::Stdout, Code::Stderr] {
+                        stdout.write_all(&transcoder.convert(op, &[], true)).await?;
+                    }
+                    stdout.flush().await?;
+                }
                 return Ok(exit_code);
             }
             _ => {
@@ -123,17 +307,26 @@ where
             encoder.string(Code::Arg, arg)?;
         }
     }
-    encoder.string(Code::Encoding, "utf-8")?;
-    encoder.string(Code::Locale, "en")?;
+    encoder.string(Code::Encoding, encoding(cfg)?.name())?;
+    encoder.string(Code::Locale, &locale::locale(cfg.locale.as_deref()))?;
     encoder.op(Code::Start)?;
 
-    let req = request(&cfg, &uuid)?
+    let req = request(cfg, &uuid)?
         .header("Side", "upload")
         .body(buf.into())?;
     let _resp = input_client.request(req).await?;
     Ok(())
 }
 
+/// The encoding jenkins writes the output in: the configured one, or that
+/// of the local terminal.
+fn encoding(cfg: &Server) -> Result<&'static encoding_rs::Encoding> {
+    match &cfg.encoding {
+        Some(label) => locale::encoding(label),
+        None => Ok(locale::local_encoding()),
+    }
+}
+
 async fn read_frame<TReader: AsyncRead + Unpin>(r: &mut TReader) -> Result<Frame> {
     let mut buf = [0; 4];
     r.read_exact(&mut buf).await?;
@@ -142,8 +335,13 @@ async fn read_frame<TReader: AsyncRead + Unpin>(r: &mut TReader) -> Result<Frame
     r.read_exact(&mut buf[0..1]).await?;
     let op = buf[0].try_into()?;
 
-    let mut data = vec![0; len];
-    r.read_exact(&mut data).await?;
+    // the buffer grows with the data actually read, whatever the length
+    // claimed by the peer
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data).await?;
+    if data.len() < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
     Ok(Frame { op, data })
 }
 
@@ -153,6 +351,24 @@ pub struct Server {
     pub username: String,
     pub password: String,
     pub proxy: Option<String>,
+    /// Locale of the messages of jenkins, taken from the environment by default
+    pub locale: Option<String>,
+    /// Encoding jenkins writes output in, that of the terminal by default
+    pub encoding: Option<String>,
+    /// Addresses to connect to instead of resolving hosts, as entries
+    /// `HOST:PORT:ADDRESS` like with `curl --resolve`
+    #[serde(default)]
+    pub resolve: Vec<String>,
+    /// Sent on every request, to get through a gateway for example
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl Server {
+    /// The address `resolve` gives for `host` and `port`, if any.
+    pub fn resolved(&self, host: &str, port: u16) -> Result<Option<IpAddr>> {
+        jk::jenkins::resolved(&self.resolve, host, port)
+    }
 }
 
 pub struct Frame {
@@ -171,46 +387,14 @@ impl fmt::Debug for Frame {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum Code {
-    Arg = 0,
-    Locale = 1,
-    Encoding = 2,
-    Start = 3,
-    Exit = 4,
-    Stdin = 5,
-    EndStdin = 6,
-    Stdout = 7,
-    Stderr = 8,
-}
-
 use std::convert::TryFrom;
 
-impl TryFrom<u8> for Code {
-    type Error = anyhow::Error;
-
-    fn try_from(i: u8) -> Result<Self> {
-        match i {
-            0 => Ok(Code::Arg),
-            1 => Ok(Code::Locale),
-            2 => Ok(Code::Encoding),
-            3 => Ok(Code::Start),
-            4 => Ok(Code::Exit),
-            5 => Ok(Code::Stdin),
-            6 => Ok(Code::EndStdin),
-            7 => Ok(Code::Stdout),
-            8 => Ok(Code::Stderr),
-            _ => Err(anyhow!("Code: unexpected value {}", i)),
-        }
-    }
-}
-
 pub struct Encoder<'a, T: std::io::Write> {
     w: &'a mut T,
 }
 //TODO: convert to AsyncWrite
 impl<T: std::io::Write> Encoder<'_, T> {
-    pub fn new(writer: &mut T) -> Encoder<T> {
+    pub fn new(writer: &mut T) -> Encoder<'_, T> {
         Encoder { w: writer }
     }
 
@@ -228,10 +412,20 @@ impl<T: std::io::Write> Encoder<'_, T> {
         })
     }
 
+    /// Writes `s` prefixed by its length, which the protocol holds in 16
+    /// bits: longer strings are an error.
     pub fn string(&mut self, op: Code, s: &str) -> Result<()> {
         let str_bytes = s.as_bytes();
+        let len = u16::try_from(str_bytes.len()).map_err(|_| {
+            anyhow!(
+                "{:?} of {} bytes too long, at most {} bytes can be sent",
+                op,
+                str_bytes.len(),
+                u16::MAX
+            )
+        })?;
         let mut data = Vec::with_capacity(2 + str_bytes.len());
-        std::io::Write::write_all(&mut data, &(str_bytes.len() as u16).to_be_bytes())?;
+        std::io::Write::write_all(&mut data, &len.to_be_bytes())?;
         std::io::Write::write_all(&mut data, str_bytes)?;
         self.frame(&Frame { op, data })
     }
@@ -18,12 +18,27 @@ struct Opts {
     /// path to config file, default to "~/.config/jk/jenkins.toml"
     #[clap(short, long)]
     config: Option<String>,
+    /// Locale of the messages of jenkins, "fr_FR" for example, default to
+    /// that of the environment
+    #[clap(long)]
+    locale: Option<String>,
+    /// Encoding jenkins writes output in, default to that of the terminal
+    #[clap(long)]
+    encoding: Option<String>,
     args: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
     default: String,
+    /// Groups of servers of jk, which ajk does not run commands on
+    #[serde(default)]
+    #[allow(dead_code)]
+    groups: HashMap<String, Vec<String>>,
+    /// Aliases of jk, which ajk does not expand
+    #[serde(default)]
+    #[allow(dead_code)]
+    alias: HashMap<String, toml::Value>,
     #[serde(flatten)]
     servers: HashMap<String, jenkins::Server>,
 }
@@ -41,10 +56,16 @@ async fn main() -> AResult<()> {
     let config = read_file(config)?;
 
     let server = opts.jenkins.unwrap_or(config.default);
-    let cfg = config
+    let mut cfg = config
         .servers
         .get(&server)
         .ok_or_else(|| anyhow!("no server {} found", server))?.clone();
+    if opts.locale.is_some() {
+        cfg.locale = opts.locale;
+    }
+    if opts.encoding.is_some() {
+        cfg.encoding = opts.encoding;
+    }
     let exit_code = jenkins::run(cfg, opts.args).await?;
     std::process::exit(exit_code);
 }
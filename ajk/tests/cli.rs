@@ -0,0 +1,219 @@
+use jenkins_mock::{MockServer, Reply, SocksProxy};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn mock() -> MockServer {
+    MockServer::builder()
+        .user("u", "p")
+        .command("who-am-i", "Reports your credential.", |inv| {
+            Reply::ok(format!("Authenticated as: {}\n", inv.user))
+        })
+        .command("fail", "Fails.", |_| Reply::error(5, "failing\n"))
+        .start()
+        .unwrap()
+}
+
+/// Writes a configuration of ajk against one server.
+fn config(url: &str, password: &str) -> PathBuf {
+    config_with(url, password, "")
+}
+
+/// Writes a configuration of ajk against one server, with `extra` appended to
+/// its table.
+fn config_with(url: &str, password: &str, extra: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "ajk-test-{}-{}.toml",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::write(
+        &path,
+        format!(
+            "default = \"mock\"\n\
+             [mock]\n\
+             url = \"{}\"\n\
+             username = \"u\"\n\
+             password = \"{}\"\n\
+             {}",
+            url, password, extra
+        ),
+    )
+    .unwrap();
+    path
+}
+
+fn ajk(config: &Path, args: &[&str], lc_all: &str) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_ajk"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env("LC_ALL", lc_all)
+        .output()
+        .unwrap();
+    let _ = fs::remove_file(config);
+    output
+}
+
+#[test]
+fn runs_command() {
+    let mock = mock();
+    let output = ajk(&config(&mock.url(), "p"), &["who-am-i"], "C.UTF-8");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Authenticated as: u\n");
+}
+
+#[test]
+fn ignores_groups_of_jk() {
+    let mock = mock();
+    let extra = "[groups]\nmocks = [\"mock\"]\n";
+    let output = ajk(
+        &config_with(&mock.url(), "p", extra),
+        &["who-am-i"],
+        "C.UTF-8",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Authenticated as: u\n");
+}
+
+#[test]
+fn ignores_aliases_of_jk() {
+    let mock = mock();
+    let extra = "[alias]\nme = \"who-am-i\"\nboth = [\"who-am-i\", \"fail\"]\n";
+    let output = ajk(
+        &config_with(&mock.url(), "p", extra),
+        &["who-am-i"],
+        "C.UTF-8",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Authenticated as: u\n");
+}
+
+#[test]
+fn forwards_exit_code() {
+    let mock = mock();
+    let output = ajk(&config(&mock.url(), "p"), &["fail"], "C.UTF-8");
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(output.stdout, b"failing\n");
+}
+
+#[test]
+fn sends_locale_of_environment() {
+    let mock = mock();
+    let output = ajk(&config(&mock.url(), "p"), &["who-am-i"], "fr_FR.UTF-8");
+    assert_eq!(output.status.code(), Some(0));
+    let sent = mock.invocations();
+    assert_eq!(sent[0].locale.as_deref(), Some("fr_FR"));
+    assert_eq!(sent[0].encoding.as_deref(), Some("UTF-8"));
+}
+
+#[test]
+fn sends_locale_and_encoding_of_flags() {
+    let mock = mock();
+    let config = config_with(&mock.url(), "p", "locale = \"de_DE\"\n");
+    let output = ajk(
+        &config,
+        &["--locale", "fr_FR", "--encoding", "ISO-8859-15", "who-am-i"],
+        "C.UTF-8",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    let sent = mock.invocations();
+    assert_eq!(sent[0].locale.as_deref(), Some("fr_FR"));
+    assert_eq!(sent[0].encoding.as_deref(), Some("ISO-8859-15"));
+}
+
+#[test]
+fn ends_output_cut_in_a_character() {
+    let mock = MockServer::builder()
+        .user("u", "p")
+        .command("cut", "Ends in the middle of a character.", |_| {
+            Reply::ok(&b"h\0i\0\xe9"[..])
+        })
+        .start()
+        .unwrap();
+    let config = config_with(&mock.url(), "p", "encoding = \"UTF-16LE\"\n");
+    let output = ajk(&config, &["cut"], "C.UTF-8");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\u{fffd}");
+}
+
+#[test]
+fn fails_on_wrong_password() {
+    let mock = mock();
+    let output = ajk(&config(&mock.url(), "wrong"), &["who-am-i"], "C.UTF-8");
+    assert_ne!(output.status.code(), Some(0));
+    assert!(mock.invocations().is_empty());
+}
+
+#[test]
+fn sends_configured_headers() {
+    let mock = MockServer::builder()
+        .user("u", "p")
+        .require_header("X-Gateway", "s3cr3t")
+        .command("who-am-i", "Reports your credential.", |inv| {
+            Reply::ok(format!("Authenticated as: {}\n", inv.user))
+        })
+        .start()
+        .unwrap();
+    let output = ajk(&config(&mock.url(), "p"), &["who-am-i"], "C.UTF-8");
+    assert_ne!(output.status.code(), Some(0));
+    let headers = "[mock.headers]\nX-Gateway = \"s3cr3t\"\n";
+    let output = ajk(
+        &config_with(&mock.url(), "p", headers),
+        &["who-am-i"],
+        "C.UTF-8",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Authenticated as: u\n");
+}
+
+#[test]
+fn connects_to_resolved_address() {
+    let mock = mock();
+    let port = mock.addr().port();
+    let url = format!("http://jenkins.invalid:{}", port);
+    let resolve = format!("resolve = [\"jenkins.invalid:{}:127.0.0.1\"]\n", port);
+    let output = ajk(&config_with(&url, "p", &resolve), &["who-am-i"], "C.UTF-8");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Authenticated as: u\n");
+}
+
+#[test]
+fn connects_through_socks_proxy() {
+    let mock = mock();
+    let port = mock.addr().port();
+    let proxy = SocksProxy::start().unwrap();
+    for (socks, host) in &[("socks5", "127.0.0.1"), ("socks5h", "localhost")] {
+        let url = format!("http://{}:{}", host, port);
+        let extra = format!("proxy = \"{}://{}\"\n", socks, proxy.addr());
+        let output = ajk(&config_with(&url, "p", &extra), &["who-am-i"], "C.UTF-8");
+        assert_eq!(output.status.code(), Some(0), "{}", socks);
+        assert_eq!(output.stdout, b"Authenticated as: u\n");
+        // the host is resolved by the proxy with socks5h
+        let target = format!("{}:{}", host, port);
+        assert_eq!(proxy.targets().last(), Some(&target));
+    }
+}
+
+#[test]
+fn authenticates_to_socks_proxy() {
+    let mock = mock();
+    let proxy = SocksProxy::with_password("dev", "s3cr@t").unwrap();
+    let extra = format!("proxy = \"socks5://dev:wrong@{}\"\n", proxy.addr());
+    let output = ajk(
+        &config_with(&mock.url(), "p", &extra),
+        &["who-am-i"],
+        "C.UTF-8",
+    );
+    assert_ne!(output.status.code(), Some(0));
+    let extra = format!("proxy = \"socks5://dev:s3cr%40t@{}\"\n", proxy.addr());
+    let output = ajk(
+        &config_with(&mock.url(), "p", &extra),
+        &["who-am-i"],
+        "C.UTF-8",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Authenticated as: u\n");
+}
@@ -0,0 +1,89 @@
+use crate::{Invocation, Reply};
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+
+pub const ARG: u8 = 0;
+pub const LOCALE: u8 = 1;
+pub const ENCODING: u8 = 2;
+pub const START: u8 = 3;
+pub const EXIT: u8 = 4;
+pub const STDOUT: u8 = 7;
+pub const STDERR: u8 = 8;
+
+/// Splits the frames of a stream: their length, op and data.
+pub fn split(mut data: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+    let mut frames = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 5 {
+            return Err(anyhow!("truncated frame"));
+        }
+        let len = u32::from_be_bytes(data[0..4].try_into()?) as usize;
+        let op = data[4];
+        let frame = data
+            .get(5..5 + len)
+            .ok_or_else(|| anyhow!("truncated frame"))?;
+        frames.push((op, frame.to_vec()));
+        data = &data[5 + len..];
+    }
+    Ok(frames)
+}
+
+/// Encodes a frame as on a stream.
+pub fn encode(op: u8, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(5 + data.len());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.push(op);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// The string of an `Arg`, `Locale` or `Encoding` frame.
+fn string(data: &[u8]) -> Result<String> {
+    let len = data
+        .get(0..2)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        .ok_or_else(|| anyhow!("string frame too short"))?;
+    let bytes = data
+        .get(2..2 + len)
+        .ok_or_else(|| anyhow!("string frame too short"))?;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+/// The command sent by the frames preceding `Start`.
+pub fn invocation(
+    frames: &[(u8, Vec<u8>)],
+    user: String,
+    endpoint: &'static str,
+) -> Result<Invocation> {
+    let mut invocation = Invocation {
+        user,
+        endpoint,
+        ..Invocation::default()
+    };
+    for (op, data) in frames {
+        match *op {
+            ARG => invocation.args.push(string(data)?),
+            LOCALE => invocation.locale = Some(string(data)?),
+            ENCODING => invocation.encoding = Some(string(data)?),
+            START => return Ok(invocation),
+            _ => {}
+        }
+    }
+    Err(anyhow!("no start frame"))
+}
+
+/// The most jenkins writes in a frame, flushing its output buffer.
+const MAX_DATA: usize = 8 * 1024;
+
+/// The frames answering a command.
+pub fn output(reply: &Reply) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = Vec::new();
+    for chunk in reply.stdout.chunks(MAX_DATA) {
+        frames.push((STDOUT, chunk.to_vec()));
+    }
+    for chunk in reply.stderr.chunks(MAX_DATA) {
+        frames.push((STDERR, chunk.to_vec()));
+    }
+    frames.push((EXIT, reply.code.to_be_bytes().to_vec()));
+    frames
+}
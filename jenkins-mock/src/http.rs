@@ -0,0 +1,221 @@
+use crate::{frame, ws, Fault, State};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+/// A request, whose body is still to be read.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// By lowercase name
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    /// Reads the head of the next request of a connection, `None` once the
+    /// client closed it.
+    fn read<R: BufRead>(r: &mut R) -> Result<Option<Request>> {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut words = line.split_whitespace();
+        let method = words.next().unwrap_or_default().to_string();
+        let mut path = words.next().unwrap_or_default().to_string();
+        // the absolute form sent to proxies, as to any server
+        if let Some((_, rest)) = path.split_once("://") {
+            path = rest[rest.find('/').unwrap_or(rest.len())..].to_string();
+        }
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            r.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+        Ok(Some(Request {
+            method,
+            path,
+            headers,
+        }))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    fn read_body<R: BufRead>(&self, r: &mut R) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        if self
+            .header("transfer-encoding")
+            .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+        {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                r.read_line(&mut line)?;
+                let size = line.trim().split(';').next().unwrap_or_default();
+                let size = usize::from_str_radix(size, 16)?;
+                if size == 0 {
+                    // trailers
+                    loop {
+                        line.clear();
+                        if r.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                            return Ok(body);
+                        }
+                    }
+                }
+                let start = body.len();
+                body.resize(start + size, 0);
+                r.read_exact(&mut body[start..])?;
+                line.clear();
+                r.read_line(&mut line)?;
+            }
+        }
+        if let Some(len) = self.header("content-length") {
+            body.resize(len.parse()?, 0);
+            r.read_exact(&mut body)?;
+        }
+        Ok(body)
+    }
+}
+
+/// Serves the requests of a connection until it is closed.
+pub fn serve(stream: TcpStream, state: &State) {
+    let _ = handle(stream, state);
+}
+
+fn handle(stream: TcpStream, state: &State) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(request) = Request::read(&mut reader)? {
+        if request.path.starts_with("/cli/ws")
+            && request
+                .header("upgrade")
+                .is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+        {
+            return ws::serve(writer, &request, state);
+        }
+        let body = request.read_body(&mut reader)?;
+        if request.method != "POST" || !request.path.starts_with("/cli") {
+            respond(&mut writer, 404)?;
+            continue;
+        }
+        let user = match state.authenticate(&request) {
+            Ok(user) => user,
+            Err(status) => {
+                respond(&mut writer, status)?;
+                continue;
+            }
+        };
+        let session = match request.header("session") {
+            Some(session) => session.to_string(),
+            None => {
+                respond(&mut writer, 400)?;
+                continue;
+            }
+        };
+        match request.header("side") {
+            Some("download") => {
+                if !download(&mut writer, &session, user, state)? {
+                    return Ok(());
+                }
+            }
+            Some("upload") => {
+                state.upload(&session, body);
+                state.wait_finish(&session);
+                respond(&mut writer, 200)?;
+            }
+            _ => respond(&mut writer, 400)?,
+        }
+    }
+    Ok(())
+}
+
+/// Runs the command of `session` once its upload side is received, and
+/// sends its output. Returns whether the connection can be kept.
+fn download<W: Write>(w: &mut W, session: &str, user: String, state: &State) -> Result<bool> {
+    let fault = state.next_fault();
+    if let Some(Fault::Status(status)) = fault {
+        respond(w, status)?;
+        return Ok(true);
+    }
+    write!(
+        w,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\r\n"
+    )?;
+    chunk(w, &[0])?;
+
+    let result = state
+        .wait_upload(session)
+        .and_then(|upload| frame::invocation(&frame::split(&upload)?, user, "http"))
+        .map(|invocation| state.run(invocation, &fault));
+    let reply = match result {
+        Ok(reply) => reply,
+        Err(err) => {
+            state.finish(session);
+            return Err(err);
+        }
+    };
+    let mut output = Vec::new();
+    for (op, data) in frame::output(&reply) {
+        output.extend_from_slice(&frame::encode(op, &data));
+    }
+    let truncated = match fault {
+        Some(Fault::Truncate(len)) if len < output.len() => {
+            output.truncate(len);
+            true
+        }
+        _ => false,
+    };
+    let result = if truncated {
+        // cut in the middle of the chunk
+        write!(w, "{:x}\r\n", output.len() + 1)
+            .and_then(|_| w.write_all(&output))
+            .and_then(|_| w.flush())
+            .map_err(anyhow::Error::from)
+    } else {
+        chunk(w, &output).and_then(|_| chunk(w, &[]))
+    };
+    state.finish(session);
+    result?;
+    Ok(!truncated)
+}
+
+/// Writes a chunk of a chunked body, the last one being empty.
+fn chunk<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    write!(w, "{:x}\r\n", data.len())?;
+    w.write_all(data)?;
+    write!(w, "\r\n")?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Answers with `status` and no body.
+pub fn respond<W: Write>(w: &mut W, status: u16) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    };
+    write!(w, "HTTP/1.1 {} {}\r\n", status, reason)?;
+    if status == 401 {
+        write!(w, "WWW-Authenticate: Basic realm=\"Jenkins\"\r\n")?;
+    }
+    write!(w, "Content-Length: 0\r\n\r\n")?;
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,461 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+mod frame;
+mod http;
+mod socks;
+mod ssh;
+mod ws;
+
+pub use socks::SocksProxy;
+pub use ssh::SshKey;
+
+/// Exit code of jenkins for unknown commands.
+pub const NO_SUCH_COMMAND: i32 = 255;
+
+type Handler = Box<dyn Fn(&Invocation) -> Reply + Send + Sync>;
+
+/// A command sent to the mock.
+#[derive(Debug, Clone, Default)]
+pub struct Invocation {
+    /// The user who sent it, empty when anonymous
+    pub user: String,
+    pub args: Vec<String>,
+    pub locale: Option<String>,
+    pub encoding: Option<String>,
+    /// `http`, `ws` or `ssh`, the endpoint it was sent to
+    pub endpoint: &'static str,
+}
+
+/// What a command answers.
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub code: i32,
+    /// How long the command runs before answering
+    pub delay: Duration,
+}
+
+impl Reply {
+    /// A command succeeding with `stdout`.
+    pub fn ok<T: Into<Vec<u8>>>(stdout: T) -> Reply {
+        Reply {
+            stdout: stdout.into(),
+            ..Reply::default()
+        }
+    }
+
+    /// A command failing with `code` and `stderr`.
+    pub fn error<T: Into<Vec<u8>>>(code: i32, stderr: T) -> Reply {
+        Reply {
+            stderr: stderr.into(),
+            code,
+            ..Reply::default()
+        }
+    }
+
+    /// Also writes `stderr`.
+    pub fn with_stderr<T: Into<Vec<u8>>>(mut self, stderr: T) -> Reply {
+        self.stderr = stderr.into();
+        self
+    }
+
+    /// Answers after `delay`, as a slow command.
+    pub fn after(mut self, delay: Duration) -> Reply {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A fault injected in the next session opened on the mock.
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Answers with this HTTP status instead of running the command
+    Status(u16),
+    /// Closes the connection once this many bytes of the output frames are
+    /// sent
+    Truncate(usize),
+    /// Waits before sending the output
+    Delay(Duration),
+    /// Closes the websocket with this code instead of sending the output
+    Close(u16),
+    /// Stops reading from the websocket, so that pings are not answered,
+    /// and never sends the output
+    Silent,
+}
+
+/// A command of the mock, as listed by `help`.
+struct Command {
+    summary: String,
+    /// Printed by `help <command>`
+    usage: Option<String>,
+    handler: Handler,
+}
+
+pub struct Builder {
+    users: HashMap<String, String>,
+    /// Values of headers, by lowercase name
+    headers: HashMap<String, String>,
+    forbidden: HashSet<String>,
+    commands: BTreeMap<String, Command>,
+    /// Public keys authorized over SSH, by user
+    ssh_keys: HashMap<String, Vec<String>>,
+}
+
+impl Builder {
+    /// Adds a user. Once there is one, the others are refused with 401.
+    pub fn user(mut self, name: &str, password: &str) -> Builder {
+        self.users.insert(name.to_string(), password.to_string());
+        self
+    }
+
+    /// Refuses the CLI to the user `name` with 403, as jenkins does for
+    /// users without the Overall/Read permission.
+    pub fn forbid(mut self, name: &str) -> Builder {
+        self.forbidden.insert(name.to_string());
+        self
+    }
+
+    /// Refuses with 403 the requests without the header `name` set to
+    /// `value`, as a gateway in front of jenkins would.
+    pub fn require_header(mut self, name: &str, value: &str) -> Builder {
+        self.headers.insert(name.to_lowercase(), value.to_string());
+        self
+    }
+
+    /// Authorizes the public key `public`, a line of `authorized_keys`, for
+    /// the user `name` over SSH, as set in their jenkins profile.
+    pub fn ssh_key(mut self, name: &str, public: &str) -> Builder {
+        self.ssh_keys
+            .entry(name.to_string())
+            .or_default()
+            .push(public.to_string());
+        self
+    }
+
+    /// Adds a command, listed by `help` with its `summary`.
+    pub fn command<F>(mut self, name: &str, summary: &str, handler: F) -> Builder
+    where
+        F: Fn(&Invocation) -> Reply + Send + Sync + 'static,
+    {
+        self.commands.insert(
+            name.to_string(),
+            Command {
+                summary: summary.to_string(),
+                usage: None,
+                handler: Box::new(handler),
+            },
+        );
+        self
+    }
+
+    /// Sets the text printed by `help <name>`, a synopsis followed by the
+    /// summary of the command by default.
+    pub fn usage(mut self, name: &str, usage: &str) -> Builder {
+        if let Some(command) = self.commands.get_mut(name) {
+            command.usage = Some(usage.to_string());
+        }
+        self
+    }
+
+    /// Starts listening on free local ports, one for HTTP and one for SSH.
+    pub fn start(self) -> Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let ssh_listener = TcpListener::bind("127.0.0.1:0")?;
+        let ssh_addr = ssh_listener.local_addr()?;
+        let mut ssh_keys = HashMap::new();
+        for (user, keys) in self.ssh_keys {
+            let keys = keys
+                .iter()
+                .map(|key| ssh::parse_public(key))
+                .collect::<Result<Vec<_>>>()?;
+            ssh_keys.insert(user, keys);
+        }
+        let state = Arc::new(State {
+            users: self.users,
+            headers: self.headers,
+            forbidden: self.forbidden,
+            commands: self.commands,
+            ssh_keys,
+            ssh_host: SshKey::generate(),
+            faults: Mutex::new(VecDeque::new()),
+            invocations: Mutex::new(Vec::new()),
+            pings: AtomicUsize::new(0),
+            sessions: Mutex::new(Sessions::default()),
+            changed: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+        let server = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if server.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = server.clone();
+                    thread::spawn(move || http::serve(stream, &state));
+                }
+            }
+        });
+        let server = state.clone();
+        thread::spawn(move || {
+            for stream in ssh_listener.incoming() {
+                if server.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = server.clone();
+                    thread::spawn(move || {
+                        let _ = ssh::serve(stream, state);
+                    });
+                }
+            }
+        });
+        Ok(MockServer {
+            addr,
+            ssh_addr,
+            state,
+        })
+    }
+}
+
+/// A jenkins controller answering the CLI protocol, over the `-http` duplex
+/// endpoint and `/cli/ws`, and its SSH server, with scripted commands.
+pub struct MockServer {
+    addr: SocketAddr,
+    ssh_addr: SocketAddr,
+    state: Arc<State>,
+}
+
+impl MockServer {
+    pub fn builder() -> Builder {
+        Builder {
+            users: HashMap::new(),
+            headers: HashMap::new(),
+            forbidden: HashSet::new(),
+            commands: BTreeMap::new(),
+            ssh_keys: HashMap::new(),
+        }
+    }
+
+    /// Address the mock listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL of the mock for the `-http` endpoint.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// URL of the mock for the websocket endpoint.
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// Address the SSH server of the mock listens on.
+    pub fn ssh_addr(&self) -> SocketAddr {
+        self.ssh_addr
+    }
+
+    /// URL of the SSH server of the mock.
+    pub fn ssh_url(&self) -> String {
+        format!("ssh://{}", self.ssh_addr)
+    }
+
+    /// The public key of the SSH server, as a line of `known_hosts` once
+    /// prefixed with the host.
+    pub fn ssh_host_key(&self) -> String {
+        self.state.ssh_host.public()
+    }
+
+    /// Injects `fault` in the next session, after the faults injected
+    /// before.
+    pub fn inject(&self, fault: Fault) {
+        self.state.faults.lock().unwrap().push_back(fault);
+    }
+
+    /// The commands received so far.
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.state.invocations.lock().unwrap().clone()
+    }
+
+    /// How many pings were received over websockets while commands ran.
+    pub fn pings(&self) -> usize {
+        self.state.pings.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // wakes up the listeners
+        let _ = TcpStream::connect(self.addr);
+        let _ = TcpStream::connect(self.ssh_addr);
+    }
+}
+
+/// The HTTP sessions in progress, by `Session` header.
+#[derive(Default)]
+struct Sessions {
+    /// Frames sent on the upload side
+    uploads: HashMap<String, Vec<u8>>,
+    /// Sessions whose command is over
+    done: HashSet<String>,
+}
+
+struct State {
+    users: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    forbidden: HashSet<String>,
+    commands: BTreeMap<String, Command>,
+    /// Public keys authorized over SSH, by user
+    ssh_keys: HashMap<String, Vec<ssh::PublicKey>>,
+    ssh_host: SshKey,
+    faults: Mutex<VecDeque<Fault>>,
+    invocations: Mutex<Vec<Invocation>>,
+    /// Pings received over websockets
+    pings: AtomicUsize,
+    sessions: Mutex<Sessions>,
+    changed: Condvar,
+    stopped: AtomicBool,
+}
+
+/// How long a side of an HTTP session waits for the other.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl State {
+    /// The user sending `request`, empty when anonymous, or the HTTP status
+    /// refusing them.
+    fn authenticate(&self, request: &http::Request) -> std::result::Result<String, u16> {
+        let gateway = self
+            .headers
+            .iter()
+            .all(|(name, value)| request.header(name) == Some(value.as_str()));
+        if !gateway {
+            return Err(403);
+        }
+        if self.users.is_empty() {
+            return Ok(String::new());
+        }
+        let credentials = request
+            .header("authorization")
+            .and_then(|auth| auth.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let (user, password) = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+            Some(credentials) => credentials,
+            None => return Err(401),
+        };
+        match self.users.get(user) {
+            Some(expected) if expected == password => {
+                if self.forbidden.contains(user) {
+                    Err(403)
+                } else {
+                    Ok(user.to_string())
+                }
+            }
+            _ => Err(401),
+        }
+    }
+
+    fn next_fault(&self) -> Option<Fault> {
+        self.faults.lock().unwrap().pop_front()
+    }
+
+    /// Runs a command, waiting as long as it or `fault` asks.
+    fn run(&self, invocation: Invocation, fault: &Option<Fault>) -> Reply {
+        self.invocations.lock().unwrap().push(invocation.clone());
+        let reply = match invocation.args.split_first() {
+            Some((name, args)) if name == "help" => self.help(args),
+            Some((name, _)) => match self.commands.get(name) {
+                Some(command) => (command.handler)(&invocation),
+                None => no_such_command(name),
+            },
+            None => no_such_command(""),
+        };
+        if let Some(Fault::Delay(delay)) = fault {
+            thread::sleep(*delay);
+        }
+        thread::sleep(reply.delay);
+        reply
+    }
+
+    /// Answers `help`, listing the commands as jenkins does, or giving the
+    /// usage of one.
+    fn help(&self, args: &[String]) -> Reply {
+        match args.first() {
+            None => {
+                let mut list = String::new();
+                for (name, command) in &self.commands {
+                    list.push_str(&format!("  {}\n    {}\n", name, command.summary));
+                }
+                Reply::error(0, list)
+            }
+            Some(name) => match self.commands.get(name) {
+                Some(Command {
+                    usage: Some(usage), ..
+                }) => Reply::error(0, usage.as_str()),
+                Some(command) => Reply::error(
+                    0,
+                    format!("java -jar jenkins-cli.jar {}\n{}\n", name, command.summary),
+                ),
+                None => no_such_command(name),
+            },
+        }
+    }
+
+    /// Stores the upload side of `session`.
+    fn upload(&self, session: &str, frames: Vec<u8>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.uploads.insert(session.to_string(), frames);
+        self.changed.notify_all();
+    }
+
+    /// Waits for the upload side of `session`.
+    fn wait_upload(&self, session: &str) -> Result<Vec<u8>> {
+        let sessions = self.sessions.lock().unwrap();
+        let (mut sessions, _) = self
+            .changed
+            .wait_timeout_while(sessions, SESSION_TIMEOUT, |s| {
+                !s.uploads.contains_key(session)
+            })
+            .unwrap();
+        sessions
+            .uploads
+            .remove(session)
+            .ok_or_else(|| anyhow!("no upload side for session {}", session))
+    }
+
+    /// Marks the command of `session` as over.
+    fn finish(&self, session: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.done.insert(session.to_string());
+        self.changed.notify_all();
+    }
+
+    /// Waits for the command of `session` to be over, as jenkins only
+    /// answers the upload side then.
+    fn wait_finish(&self, session: &str) {
+        let sessions = self.sessions.lock().unwrap();
+        let (mut sessions, _) = self
+            .changed
+            .wait_timeout_while(sessions, SESSION_TIMEOUT, |s| !s.done.contains(session))
+            .unwrap();
+        sessions.done.remove(session);
+    }
+}
+
+fn no_such_command(name: &str) -> Reply {
+    Reply::error(
+        NO_SUCH_COMMAND,
+        format!("ERROR: No such command {}\n", name),
+    )
+}
@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A SOCKS5 proxy, without authentication or with a username and password,
+/// forwarding `CONNECT` requests as `ssh -D` does.
+pub struct SocksProxy {
+    addr: SocketAddr,
+    state: Arc<ProxyState>,
+}
+
+struct ProxyState {
+    credentials: Option<(String, String)>,
+    /// The targets of the connections, as requested
+    targets: Mutex<Vec<String>>,
+    stopped: AtomicBool,
+}
+
+impl SocksProxy {
+    /// Starts a proxy accepting any client.
+    pub fn start() -> Result<SocksProxy> {
+        SocksProxy::spawn(None)
+    }
+
+    /// Starts a proxy accepting only clients authenticated as `username`.
+    pub fn with_password(username: &str, password: &str) -> Result<SocksProxy> {
+        SocksProxy::spawn(Some((username.to_string(), password.to_string())))
+    }
+
+    fn spawn(credentials: Option<(String, String)>) -> Result<SocksProxy> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(ProxyState {
+            credentials,
+            targets: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        });
+        let proxy = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if proxy.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = proxy.clone();
+                    thread::spawn(move || {
+                        let _ = serve(stream, &state);
+                    });
+                }
+            }
+        });
+        Ok(SocksProxy { addr, state })
+    }
+
+    /// Address the proxy listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The targets requested so far, as `HOST:PORT`: the host is a name when
+    /// the client left it for the proxy to resolve.
+    pub fn targets(&self) -> Vec<String> {
+        self.state.targets.lock().unwrap().clone()
+    }
+}
+
+impl Drop for SocksProxy {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // wakes up the listener
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn serve(mut client: TcpStream, state: &ProxyState) -> Result<()> {
+    let mut head = [0; 2];
+    client.read_exact(&mut head)?;
+    let mut methods = vec![0; head[1] as usize];
+    client.read_exact(&mut methods)?;
+    let method = if state.credentials.is_some() { 2 } else { 0 };
+    if head[0] != 5 || !methods.contains(&method) {
+        client.write_all(&[5, 0xff])?;
+        return Err(anyhow!("no acceptable method"));
+    }
+    client.write_all(&[5, method])?;
+    if let Some((username, password)) = &state.credentials {
+        let mut version = [0; 1];
+        client.read_exact(&mut version)?;
+        let sent = (read_string(&mut client)?, read_string(&mut client)?);
+        if (&sent.0, &sent.1) != (username, password) {
+            client.write_all(&[1, 1])?;
+            return Err(anyhow!("wrong credentials"));
+        }
+        client.write_all(&[1, 0])?;
+    }
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => read_string(&mut client)?,
+        4 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => return Err(anyhow!("unknown address type")),
+    };
+    let mut port = [0; 2];
+    client.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+    state
+        .targets
+        .lock()
+        .unwrap()
+        .push(format!("{}:{}", host, port));
+    if request[1] != 1 {
+        // command not supported
+        client.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0])?;
+        return Err(anyhow!("not a CONNECT"));
+    }
+    let target = match host.parse::<IpAddr>() {
+        Ok(ip) => TcpStream::connect((ip, port)),
+        Err(_) => TcpStream::connect((host.as_str(), port)),
+    };
+    let target = match target {
+        Ok(target) => target,
+        Err(err) => {
+            // host unreachable
+            client.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            return Err(err.into());
+        }
+    };
+    client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+
+    let (mut from_client, mut to_target) = (client.try_clone()?, target.try_clone()?);
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut from_client, &mut to_target);
+        let _ = to_target.shutdown(Shutdown::Write);
+    });
+    let (mut from_target, mut to_client) = (target, client);
+    let _ = io::copy(&mut from_target, &mut to_client);
+    let _ = to_client.shutdown(Shutdown::Write);
+    let _ = upload.join();
+    Ok(())
+}
+
+/// A string preceded by its length on a byte.
+fn read_string(r: &mut impl Read) -> Result<String> {
+    let mut len = [0; 1];
+    r.read_exact(&mut len)?;
+    let mut s = vec![0; len[0] as usize];
+    r.read_exact(&mut s)?;
+    Ok(String::from_utf8(s)?)
+}
@@ -0,0 +1,129 @@
+use crate::{Invocation, State};
+use anyhow::Result;
+use russh::keys::ssh_key::rand_core::OsRng;
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{Algorithm, PrivateKey};
+use russh::server::{Auth, Config, Handler, Msg, Session};
+use russh::{Channel, ChannelId, CryptoVec};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use russh::keys::PublicKey;
+
+/// An ed25519 key pair, as made by `ssh-keygen -t ed25519`.
+pub struct SshKey {
+    key: PrivateKey,
+}
+
+impl SshKey {
+    pub fn generate() -> SshKey {
+        SshKey {
+            key: PrivateKey::random(&mut OsRng, Algorithm::Ed25519).expect("ed25519 key"),
+        }
+    }
+
+    /// The public key, as a line of `authorized_keys`, or of `known_hosts`
+    /// once prefixed with the host.
+    pub fn public(&self) -> String {
+        self.key
+            .public_key()
+            .to_openssh()
+            .expect("encodable public key")
+    }
+
+    /// The private key file, unencrypted in the OpenSSH format.
+    pub fn private(&self) -> String {
+        self.key
+            .to_openssh(LineEnding::LF)
+            .expect("encodable private key")
+            .to_string()
+    }
+}
+
+/// The public key of an `authorized_keys` line.
+pub fn parse_public(line: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from_openssh(line)?)
+}
+
+/// Runs the command sent over the SSH connection `stream`, as the SSH
+/// server of jenkins does for `ssh -p PORT USER@HOST COMMAND ARGS...`.
+pub fn serve(stream: TcpStream, state: Arc<State>) -> Result<()> {
+    stream.set_nonblocking(true)?;
+    let config = Arc::new(Config {
+        keys: vec![state.ssh_host.key.clone()],
+        // refusals are not delayed, as the tests try wrong keys
+        auth_rejection_time: Duration::from_secs(0),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        ..Config::default()
+    });
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let stream = tokio::net::TcpStream::from_std(stream)?;
+        let connection = Connection { state, user: None };
+        russh::server::run_stream(config, stream, connection)
+            .await?
+            .await
+    })
+}
+
+/// A connection of a client, authenticated as `user`.
+struct Connection {
+    state: Arc<State>,
+    user: Option<String>,
+}
+
+impl Handler for Connection {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(&mut self, user: &str, key: &PublicKey) -> Result<Auth> {
+        let keys = self
+            .state
+            .ssh_keys
+            .get(user)
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        if keys.iter().any(|known| known.key_data() == key.key_data()) {
+            self.user = Some(user.to_string());
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::reject())
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<()> {
+        session.channel_success(channel)?;
+        let invocation = Invocation {
+            user: self.user.clone().unwrap_or_default(),
+            args: shell_words::split(&String::from_utf8_lossy(data))?,
+            endpoint: "ssh",
+            ..Invocation::default()
+        };
+        let reply = self.state.run(invocation, &self.state.next_fault());
+        if !reply.stdout.is_empty() {
+            session.data(channel, CryptoVec::from_slice(&reply.stdout))?;
+        }
+        if !reply.stderr.is_empty() {
+            session.extended_data(channel, 1, CryptoVec::from_slice(&reply.stderr))?;
+        }
+        session.exit_status_request(channel, reply.code as u32)?;
+        session.eof(channel)?;
+        session.close(channel)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use crate::http::{self, Request};
+use crate::{frame, Fault, Invocation, Reply, State};
+use anyhow::{anyhow, Result};
+use std::io::{ErrorKind, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::{CloseFrame, Role};
+use tungstenite::{Message, WebSocket};
+
+/// How often pings are looked for while a command runs.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Runs the command sent over the websocket opened by `request`. Frames are
+/// sent as binary messages holding their op then their data.
+pub fn serve(mut stream: TcpStream, request: &Request, state: &State) -> Result<()> {
+    let user = match state.authenticate(request) {
+        Ok(user) => user,
+        Err(status) => return http::respond(&mut stream, status),
+    };
+    let fault = state.next_fault();
+    if let Some(Fault::Status(status)) = fault {
+        return http::respond(&mut stream, status);
+    }
+    let key = request
+        .header("sec-websocket-key")
+        .ok_or_else(|| anyhow!("no websocket key"))?;
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )?;
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+    let mut frames = Vec::new();
+    loop {
+        match socket.read_message()? {
+            Message::Binary(buf) if !buf.is_empty() => {
+                let op = buf[0];
+                frames.push((op, buf[1..].to_vec()));
+                if op == frame::START {
+                    break;
+                }
+            }
+            Message::Close(_) => return Ok(()),
+            _ => {}
+        }
+    }
+    let invocation = frame::invocation(&frames, user, "ws")?;
+    if let Some(Fault::Silent) = fault {
+        state.run(invocation, &fault);
+        return Ok(());
+    }
+    let reply = run(&mut socket, invocation, &fault, state)?;
+    if let Some(Fault::Close(code)) = fault {
+        socket.close(Some(CloseFrame {
+            code: code.into(),
+            reason: "fault injected".into(),
+        }))?;
+        while socket.read_message().is_ok() {}
+        return Ok(());
+    }
+
+    let mut left = match fault {
+        Some(Fault::Truncate(len)) => len,
+        _ => usize::MAX,
+    };
+    for (op, data) in frame::output(&reply) {
+        if left <= data.len() {
+            // the connection is dropped without closing the websocket
+            if left > 0 {
+                let mut message = vec![op];
+                message.extend_from_slice(&data[..left - 1]);
+                socket.write_message(Message::Binary(message))?;
+            }
+            socket.get_ref().shutdown(Shutdown::Both)?;
+            return Ok(());
+        }
+        left -= data.len() + 1;
+        let mut message = vec![op];
+        message.extend_from_slice(&data);
+        socket.write_message(Message::Binary(message))?;
+    }
+    let _ = socket.close(None);
+    // until the client acknowledges the close
+    while socket.read_message().is_ok() {}
+    Ok(())
+}
+
+/// Runs a command while answering the pings of the client, as jenkins does.
+fn run(
+    socket: &mut WebSocket<TcpStream>,
+    invocation: Invocation,
+    fault: &Option<Fault>,
+    state: &State,
+) -> Result<Reply> {
+    socket.get_ref().set_read_timeout(Some(POLL))?;
+    let reply = thread::scope(|scope| {
+        let command = scope.spawn(|| state.run(invocation, fault));
+        while !command.is_finished() {
+            match socket.read_message() {
+                Ok(Message::Ping(_)) => {
+                    state.pings.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(_) => break,
+            }
+        }
+        command.join()
+    })
+    .map_err(|_| anyhow!("panic in command"))?;
+    socket.get_ref().set_read_timeout(None)?;
+    Ok(reply)
+}
@@ -0,0 +1,58 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use jenkins_mock::{MockServer, Reply};
+use jk::jenkins::{Cli, Server};
+use std::io;
+
+/// Size of the console log printed by the benchmarked command.
+const SIZE: usize = 16 * 1024 * 1024;
+
+fn cli(url: &str) -> Cli {
+    let server: Server = toml::from_str(&format!(
+        r#"
+        url = "{}"
+        username = "u"
+        password = "p"
+        locale = "en"
+        encoding = "UTF-8"
+        "#,
+        url
+    ))
+    .unwrap();
+    Cli::new(server).unwrap()
+}
+
+/// Reads the console log of a build from a local mock server.
+fn console(c: &mut Criterion) {
+    let log: Vec<u8> = (0..)
+        .flat_map(|i| format!("[INFO] line {} of the console log\n", i).into_bytes())
+        .take(SIZE)
+        .collect();
+    let mock = MockServer::builder()
+        .user("u", "p")
+        .command(
+            "console",
+            "Retrieves console output for a build.",
+            move |_| Reply::ok(log.clone()),
+        )
+        .start()
+        .unwrap();
+
+    let mut group = c.benchmark_group("console");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(10);
+    for (name, url) in &[("http", mock.url()), ("ws", mock.ws_url())] {
+        let cli = cli(url);
+        let args = ["console".to_string(), "app".to_string()];
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut resp = cli.send(&args).unwrap();
+                io::copy(resp.output(), &mut io::sink()).unwrap();
+                assert_eq!(resp.wait_exit_code().unwrap(), 0);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, console);
+criterion_main!(benches);
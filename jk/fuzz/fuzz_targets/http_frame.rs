@@ -0,0 +1,13 @@
+#![no_main]
+use bytes::BytesMut;
+use jk::jenkins::codec;
+use libfuzzer_sys::fuzz_target;
+
+// The frames of the download side of an HTTP session, as a stream.
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(frame)) = codec::decode(&mut buf) {
+        let _ = codec::read_string(&frame.data);
+        let _ = codec::read_exit_code(&frame.data);
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+use bytes::Bytes;
+use jk::jenkins::codec;
+use libfuzzer_sys::fuzz_target;
+
+// A frame received as a websocket message.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = codec::decode_message(Bytes::copy_from_slice(data)) {
+        let _ = codec::read_string(&frame.data);
+        let _ = codec::read_exit_code(&frame.data);
+    }
+});
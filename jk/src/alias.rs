@@ -0,0 +1,236 @@
+use crate::output::Output;
+use crate::Context;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::process::Command;
+
+/// An entry of the `[alias]` table of the config.
+///
+/// An alias is a command line, or a list of command lines run in sequence
+/// until one fails. A command line starting with `!` is run by the local
+/// shell, otherwise it is a jk command line where `$1`, `$2`... are replaced
+/// by the arguments given to the alias and `$@` by all of them. When an alias
+/// is a single command line without any placeholder, the arguments are
+/// appended to it.
+///
+/// A jk command line may use other aliases. As in sh, an alias is not
+/// expanded again within its own expansion: `build = "build -s"` runs the
+/// jenkins command `build`, and aliases using each other in a loop end up
+/// running the command named like the first of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Alias {
+    Line(String),
+    Chain(Vec<String>),
+}
+
+impl Alias {
+    fn lines(&self) -> Vec<&str> {
+        match self {
+            Alias::Line(line) => vec![line.as_str()],
+            Alias::Chain(lines) => lines.iter().map(String::as_str).collect(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        self.lines().join(" && ")
+    }
+}
+
+/// Runs `args`, expanding its first word when it is an alias, unless it is
+/// one of `expanding`, the aliases being expanded.
+pub fn run_command(
+    ctx: &Context,
+    args: &[String],
+    expanding: &[&str],
+    output: &Output,
+) -> Result<i32> {
+    let name = args[0].as_str();
+    match ctx.config.alias.get(name) {
+        Some(alias) if !expanding.contains(&name) => {
+            let mut expanding = expanding.to_vec();
+            expanding.push(name);
+            run_alias(ctx, alias, &args[1..], &expanding, output)
+        }
+        _ => crate::dispatch(ctx, args, output),
+    }
+}
+
+fn run_alias(
+    ctx: &Context,
+    alias: &Alias,
+    args: &[String],
+    expanding: &[&str],
+    output: &Output,
+) -> Result<i32> {
+    let append = matches!(alias, Alias::Line(_));
+    for line in alias.lines() {
+        let code = match line.strip_prefix('!') {
+            Some(command) => run_local(ctx, command, args, append, output)?,
+            None => {
+                let expanded = expand(line, args, append)?;
+                if expanded.is_empty() {
+                    return Err(anyhow!("empty alias"));
+                }
+                run_command(ctx, &expanded, expanding, output)?
+            }
+        };
+        if code != 0 {
+            return Ok(code);
+        }
+    }
+    Ok(0)
+}
+
+/// Splits `line` into words and substitutes the placeholders.
+fn expand(line: &str, args: &[String], append: bool) -> Result<Vec<String>> {
+    let mut used = false;
+    let mut words = Vec::new();
+    for word in shell_words::split(line)? {
+        if word == "$@" {
+            used = true;
+            words.extend(args.iter().cloned());
+            continue;
+        }
+        let mut expanded = String::with_capacity(word.len());
+        let mut chars = word.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '$' && chars.peek().is_some_and(char::is_ascii_digit) {
+                let mut index = String::new();
+                while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                    index.push(*d);
+                    chars.next();
+                }
+                let index: usize = index.parse()?;
+                let arg = index
+                    .checked_sub(1)
+                    .and_then(|i| args.get(i))
+                    .ok_or_else(|| anyhow!("missing argument ${} for alias", index))?;
+                expanded.push_str(arg);
+                used = true;
+            } else {
+                expanded.push(c);
+            }
+        }
+        words.push(expanded);
+    }
+    if append && !used {
+        words.extend(args.iter().cloned());
+    }
+    Ok(words)
+}
+
+/// Runs `command` with `sh`, its arguments available as `$1`, `$2`... and
+/// the selected server name in `JK_SERVER`.
+fn run_local(
+    ctx: &Context,
+    command: &str,
+    args: &[String],
+    append: bool,
+    output: &Output,
+) -> Result<i32> {
+    let mut sh = Command::new("sh");
+    sh.arg("-c")
+        .arg(script(command, append))
+        .arg("jk")
+        .args(args)
+        .env("JK_SERVER", &ctx.name);
+    output.run(&mut sh)
+}
+
+/// The script run by `sh` for `command`, with the arguments appended unless
+/// it uses them or is not to be `append`ed to.
+fn script(command: &str, append: bool) -> String {
+    let positional = command
+        .match_indices('$')
+        .any(|(i, _)| matches!(command[i + 1..].chars().next(), Some('0'..='9' | '@' | '*')));
+    if append && !positional {
+        format!("{} \"$@\"", command)
+    } else {
+        command.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let cases = [
+            ("build $1", true, &["a"][..], &["build", "a"][..]),
+            ("copy-job $2 $1", true, &["a", "b"], &["copy-job", "b", "a"]),
+            (
+                "build $1-$2 x$10",
+                true,
+                &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"],
+                &["build", "a-b", "xj"],
+            ),
+            ("build $@ -s", true, &["a", "b"], &["build", "a", "b", "-s"]),
+            ("build $@", true, &[], &["build"]),
+            (
+                "build '$1 and $2'",
+                true,
+                &["a", "b"],
+                &["build", "a and b"],
+            ),
+            // appended when the line has no placeholder
+            ("build -s", true, &["a", "b"], &["build", "-s", "a", "b"]),
+            ("build -s", false, &["a"], &["build", "-s"]),
+            ("build $1", false, &["a", "b"], &["build", "a"]),
+            ("cost $", true, &["a"], &["cost", "$", "a"]),
+        ];
+        for (line, append, given, expected) in cases.iter() {
+            assert_eq!(
+                expand(line, &args(given), *append).unwrap(),
+                args(expected),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_missing_argument() {
+        let cases = [
+            ("build $2", &["a"][..], "missing argument $2 for alias"),
+            ("build $0", &["a"], "missing argument $0 for alias"),
+            ("build $1", &[], "missing argument $1 for alias"),
+        ];
+        for (line, given, message) in cases.iter() {
+            let err = expand(line, &args(given), true).unwrap_err();
+            assert_eq!(err.to_string(), *message, "{}", line);
+        }
+    }
+
+    #[test]
+    fn splits_chains_into_lines() {
+        let alias: Alias = toml::from_str::<std::collections::HashMap<String, Alias>>(
+            "a = [\"build $1\", \"!echo done\"]",
+        )
+        .unwrap()
+        .remove("a")
+        .unwrap();
+        assert_eq!(alias.lines(), ["build $1", "!echo done"]);
+        assert_eq!(alias.describe(), "build $1 && !echo done");
+    }
+
+    #[test]
+    fn appends_arguments_to_shell_commands() {
+        let cases = [
+            ("echo hi", true, "echo hi \"$@\""),
+            ("echo hi", false, "echo hi"),
+            ("echo $1", true, "echo $1"),
+            ("echo \"$@\" done", true, "echo \"$@\" done"),
+            ("echo $*", true, "echo $*"),
+            ("echo $JK_SERVER", true, "echo $JK_SERVER \"$@\""),
+        ];
+        for (command, append, expected) in cases.iter() {
+            assert_eq!(script(command, *append), *expected, "{}", command);
+        }
+    }
+}
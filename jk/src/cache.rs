@@ -0,0 +1,202 @@
+use crate::catalog::{Catalog, Usage};
+use crate::jenkins;
+use crate::tree;
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long cached folder contents are considered fresh, in seconds.
+const TTL: u64 = 10 * 60;
+
+/// How long the command catalog is considered fresh, in seconds.
+const CATALOG_TTL: u64 = 24 * 60 * 60;
+
+/// Remote data kept on disk for each server, so that completion and
+/// argument validation do not need a round trip to jenkins every time.
+///
+/// The cache is stored in `~/.cache/jk/<server url>_<locale>.toml`, jenkins
+/// translating the help of its commands.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Whether anything was fetched since the cache was loaded or saved
+    #[serde(skip)]
+    changed: bool,
+    catalog: Option<Catalog>,
+    /// Content of folders, keyed by folder path starting with `/`
+    #[serde(default)]
+    folders: HashMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    updated: u64,
+    /// `None` when the path is not a folder
+    items: Option<Vec<String>>,
+}
+
+impl Entry {
+    fn new(items: Option<Vec<String>>) -> Entry {
+        Entry {
+            updated: now(),
+            items,
+        }
+    }
+}
+
+impl Cache {
+    /// Loads the cache of the server of `cli`, or an empty cache if there is none.
+    pub fn load(cli: &jenkins::Cli) -> Cache {
+        let path = cache_file(&cli.server().url, cli.locale());
+        let mut cache = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| toml::from_str::<Cache>(&content).ok())
+            .unwrap_or_default();
+        cache.path = path;
+        cache
+    }
+
+    /// Writes the cache back, when anything was fetched.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("no cache dir found"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // several jk processes may save the same cache concurrently
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, toml::to_string(self)?)?;
+        fs::rename(tmp, path)?;
+        self.changed = false;
+        Ok(())
+    }
+
+    /// The catalog as last fetched, however old it is.
+    pub fn cached_catalog(&self) -> Option<&Catalog> {
+        self.catalog.as_ref()
+    }
+
+    /// The catalog of remote commands, fetched again when stale.
+    pub fn catalog(&mut self, cli: &jenkins::Cli) -> Result<&Catalog> {
+        if !self.has_fresh_catalog() {
+            self.fetch_catalog(cli)?;
+        }
+        Ok(self.catalog.as_ref().unwrap())
+    }
+
+    fn has_fresh_catalog(&self) -> bool {
+        matches!(&self.catalog, Some(c) if is_fresh(c.updated, CATALOG_TTL))
+    }
+
+    fn fetch_catalog(&mut self, cli: &jenkins::Cli) -> Result<()> {
+        let help = capture(cli, &["help"])?;
+        let mut catalog = Catalog::parse(&help);
+        catalog.updated = now();
+        // keep the details already known for commands still there
+        if let Some(old) = self.catalog.take() {
+            for (name, command) in old.commands {
+                if let Some(c) = catalog.commands.get_mut(&name) {
+                    c.usage = command.usage;
+                }
+            }
+        }
+        self.catalog = Some(catalog);
+        self.changed = true;
+        Ok(())
+    }
+
+    /// The usage of the remote command `name`, fetched again when stale.
+    /// Returns `None` if the command does not exist.
+    pub fn usage(&mut self, cli: &jenkins::Cli, name: &str) -> Result<Option<&Usage>> {
+        let cached = self.has_fresh_catalog();
+        self.catalog(cli)?;
+        // the command may be newer than the catalog, from a plugin installed
+        // since for example, or hidden from the user who fetched it
+        if cached && !self.catalog.as_ref().unwrap().commands.contains_key(name) {
+            self.fetch_catalog(cli)?;
+        }
+        let catalog = self.catalog.as_mut().unwrap();
+        let command = match catalog.commands.get_mut(name) {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let fresh = matches!(&command.usage, Some(u) if is_fresh(u.updated, CATALOG_TTL));
+        if !fresh {
+            let help = capture(cli, &["help", name])?;
+            let mut usage = Usage::parse(&help);
+            usage.updated = now();
+            command.usage = Some(usage);
+            self.changed = true;
+        }
+        Ok(command.usage.as_ref())
+    }
+
+    /// Names of the remote CLI commands.
+    pub fn commands(&mut self, cli: &jenkins::Cli) -> Result<Vec<String>> {
+        Ok(self.catalog(cli)?.commands.keys().cloned().collect())
+    }
+
+    /// Names of the items in `folder`, or `None` if it is not a folder.
+    pub fn folder(&mut self, cli: &jenkins::Cli, folder: &str) -> Result<Option<Vec<String>>> {
+        let key = format!("/{}", folder);
+        match self.folders.get(&key) {
+            Some(entry) if is_fresh(entry.updated, TTL) => Ok(entry.items.clone()),
+            _ => {
+                let items = tree::list_folder(cli, folder)?;
+                self.folders.insert(key, Entry::new(items.clone()));
+                self.changed = true;
+                Ok(items)
+            }
+        }
+    }
+}
+
+fn cache_file(url: &str, locale: &str) -> Option<PathBuf> {
+    let name: String = format!("{}_{}", url, locale)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut path = dirs::cache_dir()?;
+    path.push("jk");
+    path.push(format!("{}.toml", name));
+    Some(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn is_fresh(updated: u64, ttl: u64) -> bool {
+    now().saturating_sub(updated) < ttl
+}
+
+/// Runs a command and returns its whole output, failing if it does.
+fn capture(cli: &jenkins::Cli, args: &[&str]) -> Result<String> {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    let mut resp = cli.send(&args)?;
+    let mut output = BytesMut::new().writer();
+    std::io::copy(resp.output(), &mut output)?;
+    let output = String::from_utf8_lossy(output.get_ref()).into_owned();
+    match resp.wait_exit_code()? {
+        0 => Ok(output),
+        code => Err(anyhow!(
+            "{} exited with {}: {}",
+            args.join(" "),
+            code,
+            output.trim()
+        )),
+    }
+}
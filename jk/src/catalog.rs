@@ -0,0 +1,687 @@
+use crate::cache::Cache;
+use crate::jenkins;
+use crate::output::Output;
+use crate::plugin;
+use crate::Context;
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Prints the list of commands and aliases, or the usage of a command, from
+/// the cached catalog when available so that no connection is needed.
+pub fn run_help_cmd(ctx: &Context, args: &[String], output: &Output) -> Result<i32> {
+    let cli = &ctx.cli;
+    let mut cache = Cache::load(cli);
+    let text = match args.first() {
+        None => {
+            let mut text = match cache.cached_catalog() {
+                Some(catalog) => catalog.render(),
+                None => cache.catalog(cli)?.render(),
+            };
+            let mut aliases: Vec<_> = ctx.config.alias.iter().collect();
+            aliases.sort_by(|a, b| a.0.cmp(b.0));
+            if !aliases.is_empty() {
+                text.push_str("\nAliases:\n");
+                for (name, alias) in aliases {
+                    let _ = writeln!(text, "  {}\n    {}", name, alias.describe());
+                }
+            }
+            let plugins = plugin::list();
+            if !plugins.is_empty() {
+                text.push_str("\nPlugins:\n");
+                for name in plugins {
+                    let _ = writeln!(text, "  {}", name);
+                }
+            }
+            text
+        }
+        Some(name) if ctx.config.alias.contains_key(name) => {
+            format!(
+                "{} is an alias for: {}\n",
+                name,
+                ctx.config.alias[name].describe()
+            )
+        }
+        Some(name) => {
+            let cached = cache
+                .cached_catalog()
+                .and_then(|c| c.commands.get(name))
+                .and_then(|c| c.usage.as_ref());
+            match cached {
+                Some(usage) => usage.render(),
+                None => match cache.usage(cli, name)? {
+                    Some(usage) => usage.render(),
+                    None => {
+                        cache.save()?;
+                        return Err(cache.cached_catalog().unwrap().unknown(name));
+                    }
+                },
+            }
+        }
+    };
+    cache.save()?;
+    std::io::Write::write_all(&mut output.out(), text.as_bytes())?;
+    Ok(0)
+}
+
+/// Checks `args` against the catalog of the server before sending them.
+///
+/// Validation is skipped when the catalog cannot be fetched, the command
+/// then fails remotely as it would have without validation. The catalog is
+/// fetched once, the command being tried again if the server is down.
+pub fn check(cli: &jenkins::Cli, args: &[String]) -> Result<()> {
+    let cli = &cli.without_retries();
+    let mut cache = Cache::load(cli);
+    let res = cache.usage(cli, &args[0]).map(|_| ());
+    let _ = cache.save();
+    match res {
+        Ok(_) => cache.cached_catalog().unwrap().validate(args),
+        Err(err) => {
+            debug!("cannot fetch command catalog: {}", err);
+            Ok(())
+        }
+    }
+}
+
+/// The remote CLI commands of a server, parsed from the output of `help`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    pub updated: u64,
+    pub commands: BTreeMap<String, Command>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Command {
+    /// One line summary, from `help`
+    pub summary: String,
+    /// Details, from `help <command>`, fetched on demand
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub updated: u64,
+    pub synopsis: String,
+    pub description: String,
+    pub arguments: Vec<Argument>,
+    pub options: Vec<Opt>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Argument {
+    pub name: String,
+    pub required: bool,
+    pub multiple: bool,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Opt {
+    pub name: String,
+    pub aliases: Vec<String>,
+    /// Name of the value taken by the option, if it documents one
+    pub value: Option<String>,
+    pub description: String,
+}
+
+impl Catalog {
+    /// Parses the output of `help`: command names indented by two spaces,
+    /// each followed by its summary indented by four spaces.
+    pub fn parse(help: &str) -> Catalog {
+        let mut commands = BTreeMap::new();
+        let mut current: Option<String> = None;
+        for line in help.lines() {
+            if line.starts_with("    ") {
+                if let Some(cmd) = current.as_ref().and_then(|c| commands.get_mut(c)) {
+                    let cmd: &mut Command = cmd;
+                    if !cmd.summary.is_empty() {
+                        cmd.summary.push(' ');
+                    }
+                    cmd.summary.push_str(line.trim());
+                }
+            } else if let Some(name) = line.strip_prefix("  ") {
+                let name = name.trim().to_string();
+                commands.insert(
+                    name.clone(),
+                    Command {
+                        summary: String::new(),
+                        usage: None,
+                    },
+                );
+                current = Some(name);
+            }
+        }
+        Catalog {
+            updated: 0,
+            commands,
+        }
+    }
+
+    /// Checks `args` against the catalog, failing on unknown commands and,
+    /// when the usage of the command is known, on unknown options and
+    /// missing arguments.
+    pub fn validate(&self, args: &[String]) -> Result<()> {
+        let name = &args[0];
+        let command = match self.commands.get(name) {
+            Some(command) => command,
+            None => return Err(self.unknown(name)),
+        };
+        match &command.usage {
+            Some(usage) => usage.validate(name, &args[1..]),
+            None => Ok(()),
+        }
+    }
+
+    fn unknown(&self, name: &str) -> anyhow::Error {
+        match self.suggest(name) {
+            Some(suggestion) => anyhow!("unknown command {}, did you mean {}?", name, suggestion),
+            None => anyhow!("unknown command {}", name),
+        }
+    }
+
+    /// Returns the command closest to `name`, if any is close enough.
+    pub fn suggest(&self, name: &str) -> Option<&str> {
+        self.commands
+            .keys()
+            .map(|c| (distance(name, c), c))
+            .filter(|(d, c)| *d <= 2.max(c.len() / 3))
+            .min_by_key(|(d, _)| *d)
+            .map(|(_, c)| c.as_str())
+    }
+
+    /// Renders the list of commands, like `help` does.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, command) in &self.commands {
+            let _ = writeln!(text, "  {}", name);
+            let _ = writeln!(text, "    {}", command.summary);
+        }
+        text
+    }
+}
+
+impl Usage {
+    /// Parses the output of `help <command>`: a synopsis line, a free form
+    /// description, then one `NAME : description` line per argument and
+    /// option, with descriptions possibly continued on the following lines.
+    pub fn parse(help: &str) -> Usage {
+        let mut usage = Usage::default();
+        let mut lines = help.lines();
+        let synopsis = lines.next().unwrap_or_default();
+        usage.synopsis = match synopsis.find("jenkins-cli.jar ") {
+            Some(i) => synopsis[i + "jenkins-cli.jar ".len()..].trim().to_string(),
+            None => synopsis.trim().to_string(),
+        };
+
+        let mut description = Vec::new();
+        // the argument or option whose description may continue on next lines
+        let mut last: Option<Target> = None;
+        for line in lines {
+            match line.find(" : ").filter(|_| line.starts_with(' ')) {
+                Some(i) => {
+                    let key = line[..i].trim();
+                    let text = line[i + 3..].trim().to_string();
+                    let mut words = key.split_whitespace();
+                    let name = words.next().unwrap_or_default().to_string();
+                    let rest: Vec<&str> = words.collect();
+                    if name.starts_with('-') {
+                        let aliases = rest
+                            .iter()
+                            .filter(|w| w.starts_with("(-"))
+                            .map(|w| w.trim_matches(|c| c == '(' || c == ')').to_string())
+                            .collect();
+                        let value = rest
+                            .iter()
+                            .find(|w| !w.starts_with('(') && !w.ends_with(')'))
+                            .map(|w| w.to_string());
+                        usage.options.push(Opt {
+                            name,
+                            aliases,
+                            value,
+                            description: text,
+                        });
+                        last = Some(Target::Option(usage.options.len() - 1));
+                    } else {
+                        usage.arguments.push(Argument {
+                            name,
+                            required: true,
+                            multiple: false,
+                            description: text,
+                        });
+                        last = Some(Target::Argument(usage.arguments.len() - 1));
+                    }
+                }
+                None if line.starts_with("   ") && last.is_some() => {
+                    let desc = match last.unwrap() {
+                        Target::Argument(i) => &mut usage.arguments[i].description,
+                        Target::Option(i) => &mut usage.options[i].description,
+                    };
+                    desc.push(' ');
+                    desc.push_str(line.trim());
+                }
+                None => {
+                    last = None;
+                    description.push(line.trim_end());
+                }
+            }
+        }
+        usage.description = description.join("\n").trim().to_string();
+        usage.apply_synopsis();
+        usage
+    }
+
+    /// Completes arguments and options from the synopsis: optional
+    /// arguments are in brackets, repeated ones are followed by `...`.
+    fn apply_synopsis(&mut self) {
+        let tokens: Vec<&str> = self.synopsis.split_whitespace().skip(1).collect();
+        let mut depth = 0usize;
+        let mut previous_option: Option<usize> = None;
+        for token in tokens {
+            let optional = depth > 0 || token.starts_with('[');
+            depth += token.matches('[').count();
+            depth = depth.saturating_sub(token.matches(']').count());
+            let word = token.trim_matches(|c| c == '[' || c == ']');
+            if word == "..." {
+                if let Some(arg) = self.arguments.last_mut() {
+                    arg.multiple = true;
+                }
+                continue;
+            }
+            // aliases of the previous option, `(--force)`
+            if word.starts_with('(') {
+                continue;
+            }
+            if word.starts_with('-') {
+                let index = match self.options.iter().position(|o| o.name == word) {
+                    Some(index) => index,
+                    None => {
+                        self.options.push(Opt {
+                            name: word.to_string(),
+                            aliases: Vec::new(),
+                            value: None,
+                            description: String::new(),
+                        });
+                        self.options.len() - 1
+                    }
+                };
+                previous_option = Some(index);
+                continue;
+            }
+            if let Some(index) = previous_option.take() {
+                if optional && !token.starts_with('[') {
+                    let opt = &mut self.options[index];
+                    if opt.value.is_none() {
+                        opt.value = Some(word.to_string());
+                    }
+                    continue;
+                }
+            }
+            match self.arguments.iter_mut().find(|a| a.name == word) {
+                Some(arg) => arg.required = !optional,
+                None => self.arguments.push(Argument {
+                    name: word.to_string(),
+                    required: !optional,
+                    multiple: false,
+                    description: String::new(),
+                }),
+            }
+        }
+    }
+
+    fn find_option(&self, name: &str) -> Option<&Opt> {
+        self.options
+            .iter()
+            .find(|o| o.name == name || o.aliases.iter().any(|a| a == name))
+    }
+
+    fn validate(&self, command: &str, args: &[String]) -> Result<()> {
+        let mut positionals = 0;
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                positionals += iter.count();
+                break;
+            }
+            if arg.starts_with('-') && arg.len() > 1 {
+                let name = arg.split('=').next().unwrap_or(arg);
+                match self.find_option(name) {
+                    Some(opt) => {
+                        if opt.value.is_some() && !arg.contains('=') {
+                            iter.next();
+                        }
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "{}: unknown option {}\nusage: {}",
+                            command,
+                            arg,
+                            self.synopsis
+                        ))
+                    }
+                }
+            } else {
+                positionals += 1;
+            }
+        }
+        let required = self.arguments.iter().filter(|a| a.required).count();
+        if positionals < required {
+            let missing: Vec<&str> = self
+                .arguments
+                .iter()
+                .filter(|a| a.required)
+                .skip(positionals)
+                .map(|a| a.name.as_str())
+                .collect();
+            return Err(anyhow!(
+                "{}: missing argument {}\nusage: {}",
+                command,
+                missing.join(" "),
+                self.synopsis
+            ));
+        }
+        Ok(())
+    }
+
+    /// Renders the usage, like `help <command>` does.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "usage: {}", self.synopsis);
+        if !self.description.is_empty() {
+            let _ = writeln!(text, "{}", self.description);
+        }
+        let names: Vec<String> = self
+            .arguments
+            .iter()
+            .map(|a| a.name.clone())
+            .chain(self.options.iter().map(|o| match &o.value {
+                Some(value) => format!("{} {}", o.name, value),
+                None => o.name.clone(),
+            }))
+            .collect();
+        let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+        let descriptions = self
+            .arguments
+            .iter()
+            .map(|a| &a.description)
+            .chain(self.options.iter().map(|o| &o.description));
+        for (name, description) in names.iter().zip(descriptions) {
+            let _ = writeln!(text, " {:width$} : {}", name, description, width = width);
+        }
+        text
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Argument(usize),
+    Option(usize),
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(row[j + 1])
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output of `help` on jenkins 2.401, cut down.
+    const HELP: &str = "  build
+    Builds a job, and optionally waits until its completion.
+  console
+    Retrieves console output of a build.
+  delete-job
+    Deletes job(s).
+  groovy
+    Executes the specified Groovy script.
+  who-am-i
+    Reports your credential and permissions.
+";
+
+    /// Output of `help <command>` on jenkins 2.401.
+    const USAGES: &[(&str, &str)] = &[
+        (
+            "build",
+            "java -jar jenkins-cli.jar build JOB [-c] [-f] [-p] [-r N] [-s] [-v] [-w]
+Starts a build, and optionally waits for a completion.
+Aside from general scripting use, this command can be
+used to invoke another job from within a build of one job.
+ JOB  : Name of the job to build
+ -c   : Check for SCM changes before starting the build, and if there's no
+        change, exit without doing a build
+ -f   : Follow the build progress. Like -s only interrupts are not passed
+        through to the build.
+ -p   : Specify the build parameters in the key=value format.
+ -r N : Number of times to retry reading of the output log if it does not
+        exists on first attempt. Defaults to 0. Use with -v.
+ -s   : Wait until the completion/abortion of the command. Interrupts are
+        passed through to the build.
+ -v   : Prints out the console output of the build. Use with -s
+ -w   : Wait until the start of the command
+",
+        ),
+        (
+            "console",
+            "java -jar jenkins-cli.jar console JOB [BUILD] [-f] [-n N]
+Produces the console output of a specific build to stdout, as if you are doing 'cat build.log'
+ JOB   : Name of the job
+ BUILD : Build number or permalink to point to the build. Defaults to the last
+         build
+ -f    : If the build is in progress, stay around and append console output as
+         it comes, like 'tail -f'
+ -n N  : Display the last N lines
+",
+        ),
+        (
+            "delete-job",
+            "java -jar jenkins-cli.jar delete-job NAME ...
+Deletes job(s).
+ NAME : Name of the job(s) to delete
+",
+        ),
+        (
+            "groovy",
+            "java -jar jenkins-cli.jar groovy SCRIPT [ARGUMENTS ...]
+Executes the specified Groovy script.
+ SCRIPT    : Script to be executed. Only '=' (to represent stdin) is supported.
+ ARGUMENTS : Command line arguments to pass into script.
+",
+        ),
+    ];
+
+    /// The catalog of `HELP`, with the usages of `USAGES`.
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::parse(HELP);
+        for (name, help) in USAGES {
+            catalog.commands.get_mut(*name).unwrap().usage = Some(Usage::parse(help));
+        }
+        catalog
+    }
+
+    fn usage(name: &str) -> Usage {
+        catalog().commands[name].usage.clone().unwrap()
+    }
+
+    #[test]
+    fn parses_commands() {
+        let catalog = Catalog::parse(HELP);
+        let commands: Vec<(&str, &str)> = catalog
+            .commands
+            .iter()
+            .map(|(name, command)| (name.as_str(), command.summary.as_str()))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                (
+                    "build",
+                    "Builds a job, and optionally waits until its completion."
+                ),
+                ("console", "Retrieves console output of a build."),
+                ("delete-job", "Deletes job(s)."),
+                ("groovy", "Executes the specified Groovy script."),
+                ("who-am-i", "Reports your credential and permissions."),
+            ]
+        );
+        assert_eq!(catalog.render(), HELP);
+
+        // a summary may continue on the following lines
+        let catalog = Catalog::parse("  build\n    Builds a job,\n    and waits.\n");
+        assert_eq!(
+            catalog.commands["build"].summary,
+            "Builds a job, and waits."
+        );
+    }
+
+    #[test]
+    fn parses_usage() {
+        let build = usage("build");
+        assert_eq!(
+            build.synopsis,
+            "build JOB [-c] [-f] [-p] [-r N] [-s] [-v] [-w]"
+        );
+        assert_eq!(
+            build.description,
+            "Starts a build, and optionally waits for a completion.\n\
+             Aside from general scripting use, this command can be\n\
+             used to invoke another job from within a build of one job."
+        );
+        let options: Vec<(&str, Option<&str>)> = build
+            .options
+            .iter()
+            .map(|o| (o.name.as_str(), o.value.as_deref()))
+            .collect();
+        assert_eq!(
+            options,
+            [
+                ("-c", None),
+                ("-f", None),
+                ("-p", None),
+                ("-r", Some("N")),
+                ("-s", None),
+                ("-v", None),
+                ("-w", None),
+            ]
+        );
+        assert_eq!(
+            build.options[0].description,
+            "Check for SCM changes before starting the build, and if there's no \
+             change, exit without doing a build"
+        );
+
+        let options = Usage::parse(
+            "java -jar jenkins-cli.jar restart [-f (--force)]
+ -f (--force) : Restart even if builds are running
+",
+        )
+        .options;
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].name, "-f");
+        assert_eq!(options[0].aliases, ["--force"]);
+        assert_eq!(options[0].value, None);
+    }
+
+    #[test]
+    fn applies_synopsis_to_arguments() {
+        // name, required and multiple
+        type Arguments = &'static [(&'static str, bool, bool)];
+        let cases: &[(&str, Arguments)] = &[
+            ("build", &[("JOB", true, false)]),
+            ("console", &[("JOB", true, false), ("BUILD", false, false)]),
+            ("delete-job", &[("NAME", true, true)]),
+            (
+                "groovy",
+                &[("SCRIPT", true, false), ("ARGUMENTS", false, true)],
+            ),
+        ];
+        for (name, expected) in cases {
+            let usage = usage(name);
+            let arguments: Vec<(&str, bool, bool)> = usage
+                .arguments
+                .iter()
+                .map(|a| (a.name.as_str(), a.required, a.multiple))
+                .collect();
+            assert_eq!(arguments, *expected, "{}", name);
+        }
+
+        // options only in the synopsis are known too
+        let mut usage = Usage {
+            synopsis: "list-jobs [-v] [VIEW]".to_string(),
+            ..Usage::default()
+        };
+        usage.apply_synopsis();
+        assert_eq!(usage.options[0].name, "-v");
+        assert_eq!(usage.arguments[0].name, "VIEW");
+        assert!(!usage.arguments[0].required);
+    }
+
+    #[test]
+    fn validates_arguments() {
+        let catalog = catalog();
+        let cases: &[(&[&str], Option<&str>)] = &[
+            (&["build", "a"], None),
+            (&["build", "-s", "-v", "a"], None),
+            // the value of -r is not the job
+            (&["build", "-r", "2", "a"], None),
+            (&["build", "-r=2", "a"], None),
+            (&["build", "--", "-a"], None),
+            (&["build"], Some("build: missing argument JOB")),
+            (&["build", "-r", "2"], Some("build: missing argument JOB")),
+            (&["build", "-x", "a"], Some("build: unknown option -x")),
+            (&["console", "a"], None),
+            (&["console", "a", "7", "-n", "10"], None),
+            (&["delete-job", "a", "b", "c"], None),
+            (&["delete-job"], Some("delete-job: missing argument NAME")),
+            (&["groovy", "=", "x", "y"], None),
+            // the usage of who-am-i is not known yet
+            (&["who-am-i", "-x"], None),
+            (
+                &["biuld", "a"],
+                Some("unknown command biuld, did you mean build?"),
+            ),
+            (&["nothing"], Some("unknown command nothing")),
+        ];
+        for (args, expected) in cases {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let error = catalog
+                .validate(&args)
+                .err()
+                .map(|err| err.to_string().lines().next().unwrap().to_string());
+            assert_eq!(error.as_deref(), *expected, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn suggests_close_commands() {
+        let catalog = catalog();
+        let cases = [
+            ("biuld", Some("build")),
+            ("consle", Some("console")),
+            ("who-am-I", Some("who-am-i")),
+            ("delete-jobs", Some("delete-job")),
+            ("delete", None),
+            ("zzz", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(catalog.suggest(name), expected, "{}", name);
+        }
+    }
+}
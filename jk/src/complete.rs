@@ -0,0 +1,343 @@
+use crate::cache::Cache;
+use crate::jenkins;
+use crate::plugin;
+use crate::Config;
+use anyhow::{anyhow, Result};
+
+/// Commands handled by jk itself.
+pub const BUILTINS: &[&str] = &[
+    "completions",
+    "daemon",
+    "debug",
+    "each",
+    "help",
+    "shell",
+    "tree",
+];
+
+const GLOBAL_FLAGS: &[&str] = &[
+    "--config",
+    "--connect-timeout",
+    "--encoding",
+    "--help",
+    "--idle-timeout",
+    "--jenkins",
+    "--locale",
+    "--output",
+    "--timeout",
+    "--trace",
+    "--version",
+];
+
+/// Values of `--output`.
+const FORMATS: &[&str] = &["json", "jsonl", "text"];
+
+/// Global flags taking a value, which is not completed but for `--output`.
+const VALUE_FLAGS: &[&str] = &[
+    "--connect-timeout",
+    "--encoding",
+    "--idle-timeout",
+    "--locale",
+    "--output",
+    "--timeout",
+    "--trace",
+];
+
+const BASH: &str = r#"_jk() {
+    local IFS=$'\n'
+    COMPREPLY=($(jk __complete -- "${COMP_WORDS[@]:1:COMP_CWORD}" 2>/dev/null))
+}
+complete -o default -F _jk jk
+"#;
+
+const ZSH: &str = r#"#compdef jk
+_jk() {
+    local -a candidates
+    candidates=("${(@f)$(jk __complete -- "${(@)words[2,CURRENT]}" 2>/dev/null)}")
+    compadd -Q -- ${candidates:#}
+}
+if [ "$funcstack[1]" = "_jk" ]; then
+    _jk "$@"
+else
+    compdef _jk jk
+fi
+"#;
+
+const FISH: &str = r#"function __jk_complete
+    set -l tokens (commandline -opc) (commandline -ct)
+    jk __complete -- $tokens[2..-1] 2>/dev/null
+end
+complete -c jk -f -a '(__jk_complete)'
+"#;
+
+/// Prints the completion script for `shell`.
+pub fn run_completions_cmd(args: &[String]) -> Result<i32> {
+    let script = match args.first().map(String::as_str) {
+        Some("bash") => BASH,
+        Some("zsh") => ZSH,
+        Some("fish") => FISH,
+        _ => return Err(anyhow!("usage: completions <bash|zsh|fish>")),
+    };
+    print!("{}", script);
+    Ok(0)
+}
+
+/// Prints the candidates completing the last of `words`, the words of the
+/// command line being edited without the program name.
+///
+/// This is the hidden `__complete` endpoint called by completion scripts.
+pub fn run_complete_cmd(words: &[String]) -> Result<i32> {
+    let words = match words.split_first() {
+        Some((first, rest)) if first == "--" => rest,
+        _ => words,
+    };
+    for candidate in candidates(words)? {
+        println!("{}", candidate);
+    }
+    Ok(0)
+}
+
+/// The candidates completing the last of `words`.
+fn candidates(words: &[String]) -> Result<Vec<String>> {
+    let (current, previous) = match words.split_last() {
+        Some((current, previous)) => (current.as_str(), previous),
+        None => ("", words),
+    };
+
+    let mut config_path = None;
+    let mut selection = None;
+    let mut command = None;
+    let mut iter = previous.iter();
+    while let Some(word) = iter.next() {
+        match word.as_str() {
+            "-c" | "--config" if command.is_none() => config_path = iter.next(),
+            "-j" | "--jenkins" if command.is_none() => selection = iter.next(),
+            w if command.is_none() && VALUE_FLAGS.contains(&w) => {
+                iter.next();
+            }
+            w if command.is_none() && !w.starts_with('-') => command = Some(w),
+            _ => {}
+        }
+    }
+
+    let candidates = match previous.last().map(String::as_str) {
+        Some("-c") | Some("--config") if command.is_none() => Vec::new(),
+        Some("--output") if command.is_none() => complete_words(FORMATS, current),
+        Some(w) if command.is_none() && VALUE_FLAGS.contains(&w) => Vec::new(),
+        Some("-j") | Some("--jenkins") if command.is_none() => {
+            let config = crate::read_file(crate::config_path(config_path.map(String::as_str))?)?;
+            complete_servers(&config, current)
+        }
+        _ if command.is_none() && current.starts_with('-') => complete_words(GLOBAL_FLAGS, current),
+        _ => {
+            let config = crate::read_file(crate::config_path(config_path.map(String::as_str))?)?;
+            let server = selection
+                .and_then(|s| s.split(',').next())
+                .unwrap_or(&config.default)
+                .to_string();
+            let cfg = config
+                .servers
+                .get(&server)
+                .ok_or_else(|| anyhow!("no server {} found", server))?;
+            let cli = jenkins::Cli::new(cfg.clone())?;
+            let mut cache = Cache::load(&cli);
+            let candidates = if command.is_none() {
+                let local = local_commands(BUILTINS, &config);
+                complete_command(&mut cache, &cli, current, &local)
+            } else {
+                complete_job(&mut cache, &cli, "", current)
+            };
+            cache.save()?;
+            candidates
+        }
+    };
+    Ok(candidates)
+}
+
+/// The words of `words` starting with `current`.
+fn complete_words(words: &[&str], current: &str) -> Vec<String> {
+    words
+        .iter()
+        .filter(|w| w.starts_with(current))
+        .map(|w| w.to_string())
+        .collect()
+}
+
+/// Completes the last item of a comma separated server selection.
+fn complete_servers(config: &Config, current: &str) -> Vec<String> {
+    let (head, last) = match current.rfind(',') {
+        Some(i) => (&current[..=i], &current[i + 1..]),
+        None => ("", current),
+    };
+    let mut names: Vec<&String> = config.servers.keys().chain(config.groups.keys()).collect();
+    names.sort();
+    let all = "all".to_string();
+    names.push(&all);
+    names
+        .into_iter()
+        .filter(|n| n.starts_with(last))
+        .map(|n| format!("{}{}", head, n))
+        .collect()
+}
+
+/// Names of the commands run locally: built-ins, aliases and plugins.
+pub fn local_commands(builtins: &[&str], config: &Config) -> Vec<String> {
+    builtins
+        .iter()
+        .map(|c| c.to_string())
+        .chain(config.alias.keys().cloned())
+        .chain(plugin::list())
+        .collect()
+}
+
+/// Completes a command name, local or remote.
+pub fn complete_command(
+    cache: &mut Cache,
+    cli: &jenkins::Cli,
+    word: &str,
+    local: &[String],
+) -> Vec<String> {
+    let remote = cache.commands(cli).unwrap_or_default();
+    let mut candidates: Vec<String> = local
+        .iter()
+        .cloned()
+        .chain(remote)
+        .filter(|c| c.starts_with(word))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+/// Completes a job path, relative to the folder `cwd`.
+pub fn complete_job(cache: &mut Cache, cli: &jenkins::Cli, cwd: &str, word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let folder = crate::shell::resolve_path(cwd, dir);
+    cache
+        .folder(cli, &folder)
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| format!("{}{}", dir, name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    const CONFIG: &str = "default = \"prod\"\n\
+                          [groups]\n\
+                          all-prod = [\"prod\", \"prod-eu\"]\n\
+                          [prod]\n\
+                          url = \"http://prod\"\n\
+                          username = \"u\"\n\
+                          [prod-eu]\n\
+                          url = \"http://prod-eu\"\n\
+                          username = \"u\"\n\
+                          [dev]\n\
+                          url = \"http://dev\"\n\
+                          username = \"u\"\n";
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn completes_servers() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let cases: &[(&str, &[&str])] = &[
+            ("", &["all-prod", "dev", "prod", "prod-eu", "all"]),
+            ("pr", &["prod", "prod-eu"]),
+            ("al", &["all-prod", "all"]),
+            // the last of a list
+            ("dev,pr", &["dev,prod", "dev,prod-eu"]),
+            (
+                "dev,",
+                &[
+                    "dev,all-prod",
+                    "dev,dev",
+                    "dev,prod",
+                    "dev,prod-eu",
+                    "dev,all",
+                ],
+            ),
+            ("x", &[]),
+        ];
+        for (current, expected) in cases {
+            assert_eq!(&complete_servers(&config, current), expected, "{}", current);
+        }
+    }
+
+    #[test]
+    fn completes_flags_and_their_values() {
+        let cases: &[(&[&str], &[&str])] = &[
+            (&["--out"], &["--output"]),
+            (&["--c"], &["--config", "--connect-timeout"]),
+            (&["--output", ""], &["json", "jsonl", "text"]),
+            (&["--output", "js"], &["json", "jsonl"]),
+            // values which are not completed
+            (&["--timeout", ""], &[]),
+            (&["--locale", "fr"], &[]),
+            (&["-c", ""], &[]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(&candidates(&words(line)).unwrap(), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn completes_servers_of_config() {
+        let path = std::env::temp_dir().join(format!("jk-complete-{}.toml", std::process::id()));
+        fs::write(&path, CONFIG).unwrap();
+        let path = path.to_str().unwrap();
+        let servers = candidates(&words(&["--config", path, "-j", "prod,d"]));
+        // the value of --output is skipped
+        let after_output = candidates(&words(&[
+            "--config",
+            path,
+            "--output",
+            "json",
+            "--jenkins",
+            "pr",
+        ]));
+        let _ = fs::remove_file(path);
+        assert_eq!(servers.unwrap(), ["prod,dev"]);
+        assert_eq!(after_output.unwrap(), ["prod", "prod-eu"]);
+    }
+
+    #[test]
+    fn passes_words_from_bash() {
+        // jk prints the arguments it is called with
+        let script = format!(
+            "{}jk() {{ printf '%s\\n' \"$@\"; }}\n\
+             COMP_WORDS=(jk -j 'pr od' ignored)\n\
+             COMP_CWORD=2\n\
+             _jk\n\
+             printf '%s|' \"${{COMPREPLY[@]}}\"",
+            BASH
+        );
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "__complete|--|-j|pr od|"
+        );
+    }
+
+    #[test]
+    fn passes_words_from_zsh_and_fish() {
+        assert!(ZSH.contains("jk __complete -- \"${(@)words[2,CURRENT]}\""));
+        assert!(FISH.contains("jk __complete -- $tokens[2..-1]"));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::jenkins::daemon;
+use anyhow::{anyhow, Result};
+
+/// Runs `daemon [start|stop|status]`.
+///
+/// `start`, the default, runs the daemon in the foreground until it is
+/// stopped. While it is running, jk sends the commands through it instead of
+/// connecting to jenkins itself.
+pub fn run_daemon_cmd(args: &[String]) -> Result<i32> {
+    match args.first().map(String::as_str) {
+        None | Some("start") => {
+            daemon::serve()?;
+            Ok(0)
+        }
+        Some("stop") => {
+            if daemon::stop()? {
+                Ok(0)
+            } else {
+                eprintln!("daemon not running");
+                Ok(1)
+            }
+        }
+        Some("status") => {
+            let path = daemon::socket_path().ok_or_else(|| anyhow!("no runtime dir found"))?;
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                println!("daemon listening on {}", path.display());
+                Ok(0)
+            } else {
+                println!("daemon not running");
+                Ok(1)
+            }
+        }
+        _ => Err(anyhow!("usage: daemon [start|stop|status]")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unknown_subcommand() {
+        let err = run_daemon_cmd(&["restart".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "usage: daemon [start|stop|status]");
+    }
+}
@@ -0,0 +1,40 @@
+use crate::jenkins::trace::{self, Direction};
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+
+/// Runs `debug decode <trace>`, printing the frames recorded by `--trace`,
+/// read from stdin when the trace is `-`.
+pub fn run_debug_cmd(args: &[String]) -> Result<i32> {
+    let path = match args {
+        [command, path] if command == "decode" => path,
+        _ => return Err(anyhow!("usage: debug decode <trace>")),
+    };
+    let records: Box<dyn Iterator<Item = Result<trace::Record>>> = if path == "-" {
+        Box::new(trace::read(io::stdin().lock()))
+    } else {
+        Box::new(trace::read(BufReader::new(File::open(path)?)))
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut start = None;
+    for record in records {
+        let record = record?;
+        let start = *start.get_or_insert(record.time);
+        let arrow = match record.dir {
+            Direction::Send => "->",
+            Direction::Recv => "<-",
+        };
+        writeln!(
+            out,
+            "{:>9.3} #{:<3} {} {:<8} {:>7}  {:?}",
+            record.time - start,
+            record.session,
+            arrow,
+            record.op,
+            record.len,
+            trace::preview(&record.data)
+        )?;
+    }
+    Ok(0)
+}
@@ -0,0 +1,305 @@
+use crate::jenkins;
+use crate::output::Output;
+use crate::tree;
+use crate::Context;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+const DEFAULT_PARALLEL: usize = 4;
+
+const USAGE: &str =
+    "usage: each [--dry-run] [--parallel N] [--group] <pattern> -- <command> [args...]";
+
+struct EachOpts {
+    dry_run: bool,
+    parallel: usize,
+    group: bool,
+    pattern: String,
+    command: Vec<String>,
+}
+
+/// Runs a command over every job matching a pattern.
+///
+/// The pattern is matched against full job paths: `*` and `?` match inside a
+/// path segment, `**` matches any number of segments. Every `{}` in the
+/// command is replaced by the job path, or the path is appended when the
+/// command has no `{}`.
+pub fn run_each_cmd(ctx: &Context, args: &[String], output: &Output) -> Result<i32> {
+    let opts = parse_args(args)?;
+    let jobs = expand(&ctx.cli, &opts.pattern)?;
+    if jobs.is_empty() {
+        writeln!(output.err(), "no job matching {}", opts.pattern)?;
+        return Ok(1);
+    }
+
+    if opts.dry_run {
+        let mut out = output.out();
+        for job in &jobs {
+            writeln!(out, "{}", command_for(&opts.command, job).join(" "))?;
+        }
+        return Ok(0);
+    }
+
+    let results = run_all(ctx, &opts, &jobs, output);
+
+    let failures: Vec<_> = results
+        .iter()
+        .filter(|(_, res)| !matches!(res, Ok(0)))
+        .collect();
+    if failures.is_empty() {
+        return Ok(0);
+    }
+    let mut err = output.err();
+    writeln!(err, "{} of {} jobs failed:", failures.len(), jobs.len())?;
+    for (job, res) in failures {
+        match res {
+            Ok(code) => writeln!(err, "  {} (exit code {})", job, code)?,
+            Err(e) => writeln!(err, "  {} ({})", job, e)?,
+        }
+    }
+    Ok(1)
+}
+
+fn parse_args(args: &[String]) -> Result<EachOpts> {
+    let mut opts = EachOpts {
+        dry_run: false,
+        parallel: DEFAULT_PARALLEL,
+        group: false,
+        pattern: String::new(),
+        command: Vec::new(),
+    };
+    let mut pattern = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--" => {
+                opts.command = iter.by_ref().cloned().collect();
+            }
+            "-n" | "--dry-run" => opts.dry_run = true,
+            "-g" | "--group" => opts.group = true,
+            "-P" | "--parallel" => {
+                let n = iter.next().ok_or_else(|| anyhow!("{}", USAGE))?;
+                opts.parallel = n
+                    .parse()
+                    .map_err(|_| anyhow!("invalid value for --parallel: {}", n))?;
+                if opts.parallel == 0 {
+                    return Err(anyhow!("--parallel must be at least 1"));
+                }
+            }
+            _ if pattern.is_none() => pattern = Some(arg.clone()),
+            _ => return Err(anyhow!("unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+    opts.pattern = pattern.ok_or_else(|| anyhow!("{}", USAGE))?;
+    if opts.command.is_empty() {
+        return Err(anyhow!("{}", USAGE));
+    }
+    Ok(opts)
+}
+
+/// Lists the jobs matching `pattern`, walking only the folder named by the
+/// literal segments at the start of the pattern.
+fn expand(cli: &jenkins::Cli, pattern: &str) -> Result<Vec<String>> {
+    let pattern = pattern.trim_matches('/');
+    let segments: Vec<&str> = pattern.split('/').collect();
+    let literal = segments.iter().take_while(|s| !is_wildcard(s)).count();
+    if literal == segments.len() {
+        return Ok(vec![pattern.to_string()]);
+    }
+
+    let root = segments[..literal].join("/");
+    let folder = if root.is_empty() {
+        None
+    } else {
+        Some(root.as_str())
+    };
+    let mut jobs = Vec::new();
+    tree::walk(cli, folder, |path| {
+        let path = path.trim_start_matches('/');
+        let parts: Vec<&str> = path.split('/').collect();
+        if matches_path(&segments, &parts) {
+            jobs.push(path.to_string());
+        }
+        Ok(())
+    })?;
+    Ok(jobs)
+}
+
+fn command_for(command: &[String], job: &str) -> Vec<String> {
+    if command.iter().any(|a| a.contains("{}")) {
+        command.iter().map(|a| a.replace("{}", job)).collect()
+    } else {
+        let mut cmd = command.to_vec();
+        cmd.push(job.to_string());
+        cmd
+    }
+}
+
+fn run_all(
+    ctx: &Context,
+    opts: &EachOpts,
+    jobs: &[String],
+    output: &Output,
+) -> Vec<(String, Result<i32>)> {
+    let queue = Arc::new(Mutex::new(
+        jobs.iter().cloned().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..opts.parallel.min(jobs.len()))
+        .map(|_| {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let ctx = ctx.clone();
+            let command = opts.command.clone();
+            let group = opts.group;
+            let output = output.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (index, job) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let args = command_for(&command, &job);
+                let res = if group {
+                    let (held, capture) = output.hold();
+                    let res = crate::run_command(&ctx, &args, &held);
+                    let _ = writeln!(output.out(), "==> {} <==", job)
+                        .and_then(|_| capture.write_to(&output));
+                    res
+                } else {
+                    crate::run_command(&ctx, &args, &output.prefixed(&format!("[{}] ", job)))
+                };
+                let _ = tx.send((index, job, res));
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut results: Vec<_> = rx.iter().collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+    results
+        .into_iter()
+        .map(|(_, job, res)| (job, res))
+        .collect()
+}
+
+fn is_wildcard(segment: &str) -> bool {
+    segment.contains(['*', '?'])
+}
+
+fn matches_path(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| matches_path(rest, &path[i..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                let first: Vec<char> = first.chars().collect();
+                let segment: Vec<char> = segment.chars().collect();
+                matches_segment(&first, &segment) && matches_path(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn matches_segment(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_segment(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && matches_segment(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_segment(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        let pattern: Vec<&str> = pattern.split('/').collect();
+        let path: Vec<&str> = path.split('/').collect();
+        matches_path(&pattern, &path)
+    }
+
+    #[test]
+    fn matches_paths() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            // `*` and `?` inside a segment
+            ("a/*", "a/b", true),
+            ("a/*", "a/b/c", false),
+            ("a/app-*", "a/app-1", true),
+            ("a/app-*", "a/lib-1", false),
+            ("a/*-1", "a/app-1", true),
+            ("a/a*p*", "a/app", true),
+            ("a/app-?", "a/app-1", true),
+            ("a/app-?", "a/app-10", false),
+            ("*/b", "a/b", true),
+            // `**` over any number of segments
+            ("**", "a", true),
+            ("**", "a/b/c", true),
+            ("a/**", "a/b/c", true),
+            ("a/**/c", "a/c", true),
+            ("a/**/c", "a/b/x/c", true),
+            ("a/**/c", "a/b/x/d", false),
+            ("**/app-*", "f/g/app-1", true),
+            ("**/app-*", "f/g/lib-1", false),
+        ];
+        for (pattern, path, expected) in cases.iter() {
+            assert_eq!(matches(pattern, path), *expected, "{} ~ {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn matches_segments() {
+        let cases = [
+            ("", "", true),
+            ("", "a", false),
+            ("*", "", true),
+            ("*", "abc", true),
+            ("a*c", "abbc", true),
+            ("a*c", "abcd", false),
+            ("?", "", false),
+            ("?b?", "abc", true),
+            ("é*", "été", true),
+        ];
+        for (pattern, name, expected) in cases.iter() {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            assert_eq!(
+                matches_segment(&pattern, &name),
+                *expected,
+                "{:?} ~ {:?}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn puts_job_in_command() {
+        let cases: [(&[&str], &[&str]); 4] = [
+            // without `{}`, the job is appended
+            (&["build"], &["build", "f/app"]),
+            (&["build", "-s"], &["build", "-s", "f/app"]),
+            (&["build", "{}", "-s"], &["build", "f/app", "-s"]),
+            (
+                &["copy-job", "{}", "{}-copy", "x{}{}"],
+                &["copy-job", "f/app", "f/app-copy", "xf/appf/app"],
+            ),
+        ];
+        for (command, expected) in cases.iter() {
+            let command: Vec<String> = command.iter().map(|a| a.to_string()).collect();
+            assert_eq!(command_for(&command, "f/app"), *expected, "{:?}", command);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use crate::output::{Output, Writer};
+use crate::{Config, Context};
+use jk::jenkins;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The result of a command on a server, as written by `--output json`, or
+/// last by `--output jsonl` after the output.
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    pub server: String,
+    pub args: Vec<String>,
+    /// Absent when streamed as chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// Absent when the command did not run to its end
+    pub exit_code: Option<i32>,
+    /// Seconds from the start of the command to its end
+    pub duration: f64,
+    /// Why the command did not run to its end
+    pub error: Option<Failure>,
+}
+
+/// An error of jk, rather than of the command.
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    /// `interrupted`, `timeout`, `http` when jenkins answered with an
+    /// unexpected status, `transient` when trying again may succeed, or
+    /// `error`
+    pub kind: &'static str,
+    /// HTTP status of jenkins, for `http`
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+/// Output of a command as written by `--output jsonl`, as it arrives.
+#[derive(Serialize)]
+struct Chunk<'a> {
+    server: &'a str,
+    /// `stdout` or `stderr`
+    stream: &'static str,
+    data: &'a str,
+}
+
+impl Envelope {
+    /// Runs `args` on the server `name`, collecting its output.
+    pub fn run(config: &Arc<Config>, name: &str, args: &[String]) -> Envelope {
+        let (output, capture) = Output::capture();
+        let envelope = Envelope::run_with(config, name, args, &output);
+        Envelope {
+            stdout: Some(capture.stdout()),
+            stderr: Some(capture.stderr()),
+            ..envelope
+        }
+    }
+
+    /// Runs `args` on the server `name`, writing its output to the stdout
+    /// of `to` as a line for each chunk.
+    pub fn stream(config: &Arc<Config>, name: &str, args: &[String], to: &Output) -> Envelope {
+        let output = Output::split(
+            Arc::new(Mutex::new(ChunkWriter::new(name, "stdout", to.out()))),
+            Arc::new(Mutex::new(ChunkWriter::new(name, "stderr", to.out()))),
+        );
+        Envelope::run_with(config, name, args, &output)
+    }
+
+    fn run_with(config: &Arc<Config>, name: &str, args: &[String], output: &Output) -> Envelope {
+        let start = Instant::now();
+        let result =
+            Context::new(config, name).and_then(|ctx| crate::run_command(&ctx, args, output));
+        let retry = config
+            .servers
+            .get(name)
+            .map(|server| server.retry.clone())
+            .unwrap_or_default();
+        Envelope {
+            server: name.to_string(),
+            args: args.to_vec(),
+            stdout: None,
+            stderr: None,
+            exit_code: result.as_ref().ok().copied(),
+            duration: start.elapsed().as_secs_f64(),
+            error: result.err().map(|err| Failure::classify(&err, &retry)),
+        }
+    }
+
+    /// The exit code of jk for this result, as without `--output json`.
+    pub fn code(&self) -> i32 {
+        match (&self.exit_code, &self.error) {
+            (Some(code), _) => *code,
+            (None, Some(failure)) if failure.kind == "timeout" => jenkins::timeout::EXIT_CODE,
+            (None, _) => 1,
+        }
+    }
+
+    /// The envelope on a line.
+    pub fn to_line(&self) -> String {
+        to_line(self)
+    }
+}
+
+fn to_line<T: Serialize>(value: &T) -> String {
+    let mut line = serde_json::to_string(value).expect("serializable to JSON");
+    line.push('\n');
+    line
+}
+
+impl Failure {
+    fn classify(err: &anyhow::Error, retry: &jenkins::retry::Retry) -> Failure {
+        let status = http_status(err);
+        let kind = if err
+            .chain()
+            .any(|cause| cause.is::<jenkins::interrupt::Interrupted>())
+        {
+            "interrupted"
+        } else if jenkins::timeout::is_timeout(err) {
+            "timeout"
+        } else if status.is_some() {
+            "http"
+        } else if retry.is_transient(err) {
+            "transient"
+        } else {
+            "error"
+        };
+        Failure {
+            kind,
+            status,
+            message: format!("{:#}", err),
+        }
+    }
+}
+
+/// The HTTP status jenkins answered with, when it caused `err`.
+fn http_status(err: &anyhow::Error) -> Option<u16> {
+    err.chain().find_map(|cause| {
+        if let Some(status) = cause.downcast_ref::<jenkins::retry::HttpStatus>() {
+            Some(status.status)
+        } else if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            err.status().map(|status| status.as_u16())
+        } else if let Some(tungstenite::Error::Http(resp)) = cause.downcast_ref() {
+            Some(resp.status().as_u16())
+        } else {
+            None
+        }
+    })
+}
+
+/// Writes what it is given to `out` as `Chunk` lines. A character cut
+/// between two writes is held until the next one.
+struct ChunkWriter {
+    server: String,
+    stream: &'static str,
+    out: Writer,
+    /// The start of a character cut by the last write
+    pending: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(server: &str, stream: &'static str, out: Writer) -> ChunkWriter {
+        ChunkWriter {
+            server: server.to_string(),
+            stream,
+            out,
+            pending: Vec::new(),
+        }
+    }
+
+    fn write_chunk(&mut self, data: &str) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let line = to_line(&Chunk {
+            server: &self.server,
+            stream: self.stream,
+            data,
+        });
+        // a single write, so that lines are not mixed
+        self.out.write_all(line.as_bytes())
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let end = match std::str::from_utf8(&self.pending) {
+            // the end of the last character is still to come
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            _ => self.pending.len(),
+        };
+        let chunk: Vec<u8> = self.pending.drain(..end).collect();
+        self.write_chunk(&String::from_utf8_lossy(&chunk))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        let _ = self.write_chunk(&String::from_utf8_lossy(&pending));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jenkins::interrupt::Interrupted;
+    use jenkins::retry::{HttpStatus, Retry};
+    use jenkins::timeout::TimedOut;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn classifies_errors() {
+        let retry = Retry::default();
+        let status = |status| HttpStatus {
+            side: "RECV",
+            url: "http://jenkins/cli".to_string(),
+            status,
+        };
+        let cases: Vec<(anyhow::Error, &str, Option<u16>)> = vec![
+            (Interrupted.into(), "interrupted", None),
+            (
+                anyhow::Error::from(Interrupted).context("reading"),
+                "interrupted",
+                None,
+            ),
+            (TimedOut.into(), "timeout", None),
+            (Error::from(ErrorKind::TimedOut).into(), "timeout", None),
+            (status(403).into(), "http", Some(403)),
+            (status(503).into(), "http", Some(503)),
+            (
+                Error::from(ErrorKind::ConnectionRefused).into(),
+                "transient",
+                None,
+            ),
+            (
+                Error::from(ErrorKind::UnexpectedEof).into(),
+                "transient",
+                None,
+            ),
+            (
+                Error::from(ErrorKind::PermissionDenied).into(),
+                "error",
+                None,
+            ),
+            (anyhow::anyhow!("no server x found"), "error", None),
+        ];
+        for (err, kind, status) in cases {
+            let failure = Failure::classify(&err, &retry);
+            assert_eq!((failure.kind, failure.status), (kind, status), "{:#}", err);
+            assert_eq!(failure.message, format!("{:#}", err));
+        }
+    }
+
+    fn envelope(exit_code: Option<i32>, kind: Option<&'static str>) -> Envelope {
+        Envelope {
+            server: "s".to_string(),
+            args: vec!["who-am-i".to_string()],
+            stdout: None,
+            stderr: None,
+            exit_code,
+            duration: 0.5,
+            error: kind.map(|kind| Failure {
+                kind,
+                status: None,
+                message: String::new(),
+            }),
+        }
+    }
+
+    #[test]
+    fn exits_as_without_json() {
+        assert_eq!(envelope(Some(0), None).code(), 0);
+        assert_eq!(envelope(Some(5), None).code(), 5);
+        assert_eq!(
+            envelope(None, Some("timeout")).code(),
+            jenkins::timeout::EXIT_CODE
+        );
+        assert_eq!(envelope(None, Some("http")).code(), 1);
+    }
+
+    #[test]
+    fn skips_streamed_output() {
+        assert_eq!(
+            envelope(Some(0), None).to_line(),
+            "{\"server\":\"s\",\"args\":[\"who-am-i\"],\"exit_code\":0,\
+             \"duration\":0.5,\"error\":null}\n"
+        );
+    }
+
+    #[test]
+    fn writes_chunks_of_whole_characters() {
+        let (output, buf) = Output::buffer();
+        let mut writer = ChunkWriter::new("s", "stdout", output.out());
+        // é cut in two
+        writer.write_all(b"caf\xc3").unwrap();
+        writer.write_all(b"\xa9\n").unwrap();
+        writer.write_all(b"\xff").unwrap();
+        drop(writer);
+        let lines = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            lines,
+            "{\"server\":\"s\",\"stream\":\"stdout\",\"data\":\"caf\"}\n\
+             {\"server\":\"s\",\"stream\":\"stdout\",\"data\":\"é\\n\"}\n\
+             {\"server\":\"s\",\"stream\":\"stdout\",\"data\":\"\u{fffd}\"}\n"
+        );
+    }
+}
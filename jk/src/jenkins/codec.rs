@@ -1,14 +1,22 @@
 use super::{Code, Frame};
 use crate::jenkins;
-use anyhow::Result;
-use std::io::Write;
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use log::debug;
+use std::convert::{TryFrom, TryInto};
+use std::io::{ErrorKind, Read, Write};
+
+/// Longest data of a frame decoded from a buffer. Jenkins writes the output
+/// in frames of a few kilobytes, so a longer one is a corrupted stream,
+/// whose data must not be buffered until it is complete.
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
 
 pub struct Encoder<'a, T: jenkins::Transport> {
     w: &'a mut T,
 }
 
 impl<T: jenkins::Transport> Encoder<'_, T> {
-    pub fn new(writer: &mut T) -> Encoder<T> {
+    pub fn new(writer: &mut T) -> Encoder<'_, T> {
         Encoder { w: writer }
     }
 
@@ -19,15 +27,279 @@ impl<T: jenkins::Transport> Encoder<'_, T> {
     pub fn op(&mut self, op: Code) -> Result<()> {
         self.frame(&Frame {
             op,
-            data: vec![0; 0],
+            data: Bytes::new(),
         })
     }
 
+    /// Writes `s` prefixed by its length, which the protocol holds in 16
+    /// bits: longer strings are an error.
     pub fn string(&mut self, op: Code, s: &str) -> Result<()> {
         let str_bytes = s.as_bytes();
+        let len = u16::try_from(str_bytes.len()).map_err(|_| {
+            anyhow!(
+                "{:?} of {} bytes too long, at most {} bytes can be sent",
+                op,
+                str_bytes.len(),
+                u16::MAX
+            )
+        })?;
         let mut data = Vec::with_capacity(2 + str_bytes.len());
-        data.write_all(&(str_bytes.len() as u16).to_be_bytes())?;
+        data.write_all(&len.to_be_bytes())?;
         data.write_all(str_bytes)?;
-        self.frame(&Frame { op, data })
+        self.frame(&Frame {
+            op,
+            data: data.into(),
+        })
+    }
+}
+
+/// Writes `f` as the CLI protocol does on a stream: its length, op and data.
+pub fn write_frame<W: Write>(w: &mut W, f: &Frame) -> Result<()> {
+    let len = u32::try_from(f.data.len())
+        .map_err(|_| anyhow!("{:?} frame of {} bytes too long", f.op, f.data.len()))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(&(f.op as u8).to_be_bytes())?;
+    w.write_all(&f.data)?;
+    Ok(())
+}
+
+/// Reads a frame written by `write_frame`.
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Frame> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    let len = u32::from_be_bytes(buf) as usize;
+
+    r.read_exact(&mut buf[0..1])?;
+    let op = buf[0].try_into()?;
+    debug!("read frame {:?} of {} bytes", op, len);
+
+    // the buffer grows with the data actually read, whatever the length
+    // claimed by the peer
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Frame {
+        op,
+        data: data.into(),
+    })
+}
+
+/// Encodes `f` as a websocket message: its op then its data.
+pub fn encode_message(f: &Frame) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(f.data.len() + 1);
+    buf.push(f.op as u8);
+    buf.extend_from_slice(&f.data);
+    buf
+}
+
+/// Decodes a message encoded by `encode_message`, its data sharing the
+/// memory of `buf`.
+pub fn decode_message(mut buf: Bytes) -> Result<Frame> {
+    if buf.is_empty() {
+        return Err(anyhow!("empty websocket message"));
+    }
+    let op = buf.split_to(1)[0].try_into()?;
+    Ok(Frame { op, data: buf })
+}
+
+/// Decodes the first frame of `buf` written by `write_frame` and removes it,
+/// or returns `None` while it is incomplete. The data of the frame shares
+/// the memory of `buf`.
+pub fn decode(buf: &mut BytesMut) -> Result<Option<Frame>> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buf[0..4].try_into()?) as usize;
+    if len > MAX_FRAME {
+        return Err(anyhow!(
+            "frame of {} bytes too long, at most {} bytes are decoded",
+            len,
+            MAX_FRAME
+        ));
+    }
+    if buf.len() - 5 < len {
+        return Ok(None);
+    }
+    let op = buf[4].try_into()?;
+    debug!("read frame {:?} of {} bytes", op, len);
+    buf.advance(5);
+    Ok(Some(Frame {
+        op,
+        data: buf.split_to(len).freeze(),
+    }))
+}
+
+/// Reads the string of a frame written by `Encoder::string`.
+pub fn read_string(data: &[u8]) -> Result<String> {
+    if data.len() < 2 {
+        return Err(anyhow!("string frame too short"));
+    }
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let bytes = data
+        .get(2..2 + len)
+        .ok_or_else(|| anyhow!("string frame too short"))?;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+/// Reads the exit code held by the data of an `Exit` frame.
+pub fn read_exit_code(data: &[u8]) -> Result<i32> {
+    let code = data
+        .get(0..4)
+        .ok_or_else(|| anyhow!("exit frame too short"))?;
+    Ok(i32::from_be_bytes(code.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// Collects the frames written by an `Encoder`.
+    #[derive(Default)]
+    struct Frames(Vec<Frame>);
+
+    impl jenkins::Transport for Frames {
+        fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+            self.0.push(frame.clone());
+            Ok(())
+        }
+
+        fn read_frame(&mut self) -> Result<Frame> {
+            Err(anyhow!("write only"))
+        }
+
+        fn close_input(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn code() -> impl Strategy<Value = Code> {
+        (0..=8u8).prop_map(|i| Code::try_from(i).unwrap())
+    }
+
+    proptest! {
+        #[test]
+        fn frame_round_trips(op in code(), data in vec(any::<u8>(), 0..1024)) {
+            let mut buf = Vec::new();
+            write_frame(&mut buf, &Frame { op, data: data.clone().into() }).unwrap();
+            let f = read_frame(&mut &buf[..]).unwrap();
+            prop_assert_eq!(f.op, op);
+            prop_assert_eq!(f.data, data);
+        }
+
+        #[test]
+        fn frames_round_trip_in_sequence(frames in vec((code(), vec(any::<u8>(), 0..64)), 0..16)) {
+            let mut buf = Vec::new();
+            for (op, data) in &frames {
+                write_frame(&mut buf, &Frame { op: *op, data: data.clone().into() }).unwrap();
+            }
+            let mut r = &buf[..];
+            for (op, data) in &frames {
+                let f = read_frame(&mut r).unwrap();
+                prop_assert_eq!(f.op, *op);
+                prop_assert_eq!(&f.data, data);
+            }
+            prop_assert!(r.is_empty());
+        }
+
+        #[test]
+        fn message_round_trips(op in code(), data in vec(any::<u8>(), 0..1024)) {
+            let buf = encode_message(&Frame { op, data: data.clone().into() });
+            let f = decode_message(buf.into()).unwrap();
+            prop_assert_eq!(f.op, op);
+            prop_assert_eq!(f.data, data);
+        }
+
+        #[test]
+        fn string_round_trips(s in ".{0,256}") {
+            let mut frames = Frames::default();
+            Encoder::new(&mut frames).string(Code::Arg, &s).unwrap();
+            prop_assert_eq!(read_string(&frames.0[0].data).unwrap(), s);
+        }
+
+        #[test]
+        fn rejects_truncated_frame(op in code(), data in vec(any::<u8>(), 0..256), cut in any::<prop::sample::Index>()) {
+            let mut buf = Vec::new();
+            write_frame(&mut buf, &Frame { op, data: data.into() }).unwrap();
+            let len = cut.index(buf.len());
+            prop_assert!(read_frame(&mut &buf[..len]).is_err());
+        }
+
+        #[test]
+        fn decodes_any_input_without_panic(buf in vec(any::<u8>(), 0..64)) {
+            if let Ok(f) = read_frame(&mut &buf[..]) {
+                let _ = read_string(&f.data);
+                let _ = read_exit_code(&f.data);
+            }
+            let _ = decode_message(buf.clone().into());
+            let _ = decode(&mut BytesMut::from(&buf[..]));
+        }
+
+        #[test]
+        fn decodes_frames_split_anywhere(
+            frames in vec((code(), vec(any::<u8>(), 0..64)), 0..16),
+            sizes in vec(1..32usize, 1..64),
+        ) {
+            let mut stream = Vec::new();
+            for (op, data) in &frames {
+                write_frame(&mut stream, &Frame { op: *op, data: data.clone().into() }).unwrap();
+            }
+            // the stream is received in chunks of random sizes
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            let mut rest = &stream[..];
+            for size in sizes.iter().cycle() {
+                while let Some(f) = decode(&mut buf).unwrap() {
+                    decoded.push((f.op, f.data.to_vec()));
+                }
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+                buf.extend_from_slice(chunk);
+                rest = tail;
+            }
+            prop_assert_eq!(decoded, frames);
+            prop_assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_string_longer_than_length_field() {
+        let mut frames = Frames::default();
+        let mut encoder = Encoder::new(&mut frames);
+        encoder.string(Code::Arg, &"a".repeat(65535)).unwrap();
+        let err = encoder.string(Code::Arg, &"a".repeat(65536)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Arg of 65536 bytes too long, at most 65535 bytes can be sent"
+        );
+        assert_eq!(frames.0.len(), 1);
+    }
+
+    #[test]
+    fn rejects_too_long_frames() {
+        let frame = |len: usize| {
+            let mut header = (len as u32).to_be_bytes().to_vec();
+            header.push(Code::Stdout as u8);
+            BytesMut::from(&header[..])
+        };
+        assert!(decode(&mut frame(MAX_FRAME)).unwrap().is_none());
+        let err = decode(&mut frame(MAX_FRAME + 1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "frame of 16777217 bytes too long, at most 16777216 bytes are decoded"
+        );
+    }
+
+    #[test]
+    fn reads_frame_claiming_more_than_sent() {
+        let buf = [0xff, 0xff, 0xff, 0xff, Code::Stdout as u8, b'a'];
+        let err = read_frame(&mut &buf[..]).unwrap_err();
+        let cause = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(cause.kind(), ErrorKind::UnexpectedEof);
     }
 }
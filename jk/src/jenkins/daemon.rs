@@ -0,0 +1,335 @@
+use super::interrupt::{self, Stop};
+use super::{codec, timeout, Cli, Code, Frame, Server};
+use crate::jenkins::{self, Transport as _};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the daemon waits before accepting clients again after failing
+/// to, so as not to spin while out of file descriptors.
+const ACCEPT_PAUSE: Duration = Duration::from_millis(100);
+
+/// Path of the socket the daemon listens on.
+pub fn socket_path() -> Option<PathBuf> {
+    let mut path = dirs::runtime_dir().or_else(dirs::cache_dir)?;
+    path.push("jk");
+    path.push("daemon.sock");
+    Some(path)
+}
+
+/// A command sent through the daemon.
+///
+/// The client first sends a frame describing the server, then the frames
+/// of the command as it would send them to jenkins. The daemon runs the
+/// command on its own connection to the server and sends back its output
+/// and exit code. The client closes its side of the socket to stop the
+/// command.
+pub struct Transport {
+    stream: UnixStream,
+    stop: Stop,
+    /// When the socket was closed for the command to stop
+    closed: Option<Instant>,
+}
+
+impl Transport {
+    /// Connects to the daemon, or returns `None` if it is not running.
+    pub fn connect(server: &Server, stop: &Stop) -> Result<Option<Transport>> {
+        let stream = match socket_path().map(UnixStream::connect) {
+            Some(Ok(stream)) => stream,
+            _ => return Ok(None),
+        };
+        debug!("sending command through the daemon");
+        let mut transport = Transport {
+            stream,
+            stop: stop.clone(),
+            closed: None,
+        };
+        transport.write_frame(&Frame {
+            op: Code::Arg,
+            data: toml::to_string(server)?.into_bytes().into(),
+        })?;
+        Ok(Some(transport))
+    }
+}
+
+impl jenkins::Transport for Transport {
+    fn write_frame(&mut self, f: &Frame) -> Result<()> {
+        codec::write_frame(&mut self.stream, f)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        // wait for the start of a frame, checking whether the command must
+        // stop, the daemon then has `GRACE` to send the exit code
+        self.stream.set_read_timeout(Some(interrupt::POLL))?;
+        let mut first = [0; 1];
+        loop {
+            match self.stream.read(&mut first) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(_) => break,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let stream = &self.stream;
+                    self.stop
+                        .check(&mut self.closed, || Ok(stream.shutdown(Shutdown::Write)?))?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.stream.set_read_timeout(None)?;
+        codec::read_frame(&mut (&first[..]).chain(&mut self.stream))
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Asks the running daemon to stop. Returns `false` if none is running.
+pub fn stop() -> Result<bool> {
+    let mut stream = match socket_path().map(UnixStream::connect) {
+        Some(Ok(stream)) => stream,
+        _ => return Ok(false),
+    };
+    codec::write_frame(
+        &mut stream,
+        &Frame {
+            op: Code::Exit,
+            data: Bytes::new(),
+        },
+    )?;
+    Ok(true)
+}
+
+/// Listens on the daemon socket and runs the commands of clients against
+/// jenkins, keeping one HTTP client per server so that its connections are
+/// reused from one command to the next. Returns when asked to stop.
+pub fn serve() -> Result<()> {
+    let path = socket_path().ok_or_else(|| anyhow!("no runtime dir found"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    if UnixStream::connect(&path).is_ok() {
+        return Err(anyhow!("daemon already listening on {}", path.display()));
+    }
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&path)?;
+    // clients send credentials over the socket
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    eprintln!("listening on {}", path.display());
+    accept(listener, &path)?;
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+/// Serves each client of `listener`, bound to `path`, in a thread of its
+/// own, until one asks to stop.
+fn accept(listener: UnixListener, path: &Path) -> Result<()> {
+    let pool: Arc<Mutex<HashMap<String, Cli>>> = Arc::new(Mutex::new(HashMap::new()));
+    let stopped = Arc::new(AtomicBool::new(false));
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            // out of file descriptors for example, which may not last
+            Err(err) => {
+                warn!("accepting client: {}", err);
+                thread::sleep(ACCEPT_PAUSE);
+                continue;
+            }
+        };
+        let pool = pool.clone();
+        let stopped = stopped.clone();
+        let path = path.to_path_buf();
+        // the first frame is read in the thread, so that a client sending
+        // nothing does not hold up the others
+        thread::spawn(move || {
+            let first = match codec::read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    warn!("reading client request: {:#}", err);
+                    return;
+                }
+            };
+            if let Code::Exit = first.op {
+                stopped.store(true, Ordering::SeqCst);
+                // wakes up the listener
+                let _ = UnixStream::connect(&path);
+            } else if let Err(err) = relay(&mut stream, &first, &pool) {
+                report(&mut stream, &err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Runs one command, `server` being the frame describing its server.
+fn relay(
+    stream: &mut UnixStream,
+    server: &Frame,
+    pool: &Mutex<HashMap<String, Cli>>,
+) -> Result<()> {
+    let key = String::from_utf8(server.data.to_vec())?;
+    let cli = {
+        let mut pool = pool.lock().unwrap();
+        match pool.get(&key) {
+            Some(cli) => cli.clone(),
+            None => {
+                let cli = Cli::new(toml::from_str(&key)?)?;
+                pool.insert(key, cli.clone());
+                cli
+            }
+        }
+    };
+    let mut args = Vec::new();
+    loop {
+        let f = codec::read_frame(stream)?;
+        match f.op {
+            Code::Arg => args.push(codec::read_string(&f.data)?),
+            Code::Start => break,
+            _ => {}
+        }
+    }
+    let stop = Stop::new(cli.cfg.timeout.deadline());
+    // the client closes the socket when the command must stop
+    let watch = stream.try_clone()?;
+    let cancel = stop.clone();
+    thread::spawn(move || {
+        let _ = (&watch).read(&mut [0; 1]);
+        cancel.cancel();
+    });
+    let transport = cli.start(&args, &stop)?;
+    let code = cli.receive(&args, &stop, transport, &cli.cfg.retry, |f| {
+        codec::write_frame(stream, &f)
+    })?;
+    codec::write_frame(
+        stream,
+        &Frame {
+            op: Code::Exit,
+            data: Bytes::copy_from_slice(&code.to_be_bytes()),
+        },
+    )
+}
+
+/// Sends `err` to the client as the output of a command failing.
+fn report(stream: &mut UnixStream, err: &anyhow::Error) {
+    let message = format!("jk daemon: {:#}\n", err);
+    let code = if timeout::is_timeout(err) {
+        timeout::EXIT_CODE
+    } else {
+        1
+    };
+    let _ = codec::write_frame(
+        stream,
+        &Frame {
+            op: Code::Stderr,
+            data: message.into_bytes().into(),
+        },
+    );
+    let _ = codec::write_frame(
+        stream,
+        &Frame {
+            op: Code::Exit,
+            data: Bytes::copy_from_slice(&code.to_be_bytes()),
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Sends `frames` to `stream`, then reads the answer until `Exit`,
+    /// returning the output and the exit code.
+    fn exchange(stream: &mut UnixStream, frames: &[Frame]) -> (String, i32) {
+        for f in frames {
+            codec::write_frame(stream, f).unwrap();
+        }
+        let mut output = String::new();
+        loop {
+            let f = codec::read_frame(stream).unwrap();
+            match f.op {
+                Code::Exit => return (output, codec::read_exit_code(&f.data).unwrap()),
+                _ => output.push_str(&String::from_utf8_lossy(&f.data)),
+            }
+        }
+    }
+
+    #[test]
+    fn serves_clients_while_one_stalls() {
+        let dir = std::env::temp_dir().join(format!("jk-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("daemon.sock");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon = thread::spawn({
+            let path = path.clone();
+            move || accept(listener, &path)
+        });
+
+        // connects and sends nothing
+        let _stalled = UnixStream::connect(&path).unwrap();
+
+        // nothing listens on port 1, so the command fails at once
+        let server: Server = toml::from_str(
+            r#"
+            url = "http://127.0.0.1:1"
+            username = "u"
+            password = "p"
+            [retry]
+            attempts = 1
+            "#,
+        )
+        .unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let frames = [
+            Frame {
+                op: Code::Arg,
+                data: toml::to_string(&server).unwrap().into_bytes().into(),
+            },
+            Frame {
+                op: Code::Arg,
+                data: Bytes::from_static(b"\0\x08who-am-i"),
+            },
+            Frame {
+                op: Code::Start,
+                data: Bytes::new(),
+            },
+        ];
+        let (output, code) = exchange(&mut client, &frames);
+        assert_eq!(code, 1);
+        assert!(output.starts_with("jk daemon: "), "{}", output);
+
+        let mut stopping = UnixStream::connect(&path).unwrap();
+        codec::write_frame(
+            &mut stopping,
+            &Frame {
+                op: Code::Exit,
+                data: Bytes::new(),
+            },
+        )
+        .unwrap();
+        daemon.join().unwrap().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,198 +1,543 @@
-use super::Cli;
+use super::interrupt::{self, Stop};
+use super::retry::HttpStatus;
+use super::{codec, timeout, Cli, Stream};
 use crate::jenkins;
 use crate::jenkins::Frame;
 use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
 use log::debug;
-use pipe::{pipe, PipeReader, PipeWriter};
 use reqwest::blocking;
-use std::convert::TryInto;
-use std::io::{Read, Write};
-use std::sync::{Arc, Barrier};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::SocketAddr;
 use std::thread;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-pub struct Transport {
-    server_thread: Option<thread::JoinHandle<Result<()>>>,
-    server_output: PipeReader,
+/// Timeout of the requests of a command without deadline. The client
+/// timeout only bounds reads, and reqwest cannot lift it for a request.
+const NO_TIMEOUT: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
 
-    client_thread: Option<thread::JoinHandle<Result<()>>>,
-    client_input: PipeWriter,
+/// How much of the output is read at once.
+const READ_SIZE: usize = 16 * 1024;
 
+/// The two sides of a session: the download side is read by the thread
+/// decoding the output, the upload side is sent by a thread of its own once
+/// the command is written, as jenkins answers it when the command is over.
+pub struct Transport {
+    clt: Cli,
+    uuid: Uuid,
+    stop: Stop,
+    /// `None` once dropped, so that jenkins aborts the command
+    download: Option<Download>,
+    /// `name=value` of the cookies set by jenkins through `proxy_command`,
+    /// reqwest keeping them otherwise
+    cookies: Vec<String>,
+    /// Output read but not decoded yet
+    output: BytesMut,
     initial_zero_skipped: bool,
+    last_read: Instant,
+
+    /// Frames of the command, sent as the body of the upload side
+    input: BytesMut,
+    upload_thread: Option<thread::JoinHandle<Result<()>>>,
 }
 
 impl Transport {
-    pub fn new(clt: &Cli) -> Result<Transport> {
-        // - a thread is spawn to read command output, and wait for main thread to be ready
-        // - main thread prepare the command and wait first thread to be ready to listen
-        let ready = Arc::new(Barrier::new(2));
+    pub fn new(clt: &Cli, stop: &Stop) -> Result<Transport> {
+        // the command is only sent once jenkins accepted the download side,
+        // so that failing to establish the session can be retried
         let uuid = Uuid::new_v4();
-        let (server, output) = Self::recv(clt.clone(), uuid, ready.clone());
-        let (client, input) = Self::send(clt.clone(), uuid, ready);
+        let (download, cookies) = if clt.cfg.proxy_command.is_some() {
+            let timeout = timeout::shortest(clt.cfg.timeout.connect(), stop.remaining()?);
+            let answer = Answer::post(clt, &uuid, "download", &[], &[], timeout)?;
+            answer.check("RECV")?;
+            // reads wake up regularly to check whether the command must stop
+            super::connection(answer.reader.get_ref()).set_read_timeout(Some(interrupt::POLL))?;
+            let cookies = answer.cookies.clone();
+            (Download::Tunnel(answer), cookies)
+        } else {
+            let download = Self::request(clt, &uuid, "download")?
+                // the request timeout bounds the whole response
+                .timeout(stop.remaining()?.unwrap_or(NO_TIMEOUT))
+                .send()?;
+            if !download.status().is_success() {
+                return Err(HttpStatus {
+                    side: "RECV",
+                    url: download.url().to_string(),
+                    status: download.status().as_u16(),
+                }
+                .into());
+            }
+            (Download::Client(download), Vec::new())
+        };
         Ok(Transport {
-            server_thread: Some(server),
-            server_output: output,
-            client_thread: Some(client),
-            client_input: input,
+            clt: clt.clone(),
+            uuid,
+            stop: stop.clone(),
+            download: Some(download),
+            cookies,
+            output: BytesMut::with_capacity(READ_SIZE),
             initial_zero_skipped: false,
+            last_read: Instant::now(),
+            input: BytesMut::new(),
+            upload_thread: None,
         })
     }
 
+    /// The HTTP client of `clt`, built on first use and then shared by all
+    /// the commands it sends, so that connections are kept alive between them.
     fn client(clt: &Cli) -> Result<blocking::Client> {
+        if let Some(client) = clt.http.get() {
+            return Ok(client.clone());
+        }
         let mut builder = blocking::Client::builder()
-            .tcp_keepalive(std::time::Duration::from_secs(1))
-            .timeout(None)
+            .tcp_keepalive(Duration::from_secs(1))
+            // reads of the output wake up regularly to check whether the
+            // command must stop
+            .timeout(interrupt::POLL)
             .cookie_store(true)
             .danger_accept_invalid_certs(true);
         if let Some(proxy) = &clt.cfg.proxy {
             builder = builder.proxy(reqwest::Proxy::all(proxy)?);
         }
-        Ok(builder.build()?)
-    }
-
-    fn recv(
-        clt_server: Cli,
-        uuid: Uuid,
-        server_ready: Arc<Barrier>,
-    ) -> (thread::JoinHandle<Result<()>>, PipeReader) {
-        let (output, mut input) = pipe::pipe();
-
-        let server = thread::spawn(move || -> Result<()> {
-            let clt = Self::client(&clt_server)?;
-            let url = reqwest::Url::parse(&format!("{}/{}", &clt_server.cfg.url, "cli"))?;
-            let mut server_output = clt
-                .post(url)
-                .query(&[("remoting", "false")])
-                .basic_auth(clt_server.cfg.username, Some(clt_server.cfg.password))
-                .header("Session", format!("{}", &uuid))
-                .header("Side", "download")
-                .send()?;
-            if !server_output.status().is_success() {
-                return Err(anyhow!(
-                    "RECV {}: {}",
-                    server_output.url(),
-                    server_output.status()
-                ));
+        if let Some(timeout) = clt.cfg.timeout.connect() {
+            builder = builder.connect_timeout(timeout);
+        }
+        let url = reqwest::Url::parse(&clt.cfg.url)?;
+        if let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) {
+            if let Some(addr) = clt.cfg.resolved(host, port)? {
+                builder = builder.resolve(host, SocketAddr::new(addr, port));
             }
-            server_ready.wait(); // wait for main thread to send the command
-            server_output.copy_to(&mut input)?;
-            input.flush()?;
-            Ok(())
-        });
-        (server, output)
-    }
-
-    fn send(
-        clt_client: Cli,
-        uuid: Uuid,
-        ready: Arc<Barrier>,
-    ) -> (thread::JoinHandle<Result<()>>, PipeWriter) {
-        let (output, input) = pipe::pipe();
-
-        let client = thread::spawn(move || -> Result<()> {
-            let clt = Self::client(&clt_client)?;
-            let url = reqwest::Url::parse(&format!("{}/{}", &clt_client.cfg.url, "cli"))?;
-            let mut req = clt
-                .post(url)
-                .query(&[("remoting", "false")])
-                .basic_auth(&clt_client.cfg.username, Some(&clt_client.cfg.password))
-                .header("Content-Type", "application/octet-stream")
-                .header("Transfer-encoding", "chunked")
-                .header("Session", format!("{}", &uuid))
-                .header("Side", "upload");
-
-            // this works because:
-            // - bytes() return a Iterator of Result,
-            // - Result implements FromIterator trait
-            // - which collect() calls to produce a value from its input
-            let input = output
-                .bytes()
-                .collect::<Result<Vec<u8>, std::io::Error>>()?;
-            req = req.body(input);
-            ready.wait(); // wait for thread to be ready to read the result
-            let rep = req.send().with_context(|| "while sending request... ")?;
-            if !rep.status().is_success() {
-                return Err(anyhow!("SEND {}: {}", rep.url(), rep.status()));
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in &clt.cfg.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("invalid header name {}", name))?,
+                HeaderValue::from_str(value)
+                    .with_context(|| format!("invalid value of header {}", name))?,
+            );
+        }
+        let client = builder.default_headers(headers).build()?;
+        Ok(clt.http.get_or_init(|| client).clone())
+    }
+
+    /// A request for the `side` of the session `uuid`.
+    fn request(clt: &Cli, uuid: &Uuid, side: &str) -> Result<blocking::RequestBuilder> {
+        let url = reqwest::Url::parse(&format!("{}/{}", &clt.cfg.url, "cli"))?;
+        Ok(Self::client(clt)?
+            .post(url)
+            .query(&[("remoting", "false")])
+            .basic_auth(&clt.cfg.username, Some(&clt.cfg.password))
+            .header("Session", format!("{}", uuid))
+            .header("Side", side))
+    }
+
+    /// Reads more of the output, returning `false` at its end. Once the
+    /// command must stop, the download side is dropped so that jenkins
+    /// aborts it.
+    fn fill(&mut self) -> Result<bool> {
+        let download = self
+            .download
+            .as_mut()
+            .ok_or_else(|| anyhow!("RECV: download side closed"))?;
+        let idle = self.clt.cfg.timeout.idle();
+        loop {
+            let len = self.output.len();
+            self.output.resize(len + READ_SIZE, 0);
+            let read = download.read(&mut self.output[len..]);
+            self.output.truncate(len + *read.as_ref().unwrap_or(&0));
+            let last_read = self.last_read;
+            let err = match read {
+                Ok(0) => return Ok(false),
+                Ok(_) => {
+                    self.last_read = Instant::now();
+                    return Ok(true);
+                }
+                Err(err) if is_poll_timeout(&err) => match self.stop.remaining() {
+                    Err(err) => err,
+                    Ok(_) if idle.is_some_and(|idle| last_read.elapsed() >= idle) => {
+                        std::io::Error::from(ErrorKind::TimedOut).into()
+                    }
+                    Ok(_) => continue,
+                },
+                Err(err) => err.into(),
+            };
+            self.download = None;
+            return Err(err);
+        }
+    }
+
+    /// The error of the upload side, once it is answered.
+    fn upload_error(&mut self) -> Option<anyhow::Error> {
+        match self.upload_thread.take() {
+            Some(upload) if upload.is_finished() => join(upload).err(),
+            upload => {
+                self.upload_thread = upload;
+                None
             }
-            Ok(())
-        });
-        (client, input)
+        }
+    }
+}
+
+/// Whether a read of a response failed only for taking longer than the
+/// client timeout, or the read timeout of the connection.
+fn is_poll_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+        || err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<reqwest::Error>())
+            .is_some_and(reqwest::Error::is_timeout)
+}
+
+fn join(thread: thread::JoinHandle<Result<()>>) -> Result<()> {
+    match thread.join() {
+        Ok(result) => result,
+        Err(err) => Err(anyhow!("panic in transport thread: {:?}", err)),
     }
 }
 
 impl jenkins::Transport for Transport {
     fn write_frame(&mut self, f: &Frame) -> Result<()> {
-        self.write_all(&(f.data.len() as u32).to_be_bytes())?;
-        self.write_all(&(f.op as u8).to_be_bytes())?;
-        self.write_all(&f.data)?;
-        Ok(())
+        if self.upload_thread.is_some() {
+            return Err(anyhow!("SEND: input already closed"));
+        }
+        codec::write_frame(&mut (&mut self.input).writer(), f)
     }
 
     fn read_frame(&mut self) -> Result<Frame> {
-        let mut buf = [0; 4];
-        if !self.initial_zero_skipped {
-            self.read_exact(&mut buf[0..1])?;
-            self.initial_zero_skipped = true;
-            debug!("read initial zero");
+        loop {
+            if !self.initial_zero_skipped && !self.output.is_empty() {
+                self.output.advance(1);
+                self.initial_zero_skipped = true;
+                debug!("read initial zero");
+            }
+            if self.initial_zero_skipped {
+                if let Some(f) = codec::decode(&mut self.output)? {
+                    return Ok(f);
+                }
+            }
+            if !self.fill()? {
+                // the output ended early, most likely because of a failed request
+                let err = std::io::Error::from(ErrorKind::UnexpectedEof).into();
+                return Err(self.upload_error().unwrap_or(err));
+            }
         }
-
-        let mut buf = [0; 4];
-        self.read_exact(&mut buf)?;
-        let len = u32::from_be_bytes(buf) as usize;
-        debug!("read frame len: {}", len);
-
-        self.read_exact(&mut buf[0..1])?;
-        debug!("read frame op: {}", buf[0]);
-        let op = buf[0].try_into()?;
-
-        let mut data = vec![0; len];
-        self.read_exact(&mut data)?;
-        debug!("read frame data: {:?}", data);
-        Ok(Frame { op, data })
     }
 
     fn close_input(&mut self) -> Result<()> {
-        self.flush()?;
-
-        // try close input so that client_thread leave bytes()
-        // by replacing it, it should drop the previous one
-        // then output is drop and should close the new input too
-        // it's ugly, but could not find an another way to do it :'(
-        let (_output, input) = pipe();
-        self.client_input = input;
+        if self.clt.cfg.proxy_command.is_some() {
+            let (clt, uuid, stop) = (self.clt.clone(), self.uuid, self.stop.clone());
+            let body = self.input.split().freeze();
+            let cookies = self.cookies.clone();
+            self.upload_thread = Some(thread::spawn(move || -> Result<()> {
+                // jenkins answers the upload side once the command is over
+                let timeout = stop.remaining()?;
+                Answer::post(&clt, &uuid, "upload", &body, &cookies, timeout)?.check("SEND")
+            }));
+            return Ok(());
+        }
+        let req = Self::request(&self.clt, &self.uuid, "upload")?
+            .header("Content-Type", "application/octet-stream")
+            .header("Transfer-encoding", "chunked")
+            // jenkins answers the upload side once the command is over
+            .timeout(self.stop.remaining()?.unwrap_or(NO_TIMEOUT))
+            .body(self.input.split().freeze());
+        self.upload_thread = Some(thread::spawn(move || -> Result<()> {
+            let rep = req.send().with_context(|| "while sending request... ")?;
+            if !rep.status().is_success() {
+                return Err(HttpStatus {
+                    side: "SEND",
+                    url: rep.url().to_string(),
+                    status: rep.status().as_u16(),
+                }
+                .into());
+            }
+            Ok(())
+        }));
         Ok(())
     }
 }
 
-impl std::io::Write for Transport {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.client_input.write(buf)
+/// The answer to the download side.
+enum Download {
+    Client(blocking::Response),
+    Tunnel(Answer),
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Download::Client(resp) => resp.read(buf),
+            Download::Tunnel(answer) => answer.read(buf),
+        }
     }
+}
+
+/// An answer of jenkins through `proxy_command`, which reqwest cannot
+/// connect through.
+struct Answer {
+    url: String,
+    status: u16,
+    /// `name=value` of the cookies set
+    cookies: Vec<String>,
+    reader: BufReader<Stream>,
+    body: Body,
+    /// What was read of the line of the length of a chunk
+    line: Vec<u8>,
+}
+
+/// What is left of the body of an `Answer`.
+enum Body {
+    Length(u64),
+    /// A chunk of the length is left, before the line of the next one
+    Chunked(u64),
+    /// Up to the end of the connection
+    Close,
+}
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.client_input.flush()
+impl Answer {
+    /// Posts `body` to the `side` of the session `uuid` over a new
+    /// connection, waiting for the head of the answer within `timeout`.
+    fn post(
+        clt: &Cli,
+        uuid: &Uuid,
+        side: &str,
+        body: &[u8],
+        cookies: &[String],
+        timeout: Option<Duration>,
+    ) -> Result<Answer> {
+        let url = reqwest::Url::parse(&format!("{}/cli?remoting=false", clt.cfg.url))?;
+        // as the client of reqwest
+        let tls = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        let connect_timeout = timeout::shortest(clt.cfg.timeout.connect(), timeout);
+        let mut stream = clt.open(&url, &tls, connect_timeout)?;
+        let mut head = format!(
+            "POST {}?{} HTTP/1.1\r\nHost: {}",
+            url.path(),
+            url.query().unwrap_or_default(),
+            url.host_str().unwrap_or_default()
+        );
+        if let Some(port) = url.port() {
+            let _ = write!(head, ":{}", port);
+        }
+        let credentials = format!("{}:{}", clt.cfg.username, clt.cfg.password);
+        let _ = write!(
+            head,
+            "\r\nAuthorization: Basic {}\r\nSession: {}\r\nSide: {}\r\n\
+             Content-Type: application/octet-stream\r\nContent-Length: {}\r\n",
+            base64::encode(credentials),
+            uuid,
+            side,
+            body.len()
+        );
+        if !cookies.is_empty() {
+            let _ = write!(head, "Cookie: {}\r\n", cookies.join("; "));
+        }
+        for (name, value) in &clt.cfg.headers {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        super::connection(&stream).set_read_timeout(timeout)?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("{}: invalid answer {:?}", side, line.trim_end()))?;
+        let mut answer = Answer {
+            url: url.to_string(),
+            status,
+            cookies: Vec::new(),
+            reader,
+            body: Body::Close,
+            line: Vec::new(),
+        };
+        loop {
+            line.clear();
+            if answer.reader.read_line(&mut line)? == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            let (name, value) = match line.trim_end().split_once(':') {
+                Some((name, value)) => (name.to_ascii_lowercase(), value.trim()),
+                None => break,
+            };
+            match name.as_str() {
+                "content-length" if !matches!(answer.body, Body::Chunked(_)) => {
+                    answer.body = Body::Length(value.parse()?)
+                }
+                "transfer-encoding" if value.eq_ignore_ascii_case("chunked") => {
+                    answer.body = Body::Chunked(0)
+                }
+                "set-cookie" => {
+                    let cookie = value.split(';').next().unwrap_or_default();
+                    answer.cookies.push(cookie.to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(answer)
+    }
+
+    fn check(&self, side: &'static str) -> Result<()> {
+        if (200..300).contains(&self.status) {
+            Ok(())
+        } else {
+            Err(HttpStatus {
+                side,
+                url: self.url.clone(),
+                status: self.status,
+            }
+            .into())
+        }
+    }
+
+    /// Reads the line of the length of the next chunk. A line cut by the
+    /// read timeout is kept for the next call.
+    fn next_chunk(&mut self) -> std::io::Result<u64> {
+        loop {
+            if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            // the end of the previous chunk
+            if line.trim().is_empty() {
+                continue;
+            }
+            let size = line.split(';').next().unwrap_or_default().trim();
+            return u64::from_str_radix(size, 16)
+                .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "invalid chunk size"));
+        }
     }
 }
 
-impl std::io::Read for Transport {
+impl Read for Answer {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.server_output.read(buf)
+        if let Body::Chunked(0) = self.body {
+            self.body = match self.next_chunk()? {
+                0 => Body::Length(0),
+                size => Body::Chunked(size),
+            };
+        }
+        let left = match &mut self.body {
+            Body::Close => return self.reader.read(buf),
+            Body::Length(left) | Body::Chunked(left) => left,
+        };
+        if *left == 0 {
+            return Ok(0);
+        }
+        let len = buf.len().min(usize::try_from(*left).unwrap_or(usize::MAX));
+        let n = self.reader.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        *left -= n as u64;
+        Ok(n)
     }
 }
 
 impl Drop for Transport {
     fn drop(&mut self) {
-        self.server_thread
-            .take()
-            .unwrap()
-            .join()
-            .unwrap()
-            .expect("error in server thread");
-        self.client_thread
-            .take()
-            .unwrap()
-            .join()
-            .unwrap()
-            .expect("error in client thread");
+        // a command still running is aborted before waiting for the upload
+        // side, which jenkins only answers once the command is over
+        self.download = None;
+        // errors here are those of a command which already failed
+        if let Some(Err(err)) = self.upload_thread.take().map(join) {
+            debug!("transport thread: {:#}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Cli` whose `proxy_command` answers `answer`, whatever it is sent.
+    fn tunnel(answer: &str) -> Cli {
+        let server = toml::from_str(&format!(
+            "url = \"http://jenkins:8080\"\nusername = \"u\"\npassword = \"p\"\n\
+             proxy_command = \"printf '{}'; cat >/dev/null\"",
+            answer
+        ))
+        .unwrap();
+        Cli::new(server).unwrap()
+    }
+
+    fn post(answer: &str) -> Result<Answer> {
+        let timeout = Some(Duration::from_secs(5));
+        Answer::post(
+            &tunnel(answer),
+            &Uuid::new_v4(),
+            "download",
+            &[],
+            &[],
+            timeout,
+        )
+    }
+
+    #[test]
+    fn reads_chunked_answer() {
+        let mut answer = post(
+            "HTTP/1.1 200 OK\\\\r\\\\nSet-Cookie: JSESSIONID=1; Path=/\\\\r\\\\n\
+             Transfer-Encoding: chunked\\\\r\\\\n\\\\r\\\\n\
+             3\\\\r\\\\nabc\\\\r\\\\n2;x=y\\\\r\\\\nde\\\\r\\\\n0\\\\r\\\\n\\\\r\\\\n",
+        )
+        .unwrap();
+        answer.check("RECV").unwrap();
+        assert_eq!(answer.cookies, ["JSESSIONID=1"]);
+        let mut body = String::new();
+        answer.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abcde");
+    }
+
+    #[test]
+    fn reads_answer_of_known_length() {
+        let mut answer =
+            post("HTTP/1.1 403 Forbidden\\\\r\\\\nContent-Length: 6\\\\r\\\\n\\\\r\\\\ndenied")
+                .unwrap();
+        let err = answer.check("RECV").unwrap_err();
+        assert_eq!(err.downcast_ref::<HttpStatus>().unwrap().status, 403);
+        let mut body = String::new();
+        answer.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "denied");
+    }
+
+    #[test]
+    fn rejects_headers_adding_others() {
+        for (headers, message) in [
+            (
+                "X-Gateway = \"a\\r\\nX-Admin: 1\"",
+                "invalid value of header X-Gateway",
+            ),
+            (
+                "\"X-Admin: 1\\r\\nX-Gateway\" = \"a\"",
+                "invalid header name X-Admin: 1\r\nX-Gateway",
+            ),
+        ] {
+            let server: jenkins::Server = toml::from_str(&format!(
+                "url = \"http://jenkins:8080\"\nusername = \"u\"\n[headers]\n{}",
+                headers
+            ))
+            .unwrap();
+            let err = Cli::new(server).err().unwrap();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_answer() {
+        assert!(post("SSH-2.0-OpenSSH\\\\r\\\\n").is_err());
     }
 }
@@ -0,0 +1,157 @@
+use super::timeout::TimedOut;
+use anyhow::Result;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit code of jk when interrupted, as for shells.
+pub const EXIT_CODE: i32 = 130;
+
+/// How long interrupted commands have to end before jk exits anyway.
+pub const GRACE: Duration = Duration::from_secs(2);
+
+/// How often blocked reads check whether their command must stop.
+pub const POLL: Duration = Duration::from_millis(250);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops the running commands on SIGINT and SIGTERM, and exits with
+/// `EXIT_CODE` if they are not over after `GRACE`.
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        thread::sleep(GRACE);
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            std::process::exit(EXIT_CODE);
+        }
+    })?;
+    Ok(())
+}
+
+/// Whether jk has been interrupted since the last `reset`.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Forgets an interruption once the interrupted commands are over, for jk
+/// to keep running.
+pub fn reset() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// When a running command must stop: once past its deadline, when it is
+/// cancelled, or when jk is interrupted.
+#[derive(Debug, Clone, Default)]
+pub struct Stop {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Stop {
+    pub fn new(deadline: Option<Instant>) -> Stop {
+        Stop {
+            deadline,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || is_interrupted()
+    }
+
+    /// Time left before the deadline, failing once the command must stop.
+    pub fn remaining(&self) -> Result<Option<Duration>> {
+        if self.is_cancelled() {
+            return Err(Interrupted.into());
+        }
+        match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Ok(Some(left)),
+                _ => Err(TimedOut.into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Checks, while waiting for jenkins, whether the command must stop.
+    /// The first time it must, `close` is called for jenkins to abort the
+    /// command and the time is kept in `closed`: when interrupted, jenkins
+    /// then has `GRACE` to send the exit code. Returns whether the command
+    /// is stopping.
+    pub fn check<F>(&self, closed: &mut Option<Instant>, close: F) -> Result<bool>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let err = match self.remaining() {
+            Ok(_) => return Ok(false),
+            Err(err) => err,
+        };
+        let closed = match closed {
+            Some(closed) => *closed,
+            None => {
+                close()?;
+                *closed.insert(Instant::now())
+            }
+        };
+        if !err.is::<Interrupted>() || closed.elapsed() >= GRACE {
+            return Err(err);
+        }
+        Ok(true)
+    }
+}
+
+/// A command stopped before its end.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closes_once_when_stopping() {
+        let mut closes = 0;
+        let mut closed = None;
+        let stop = Stop::new(None);
+        let running = stop.check(&mut closed, || panic!("closed while running"));
+        assert!(!running.unwrap());
+
+        stop.cancel();
+        for _ in 0..2 {
+            let stopping = stop.check(&mut closed, || {
+                closes += 1;
+                Ok(())
+            });
+            assert!(stopping.unwrap());
+        }
+        assert_eq!(closes, 1);
+
+        // the grace period is over
+        closed = Instant::now().checked_sub(GRACE);
+        let err = stop.check(&mut closed, || Ok(())).unwrap_err();
+        assert!(err.is::<Interrupted>());
+    }
+
+    #[test]
+    fn gives_no_grace_past_deadline() {
+        let mut closed = None;
+        let stop = Stop::new(Some(Instant::now()));
+        let err = stop.check(&mut closed, || Ok(())).unwrap_err();
+        assert!(err.is::<TimedOut>());
+        assert!(closed.is_some());
+    }
+}
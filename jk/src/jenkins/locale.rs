@@ -0,0 +1,184 @@
+use super::Code;
+use anyhow::{anyhow, Result};
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use std::env;
+
+/// Locale asked to jenkins when neither the configuration nor the
+/// environment gives one.
+const DEFAULT_LOCALE: &str = "en";
+
+/// The first of the environment variables `vars` which is set, looked up
+/// in the order of setlocale(3) with `get`.
+fn env_var<F: Fn(&str) -> Option<String>>(vars: &[&str], get: F) -> Option<String> {
+    vars.iter()
+        .filter_map(|var| get(var))
+        .find(|value| !value.is_empty())
+}
+
+/// The locale `configured` for a server, or the locale of messages in the
+/// environment, as java names it: `fr_FR` for `fr_FR.UTF-8@euro`.
+pub fn locale(configured: Option<&str>) -> String {
+    locale_with(configured, |var| env::var(var).ok())
+}
+
+fn locale_with<F: Fn(&str) -> Option<String>>(configured: Option<&str>, get: F) -> String {
+    let name = configured
+        .map(str::to_string)
+        .or_else(|| env_var(&["LC_ALL", "LC_MESSAGES", "LANG"], get))
+        .unwrap_or_default();
+    match name.split(['.', '@']).next() {
+        None | Some("") | Some("C") | Some("POSIX") => DEFAULT_LOCALE.to_string(),
+        Some(name) => name.replace('-', "_"),
+    }
+}
+
+/// The encoding of the local terminal, given by the codeset of the locale
+/// of characters in the environment, `ISO-8859-15` for
+/// `fr_FR.ISO-8859-15@euro`. UTF-8 when there is none.
+pub fn local_encoding() -> &'static Encoding {
+    local_encoding_with(|var| env::var(var).ok())
+}
+
+fn local_encoding_with<F: Fn(&str) -> Option<String>>(get: F) -> &'static Encoding {
+    env_var(&["LC_ALL", "LC_CTYPE", "LANG"], get)
+        .as_deref()
+        .and_then(|name| name.split_once('.'))
+        .and_then(|(_, codeset)| codeset.split('@').next())
+        .and_then(|codeset| Encoding::for_label(codeset.as_bytes()))
+        .unwrap_or(UTF_8)
+        .output_encoding()
+}
+
+/// The encoding named `label`.
+pub fn encoding(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| anyhow!("unknown encoding {}", label))
+}
+
+/// Converts the output of a command from the encoding jenkins writes it in
+/// to the local one.
+pub struct Transcoder {
+    to: &'static Encoding,
+    /// Kept apart for stdout and stderr, whose frames may each end in the
+    /// middle of a character
+    stdout: Decoder,
+    stderr: Decoder,
+}
+
+impl Transcoder {
+    /// Returns `None` when there is nothing to convert.
+    pub fn new(from: &'static Encoding, to: &'static Encoding) -> Option<Transcoder> {
+        if from == to {
+            return None;
+        }
+        Some(Transcoder {
+            to,
+            stdout: from.new_decoder_without_bom_handling(),
+            stderr: from.new_decoder_without_bom_handling(),
+        })
+    }
+
+    /// Converts `data` written on the stream `op`. The characters it ends
+    /// the middle of are kept for the next call, unless it is the `last`.
+    pub fn convert(&mut self, op: Code, data: &[u8], last: bool) -> Vec<u8> {
+        let decoder = match op {
+            Code::Stderr => &mut self.stderr,
+            _ => &mut self.stdout,
+        };
+        let mut text = String::with_capacity(
+            decoder
+                .max_utf8_buffer_length(data.len())
+                .unwrap_or(data.len()),
+        );
+        // characters which cannot be decoded are replaced
+        let _ = decoder.decode_to_string(data, &mut text, last);
+        let (bytes, _, _) = self.to.encode(&text);
+        bytes.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{ISO_8859_15, SHIFT_JIS, WINDOWS_1252};
+
+    /// Looks variables up in `vars` instead of the environment.
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |var| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn takes_locale_of_messages() {
+        let cases: &[(&[(&str, &str)], &str)] = &[
+            (&[], "en"),
+            (&[("LANG", "C")], "en"),
+            (&[("LANG", "POSIX")], "en"),
+            (&[("LANG", "C.UTF-8")], "en"),
+            (&[("LANG", "fr_FR.UTF-8@euro")], "fr_FR"),
+            (&[("LANG", "de_DE.ISO-8859-15")], "de_DE"),
+            (&[("LANG", "fr_FR"), ("LC_MESSAGES", "de_DE")], "de_DE"),
+            (&[("LC_MESSAGES", "de_DE"), ("LC_ALL", "ja_JP")], "ja_JP"),
+            // empty variables are not set
+            (&[("LC_ALL", ""), ("LANG", "fr_FR.UTF-8")], "fr_FR"),
+            // not the locale of messages
+            (&[("LC_CTYPE", "fr_FR.UTF-8")], "en"),
+        ];
+        for (env, expected) in cases {
+            assert_eq!(locale_with(None, vars(env)), *expected, "{:?}", env);
+        }
+        let env = [("LC_ALL", "fr_FR.UTF-8")];
+        assert_eq!(locale_with(Some("pt-BR"), vars(&env)), "pt_BR");
+    }
+
+    #[test]
+    fn takes_encoding_of_characters() {
+        let cases: &[(&[(&str, &str)], &'static Encoding)] = &[
+            (&[], UTF_8),
+            (&[("LANG", "C")], UTF_8),
+            (&[("LANG", "fr_FR.UTF-8@euro")], UTF_8),
+            (&[("LANG", "de_DE.ISO-8859-15")], ISO_8859_15),
+            (&[("LANG", "fr_FR.ISO-8859-15@euro")], ISO_8859_15),
+            (&[("LANG", "en_US.ISO-8859-1")], WINDOWS_1252),
+            (
+                &[("LANG", "de_DE.UTF-8"), ("LC_CTYPE", "de_DE.ISO-8859-15")],
+                ISO_8859_15,
+            ),
+            (
+                &[("LC_ALL", ""), ("LANG", "de_DE.ISO-8859-15")],
+                ISO_8859_15,
+            ),
+            // unknown codesets
+            (&[("LANG", "de_DE.NOPE")], UTF_8),
+            (&[("LANG", "de_DE.")], UTF_8),
+        ];
+        for (env, expected) in cases {
+            assert_eq!(local_encoding_with(vars(env)), *expected, "{:?}", env);
+        }
+    }
+
+    #[test]
+    fn keeps_characters_cut_between_frames() {
+        assert!(Transcoder::new(UTF_8, UTF_8).is_none());
+        let mut transcoder = Transcoder::new(SHIFT_JIS, UTF_8).unwrap();
+        // 日本 cut in the middle of both characters, on each stream
+        assert_eq!(transcoder.convert(Code::Stdout, b"\x93", false), b"");
+        assert_eq!(transcoder.convert(Code::Stderr, b"\x96", false), b"");
+        assert_eq!(
+            transcoder.convert(Code::Stdout, b"\xfa!", false),
+            "日!".as_bytes()
+        );
+        assert_eq!(
+            transcoder.convert(Code::Stderr, b"\x7b", false),
+            "本".as_bytes()
+        );
+        // the end of the output
+        assert_eq!(transcoder.convert(Code::Stdout, b"\x93", false), b"");
+        assert_eq!(
+            transcoder.convert(Code::Stdout, b"", true),
+            "\u{fffd}".as_bytes()
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use super::{codec, Code, Connector, Frame};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::io;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+/// What the controller end sends to the client end: a frame, or the kind of
+/// error failing its read.
+type Message = std::result::Result<Frame, io::ErrorKind>;
+
+/// Frames the controller end can write before the client end reads them, as
+/// the buffers of a socket would hold.
+pub const CAPACITY: usize = 64;
+
+/// Connects a client end to a controller end, both in memory.
+pub fn pair() -> (Transport, Controller) {
+    let (to_controller, from_client) = channel();
+    let (to_client, from_controller) = sync_channel(CAPACITY);
+    (
+        Transport {
+            tx: Some(to_controller),
+            rx: from_controller,
+        },
+        Controller {
+            tx: to_client,
+            rx: from_client,
+        },
+    )
+}
+
+/// Spawns a thread playing jenkins for the command sent through the returned
+/// transport: `handler` is called with the command and answers it through
+/// the controller. The connection is cut when `handler` returns early.
+pub fn serve<F>(handler: F) -> Transport
+where
+    F: FnOnce(Command, &mut Controller) -> Result<()> + Send + 'static,
+{
+    let (transport, mut controller) = pair();
+    thread::spawn(move || {
+        if let Ok(command) = controller.read_command() {
+            let _ = handler(command, &mut controller);
+        }
+    });
+    transport
+}
+
+/// A connector serving every command with `handler`, for
+/// `Cli::with_connector`.
+pub fn connector<F>(handler: F) -> Connector
+where
+    F: Fn(Command, &mut Controller) -> Result<()> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    Arc::new(move || {
+        let handler = handler.clone();
+        Ok(Box::new(serve(move |command, controller| {
+            handler(command, controller)
+        })))
+    })
+}
+
+/// The client end of a loopback, to pass to `Cli::send_with_transport`.
+pub struct Transport {
+    /// `None` once the input is closed
+    tx: Option<Sender<Frame>>,
+    rx: Receiver<Message>,
+}
+
+impl super::Transport for Transport {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let tx = self
+            .tx
+            .as_ref()
+            .ok_or_else(|| anyhow!("loopback: input already closed"))?;
+        tx.send(frame.clone())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        match self.rx.recv() {
+            Ok(Ok(frame)) => Ok(frame),
+            Ok(Err(kind)) => Err(io::Error::from(kind).into()),
+            // the controller is gone without exit code, as when the
+            // connection is cut
+            Err(_) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+}
+
+/// A command received by a `Controller`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Command {
+    pub args: Vec<String>,
+    pub locale: Option<String>,
+    pub encoding: Option<String>,
+}
+
+/// The controller end of a loopback, answering as jenkins would. Up to
+/// `CAPACITY` frames are queued, so they can be written before the client
+/// reads them; writing more waits for the client. Dropping it cuts the
+/// connection.
+pub struct Controller {
+    tx: SyncSender<Message>,
+    rx: Receiver<Frame>,
+}
+
+impl Controller {
+    /// Reads the frames sent by the client up to `Start`.
+    pub fn read_command(&mut self) -> Result<Command> {
+        let mut command = Command::default();
+        loop {
+            let frame = self.read_frame()?;
+            match frame.op {
+                Code::Arg => command.args.push(codec::read_string(&frame.data)?),
+                Code::Locale => command.locale = Some(codec::read_string(&frame.data)?),
+                Code::Encoding => command.encoding = Some(codec::read_string(&frame.data)?),
+                Code::Start => return Ok(command),
+                op => return Err(anyhow!("loopback: unexpected {:?} before Start", op)),
+            }
+        }
+    }
+
+    /// Reads the next frame sent by the client.
+    pub fn read_frame(&mut self) -> Result<Frame> {
+        self.rx
+            .recv()
+            .map_err(|_| anyhow!("loopback: input closed by the client"))
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.send(Ok(frame.clone()))
+    }
+
+    pub fn stdout(&mut self, data: &[u8]) -> Result<()> {
+        self.write_frame(&Frame {
+            op: Code::Stdout,
+            data: Bytes::copy_from_slice(data),
+        })
+    }
+
+    pub fn stderr(&mut self, data: &[u8]) -> Result<()> {
+        self.write_frame(&Frame {
+            op: Code::Stderr,
+            data: Bytes::copy_from_slice(data),
+        })
+    }
+
+    /// Ends the command with `code`.
+    pub fn exit(&mut self, code: i32) -> Result<()> {
+        self.write_frame(&Frame {
+            op: Code::Exit,
+            data: Bytes::copy_from_slice(&code.to_be_bytes()),
+        })
+    }
+
+    /// Fails the next read of the client with an error of `kind`, `TimedOut`
+    /// for a server which stopped answering for example.
+    pub fn fail(&mut self, kind: io::ErrorKind) -> Result<()> {
+        self.send(Err(kind))
+    }
+
+    fn send(&mut self, message: Message) -> Result<()> {
+        self.tx
+            .send(message)
+            .map_err(|_| anyhow!("loopback: client gone"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jenkins::{Cli, Server, OUTPUT_FRAMES};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cli() -> Cli {
+        let server: Server = toml::from_str(
+            r#"
+            url = "http://jenkins.invalid"
+            username = "u"
+            password = "p"
+            locale = "fr_FR"
+            encoding = "UTF-8"
+            [retry]
+            delay = 0
+            "#,
+        )
+        .unwrap();
+        Cli::new(server).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Sends `args` through `transport`, returning the output and the exit
+    /// code.
+    fn run(cli: &Cli, args: &[String], transport: Transport) -> Result<(String, i32)> {
+        let mut resp = cli.send_with_transport(transport, args)?;
+        let mut output = String::new();
+        resp.output().read_to_string(&mut output)?;
+        Ok((output, resp.wait_exit_code()?))
+    }
+
+    #[test]
+    fn sends_command_and_reads_output() {
+        let (transport, mut controller) = pair();
+        controller.stdout(b"Authenticated as: u\n").unwrap();
+        controller.exit(0).unwrap();
+        let (output, code) = run(&cli(), &args(&["who-am-i"]), transport).unwrap();
+        assert_eq!(output, "Authenticated as: u\n");
+        assert_eq!(code, 0);
+        assert_eq!(
+            controller.read_command().unwrap(),
+            Command {
+                args: args(&["who-am-i"]),
+                locale: Some("fr_FR".to_string()),
+                encoding: Some("UTF-8".to_string()),
+            }
+        );
+        assert!(controller.read_frame().is_err());
+    }
+
+    #[test]
+    fn forwards_stderr_and_exit_code() {
+        let transport = serve(|command, controller| {
+            controller.stderr(format!("ERROR: No such job {}\n", command.args[1]).as_bytes())?;
+            controller.exit(3)
+        });
+        let (output, code) = run(&cli(), &args(&["build", "app"]), transport).unwrap();
+        assert_eq!(output, "ERROR: No such job app\n");
+        assert_eq!(code, 3);
+    }
+
+    #[test]
+    fn fails_when_connection_is_cut() {
+        let transport = serve(|_, controller| controller.stdout(b"a\nb"));
+        let err = run(&cli(), &args(&["list-jobs"]), transport).unwrap_err();
+        let cause = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(cause.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn fails_on_read_error() {
+        let (transport, mut controller) = pair();
+        controller.fail(io::ErrorKind::TimedOut).unwrap();
+        let err = run(&cli(), &args(&["who-am-i"]), transport).unwrap_err();
+        assert!(crate::jenkins::timeout::is_timeout(&err), "{:#}", err);
+    }
+
+    #[test]
+    fn retries_read_only_command_of_connector() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let count = attempts.clone();
+        let connector = connector(move |_, controller| {
+            if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                return controller.fail(io::ErrorKind::ConnectionReset);
+            }
+            controller.stdout(b"a\nb\n")?;
+            controller.exit(0)
+        });
+        let cli = Cli::with_connector(cli().server().clone(), connector).unwrap();
+        let mut resp = cli.send(&args(&["list-jobs"])).unwrap();
+        let mut output = String::new();
+        resp.output().read_to_string(&mut output).unwrap();
+        assert_eq!(resp.wait_exit_code().unwrap(), 0);
+        assert_eq!(output, "a\nb\n");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn streams_output_before_exit() {
+        let (transport, mut controller) = pair();
+        let mut resp = cli()
+            .send_with_transport(transport, &args(&["console", "app"]))
+            .unwrap();
+        controller.stdout(b"Started\n").unwrap();
+        controller.stderr(b"warning\n").unwrap();
+        let mut chunks = resp.chunks();
+        let f = chunks.next().unwrap();
+        assert_eq!((f.op, &f.data[..]), (Code::Stdout, &b"Started\n"[..]));
+        let f = chunks.next().unwrap();
+        assert_eq!((f.op, &f.data[..]), (Code::Stderr, &b"warning\n"[..]));
+        controller.exit(0).unwrap();
+        assert!(chunks.next().is_none());
+        drop(chunks);
+        assert_eq!(resp.wait_exit_code().unwrap(), 0);
+    }
+
+    #[test]
+    fn holds_back_output_not_read() {
+        let written = Arc::new(AtomicUsize::new(0));
+        let count = written.clone();
+        let transport = serve(move |_, controller| {
+            for _ in 0..1000 {
+                controller.stdout(b"line\n")?;
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+            controller.exit(0)
+        });
+        let resp = cli()
+            .send_with_transport(transport, &args(&["console", "app"]))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        // the output buffered, the frame held by the decoding thread, and
+        // the frames in the loopback
+        assert!(written.load(Ordering::SeqCst) <= OUTPUT_FRAMES + 1 + CAPACITY);
+        let mut lines = 0;
+        let code = resp
+            .for_each_chunk(|f| {
+                lines += f.data.len() / "line\n".len();
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!((code, lines), (0, 1000));
+    }
+
+    #[test]
+    fn discards_output_not_read_at_exit() {
+        let transport = serve(|_, controller| {
+            for _ in 0..1000 {
+                controller.stdout(b"line\n")?;
+            }
+            controller.exit(4)
+        });
+        let resp = cli()
+            .send_with_transport(transport, &args(&["console", "app"]))
+            .unwrap();
+        assert_eq!(resp.wait_exit_code().unwrap(), 4);
+    }
+
+    #[test]
+    fn aborts_command_when_response_is_dropped() {
+        let (done, ended) = channel();
+        let transport = serve(move |_, controller| {
+            // until the client is gone
+            while controller.stdout(b"line\n").is_ok() {}
+            let _ = done.send(());
+            Ok(())
+        });
+        let resp = cli()
+            .send_with_transport(transport, &args(&["console", "app"]))
+            .unwrap();
+        drop(resp);
+        ended
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+    }
+}
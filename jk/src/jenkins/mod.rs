@@ -1,25 +1,42 @@
-use anyhow::{anyhow, Result};
-use pipe::{pipe, PipeReader};
+use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, Bytes};
+use reqwest::header::{HeaderName, HeaderValue};
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::convert::TryInto;
 use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{mpsc, Arc, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-mod codec;
+pub mod codec;
+pub mod daemon;
 mod http;
+pub mod interrupt;
+pub mod locale;
+pub mod loopback;
+pub mod replay;
+pub mod retry;
+pub mod socks;
+mod ssh;
+pub mod timeout;
+pub mod trace;
+mod tunnel;
 mod websocket;
 
 use codec::Encoder;
+use interrupt::Stop;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Frame {
-    op: Code,
-    data: Vec<u8>,
+    pub op: Code,
+    pub data: Bytes,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Code {
     Arg = 0,
     Locale = 1,
@@ -51,17 +68,191 @@ impl TryFrom<u8> for Code {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl std::str::FromStr for Code {
+    type Err = anyhow::Error;
+
+    /// Parses the name of a code, as printed by `Debug`.
+    fn from_str(s: &str) -> Result<Self> {
+        (0..=8)
+            .map(|i| Code::try_from(i).unwrap())
+            .find(|code| format!("{:?}", code) == s)
+            .ok_or_else(|| anyhow!("Code: unexpected name {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Server {
     pub url: String,
     pub username: String,
+    /// API token of the user, not needed over SSH
+    #[serde(default)]
     pub password: String,
+    /// URL of an HTTP proxy, or of a SOCKS5 one as `socks5://` to resolve
+    /// hosts locally or `socks5h://` to leave it to the proxy
     pub proxy: Option<String>,
+    /// Command whose stdin and stdout are used as the connection to jenkins,
+    /// like the `ProxyCommand` of ssh: `ssh bastion nc %h %p`
+    pub proxy_command: Option<String>,
+    /// Private key file to authenticate with over SSH, instead of the keys
+    /// of ssh-agent
+    pub identity: Option<String>,
+    /// Locale of the messages of jenkins, `fr_FR` for example, taken from
+    /// the environment by default
+    pub locale: Option<String>,
+    /// Encoding jenkins writes the output of commands in, that of the
+    /// terminal by default
+    pub encoding: Option<String>,
+    /// Addresses to connect to instead of resolving hosts, as entries
+    /// `HOST:PORT:ADDRESS` like with `curl --resolve`
+    #[serde(default)]
+    pub resolve: Vec<String>,
+    /// Sent on every request, to get through a gateway for example
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub retry: retry::Retry,
+    #[serde(default)]
+    pub timeout: timeout::Timeout,
+}
+
+impl Server {
+    /// The address `resolve` gives for `host` and `port`, if any.
+    pub fn resolved(&self, host: &str, port: u16) -> Result<Option<IpAddr>> {
+        resolved(&self.resolve, host, port)
+    }
+}
+
+/// The address the entries `HOST:PORT:ADDRESS` of `resolve` give for `host`
+/// and `port`, if any.
+pub fn resolved(resolve: &[String], host: &str, port: u16) -> Result<Option<IpAddr>> {
+    for entry in resolve {
+        let mut parts = entry.splitn(3, ':');
+        let (name, entry_port, addr) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(port), Some(addr)) => (name, port, addr),
+            _ => return Err(anyhow!("resolve: {} is not HOST:PORT:ADDRESS", entry)),
+        };
+        let entry_port: u16 = entry_port
+            .parse()
+            .map_err(|_| anyhow!("resolve: invalid port in {}", entry))?;
+        let addr: IpAddr = addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| anyhow!("resolve: invalid address in {}", entry))?;
+        if name.eq_ignore_ascii_case(host) && entry_port == port {
+            return Ok(Some(addr));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks the names and values of `headers`, so that none adds others to
+/// the requests written as text.
+fn check_headers(headers: &HashMap<String, String>) -> Result<()> {
+    for (name, value) in headers {
+        HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid header name {}", name))?;
+        HeaderValue::from_str(value)
+            .with_context(|| format!("invalid value of header {}", name))?;
+    }
+    Ok(())
 }
 
 #[derive(Clone)]
 pub struct Cli {
     cfg: Server,
+    locale: String,
+    encoding: &'static encoding_rs::Encoding,
+    /// When the commands sent must be over, the total timeout being that of
+    /// the whole invocation of jk
+    deadline: Option<Instant>,
+    /// HTTP client shared by the clones of this `Cli`
+    http: Arc<OnceLock<reqwest::blocking::Client>>,
+    /// Sessions left to replay, for `replay:` servers
+    replay: Arc<OnceLock<replay::Recording>>,
+    /// Opens the transports instead of connecting to `cfg.url`
+    connector: Option<Connector>,
+}
+
+/// Opens a transport for each command of a `Cli`, in place of the
+/// connection to its server.
+pub type Connector = Arc<dyn Fn() -> Result<Box<dyn Transport + Send>> + Send + Sync>;
+
+/// A connection to jenkins, direct or through a SOCKS proxy, or through
+/// `proxy_command`.
+#[derive(Debug)]
+enum Connection {
+    Tcp(TcpStream),
+    Tunnel(tunnel::Tunnel),
+}
+
+/// A connection to jenkins, over TLS for `https` and `wss` urls.
+type Stream = tungstenite::stream::Stream<Connection, native_tls::TlsStream<Connection>>;
+
+impl Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            Connection::Tunnel(tunnel) => tunnel.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(tcp) => tcp.set_write_timeout(timeout),
+            Connection::Tunnel(tunnel) => tunnel.set_write_timeout(timeout),
+        }
+    }
+
+    /// Sends small writes at once, over TCP.
+    fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(tcp) => tcp.set_nodelay(nodelay),
+            Connection::Tunnel(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(tcp) => tcp.read(buf),
+            Connection::Tunnel(tunnel) => tunnel.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(tcp) => tcp.write(buf),
+            Connection::Tunnel(tunnel) => tunnel.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(tcp) => tcp.flush(),
+            Connection::Tunnel(tunnel) => tunnel.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Connection::Tcp(tcp) => tcp.as_raw_fd(),
+            Connection::Tunnel(tunnel) => tunnel.as_raw_fd(),
+        }
+    }
+}
+
+/// The connection under `stream`.
+fn connection(stream: &Stream) -> &Connection {
+    match stream {
+        Stream::Plain(conn) => conn,
+        Stream::Tls(tls) => tls.get_ref(),
+    }
 }
 
 pub trait Transport {
@@ -70,17 +261,59 @@ pub trait Transport {
     fn close_input(&mut self) -> Result<()>;
 }
 
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        (**self).write_frame(frame)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        (**self).read_frame()
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        (**self).close_input()
+    }
+}
+
+/// Frames of output buffered between the thread decoding it and the reader
+/// of a `Response`. Once they are not read, the thread stops reading from
+/// jenkins, whose output is then held back by the socket.
+pub const OUTPUT_FRAMES: usize = 16;
+
+/// A command being run. Its output is streamed as it arrives, with at most
+/// `OUTPUT_FRAMES` frames buffered; dropping the response before the end of
+/// the output aborts the command.
 pub struct Response {
-    output: PipeReader,
+    output: Output,
     decode_thread: thread::JoinHandle<Result<i32>>,
 }
 
 impl Response {
+    /// The output of the command, stdout and stderr mixed as received.
     pub fn output(&mut self) -> &mut impl Read {
         &mut self.output
     }
 
-    pub fn wait_exit_code(self) -> Result<i32> {
+    /// The `Stdout` and `Stderr` frames of the output, as they arrive. The
+    /// iteration ends with the output, the exit code is then given by
+    /// `wait_exit_code`.
+    pub fn chunks(&mut self) -> impl Iterator<Item = Frame> + '_ {
+        &mut self.output
+    }
+
+    /// Passes the frames of the output to `f` as they arrive, then returns
+    /// the exit code.
+    pub fn for_each_chunk<F: FnMut(Frame) -> Result<()>>(mut self, mut f: F) -> Result<i32> {
+        for chunk in self.chunks() {
+            f(chunk)?;
+        }
+        self.wait_exit_code()
+    }
+
+    /// Waits for the end of the command. The output not read yet is
+    /// discarded.
+    pub fn wait_exit_code(mut self) -> Result<i32> {
+        self.chunks().for_each(drop);
         match self.decode_thread.join() {
             Ok(code) => Ok(code?),
             Err(err) => Err(anyhow!("error in decoding thread: {:?}", err)),
@@ -88,60 +321,343 @@ impl Response {
     }
 }
 
+/// The reading end of the output of a `Response`.
+struct Output {
+    frames: mpsc::Receiver<Frame>,
+    /// What is left of the frame being read
+    pending: Option<Frame>,
+}
+
+impl Iterator for Output {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        match self.pending.take() {
+            Some(f) if !f.data.is_empty() => Some(f),
+            _ => self.frames.recv().ok(),
+        }
+    }
+}
+
+impl Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut f = match self.next() {
+            Some(f) => f,
+            None => return Ok(0),
+        };
+        let n = buf.len().min(f.data.len());
+        buf[..n].copy_from_slice(&f.data.split_to(n));
+        self.pending = Some(f);
+        Ok(n)
+    }
+}
+
 impl Cli {
     pub fn new(cfg: Server) -> Result<Cli> {
-        Ok(Cli { cfg })
+        // whatever the transport
+        if cfg.proxy.is_some() && cfg.proxy_command.is_some() {
+            return Err(anyhow!("proxy and proxy_command cannot be both set"));
+        }
+        check_headers(&cfg.headers)?;
+        let encoding = match &cfg.encoding {
+            Some(label) => locale::encoding(label)?,
+            None => locale::local_encoding(),
+        };
+        Ok(Cli {
+            locale: locale::locale(cfg.locale.as_deref()),
+            encoding,
+            deadline: cfg.timeout.deadline(),
+            cfg,
+            http: Arc::new(OnceLock::new()),
+            replay: Arc::new(OnceLock::new()),
+            connector: None,
+        })
+    }
+
+    /// A `Cli` sending its commands through the transports opened by
+    /// `connector`, without daemon.
+    pub fn with_connector(cfg: Server, connector: Connector) -> Result<Cli> {
+        Ok(Cli {
+            connector: Some(connector),
+            ..Cli::new(cfg)?
+        })
+    }
+
+    /// This `Cli` trying its commands once, for those which can do without
+    /// them.
+    pub fn without_retries(&self) -> Cli {
+        let mut cli = self.clone();
+        cli.cfg.retry.attempts = 1;
+        cli
     }
 
+    /// This `Cli` with the total timeout counted from now, for a new command
+    /// of the shell for example.
+    pub fn restarted(&self) -> Cli {
+        Cli {
+            deadline: self.cfg.timeout.deadline(),
+            ..self.clone()
+        }
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.cfg
+    }
+
+    /// The locale commands are run with, `en_US` style.
+    pub fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    /// Opens a connection to `host`, through `proxy_command` or the SOCKS
+    /// proxy if any, within `timeout`: to the first address of `host`
+    /// accepting it otherwise.
+    fn connect_host(&self, host: &str, port: u16, timeout: Option<Duration>) -> Result<Connection> {
+        if let Some(command) = &self.cfg.proxy_command {
+            return Ok(Connection::Tunnel(tunnel::Tunnel::open(
+                command, host, port,
+            )?));
+        }
+        if let Some(proxy) = socks::proxy(&self.cfg)? {
+            let tcp = socks::connect(&proxy, host, port, &self.cfg, timeout)?;
+            return Ok(Connection::Tcp(tcp));
+        }
+        let addrs: Vec<SocketAddr> = match self.cfg.resolved(host, port)? {
+            Some(addr) => vec![SocketAddr::new(addr, port)],
+            None => (host, port).to_socket_addrs()?.collect(),
+        };
+        let mut last_err = None;
+        for addr in addrs {
+            let tcp = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match tcp {
+                Ok(tcp) => return Ok(Connection::Tcp(tcp)),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        match last_err {
+            Some(err) => Err(err.into()),
+            None => Err(anyhow!("no address found for {}", host)),
+        }
+    }
+
+    /// Opens the connection to the host of `url`, and the TLS session over
+    /// it with `tls` for `https` and `wss` urls, within `timeout`.
+    fn open(
+        &self,
+        url: &Url,
+        tls: &native_tls::TlsConnector,
+        timeout: Option<Duration>,
+    ) -> Result<Stream> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("no host in {}", url))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow!("no port in {}", url))?;
+        let conn = self.connect_host(host, port, timeout)?;
+        conn.set_nodelay(true)?;
+        // the handshakes are bounded too
+        conn.set_read_timeout(timeout)?;
+        conn.set_write_timeout(timeout)?;
+        match url.scheme() {
+            "https" | "wss" => Ok(Stream::Tls(tls.connect(host, conn)?)),
+            _ => Ok(Stream::Plain(conn)),
+        }
+    }
+
+    /// Sends a command, through the daemon when one is running.
     pub fn send(&self, args: &[String]) -> Result<Response> {
-        let url = Url::parse(&self.cfg.url)?;
-        let scheme = url.scheme();
-        if scheme.starts_with("ws") {
-            self.send_with_transport(websocket::Transport::new(self)?, args)
+        let stop = Stop::new(self.deadline);
+        // the daemon runs commands with the locale and encoding of the client
+        let server = Server {
+            locale: Some(self.locale.clone()),
+            encoding: Some(self.encoding.name().to_string()),
+            ..self.cfg.clone()
+        };
+        let daemon = if replay::is_replay(&self.cfg) || self.connector.is_some() {
+            None
         } else {
-            self.send_with_transport(http::Transport::new(self)?, args)
-        }
+            daemon::Transport::connect(&server, &stop)?
+        };
+        let transport: Box<dyn Transport + Send> = match daemon {
+            Some(transport) => {
+                let mut transport = trace::wrap(Box::new(transport), &self.cfg);
+                self.write_command(&mut transport, args)?;
+                transport
+            }
+            None => self.start(args, &stop)?,
+        };
+        Ok(self.respond(args, stop, transport, self.cfg.retry.clone()))
     }
 
-    fn send_with_transport<T: 'static + Transport + Send>(
+    /// Sends a command through `transport`. As it cannot be opened again,
+    /// the command is not retried when its output is cut.
+    pub fn send_with_transport<T: Transport + Send + 'static>(
         &self,
-        mut transport: T,
+        transport: T,
         args: &[String],
     ) -> Result<Response> {
-        let mut encoder = Encoder::new(&mut transport);
-        for arg in args {
-            encoder.string(Code::Arg, arg)?;
-        }
-        encoder.string(Code::Encoding, "utf-8")?;
-        encoder.string(Code::Locale, "en")?;
-        encoder.op(Code::Start)?;
-        transport.close_input()?;
+        let stop = Stop::new(self.deadline);
+        let mut transport = trace::wrap(Box::new(transport), &self.cfg);
+        self.write_command(&mut transport, args)?;
+        let retry = retry::Retry {
+            attempts: 1,
+            ..self.cfg.retry.clone()
+        };
+        Ok(self.respond(args, stop, transport, retry))
+    }
 
-        let (output, mut input) = pipe();
+    /// Decodes the output of the command `args` from `transport` in a
+    /// thread, transcoding it to the local encoding.
+    fn respond(
+        &self,
+        args: &[String],
+        stop: Stop,
+        transport: Box<dyn Transport + Send>,
+        retry: retry::Retry,
+    ) -> Response {
+        let cli = self.clone();
+        let args = args.to_vec();
+        let mut transcoder = locale::Transcoder::new(self.encoding, locale::local_encoding());
+        let (input, frames) = mpsc::sync_channel(OUTPUT_FRAMES);
+        // sending fails once the response is dropped, which ends the thread
+        let send = move |f: Frame| {
+            input
+                .send(f)
+                .map_err(|_| anyhow!("output of the command closed"))
+        };
         let decode_thread = thread::spawn(move || -> Result<i32> {
-            loop {
-                let f = transport.read_frame()?;
-                match &f.op {
-                    Code::Stderr => {
-                        input.write_all(&f.data)?;
-                        input.flush()?;
+            let code = cli.receive(&args, &stop, transport, &retry, |f| match &mut transcoder {
+                Some(transcoder) => send(Frame {
+                    op: f.op,
+                    data: transcoder.convert(f.op, &f.data, false).into(),
+                }),
+                None => send(f),
+            })?;
+            if let Some(transcoder) = &mut transcoder {
+                for op in [Code::Stdout, Code::Stderr] {
+                    let rest = transcoder.convert(op, &[], true);
+                    if !rest.is_empty() {
+                        send(Frame {
+                            op,
+                            data: rest.into(),
+                        })?;
                     }
-                    Code::Stdout => {
-                        input.write_all(&f.data)?;
-                        input.flush()?;
+                }
+            }
+            Ok(code)
+        });
+        Response {
+            output: Output {
+                frames,
+                pending: None,
+            },
+            decode_thread,
+        }
+    }
+
+    /// Opens a transport straight to the server and sends it the command
+    /// `args`, trying again when the session cannot be established.
+    fn start(&self, args: &[String], stop: &Stop) -> Result<Box<dyn Transport + Send>> {
+        self.cfg.retry.run(|| {
+            stop.remaining()?;
+            let mut transport = self.connect(stop)?;
+            self.write_command(&mut transport, args)?;
+            Ok(transport)
+        })
+    }
+
+    fn connect(&self, stop: &Stop) -> Result<Box<dyn Transport + Send>> {
+        if let Some(connector) = &self.connector {
+            return Ok(trace::wrap(connector()?, &self.cfg));
+        }
+        if replay::is_replay(&self.cfg) {
+            return Ok(trace::wrap(
+                Box::new(replay::Transport::new(self)),
+                &self.cfg,
+            ));
+        }
+        let url = Url::parse(&self.cfg.url)?;
+        let scheme = url.scheme();
+        let transport: Box<dyn Transport + Send> = if scheme.starts_with("ws") {
+            Box::new(websocket::Transport::new(self, stop)?)
+        } else if scheme == "ssh" {
+            Box::new(ssh::Transport::new(self, stop)?)
+        } else {
+            Box::new(http::Transport::new(self, stop)?)
+        };
+        Ok(trace::wrap(transport, &self.cfg))
+    }
+
+    /// Reads the output of the command `args` from `transport`, passing its
+    /// `Stdout` and `Stderr` frames to `emit`, and returns its exit code.
+    ///
+    /// When the output of a read-only command is cut, the command is sent
+    /// again and the part of its output already emitted is skipped. Once the
+    /// command must `stop`, the transport closes the session so that jenkins
+    /// aborts it.
+    fn receive<F: FnMut(Frame) -> Result<()>>(
+        &self,
+        args: &[String],
+        stop: &Stop,
+        mut transport: Box<dyn Transport + Send>,
+        retry: &retry::Retry,
+        mut emit: F,
+    ) -> Result<i32> {
+        let read_only = retry::is_read_only(args);
+        let mut emitted: usize = 0;
+        let mut attempt = 1;
+        loop {
+            let mut received = 0;
+            let err = loop {
+                let mut f = match transport.read_frame() {
+                    Ok(f) => f,
+                    Err(err) => break err,
+                };
+                match &f.op {
+                    Code::Stdout | Code::Stderr => {
+                        let skip = emitted.saturating_sub(received).min(f.data.len());
+                        received += f.data.len();
+                        if skip < f.data.len() {
+                            f.data.advance(skip);
+                            emit(f)?;
+                            emitted = received;
+                        }
                     }
                     Code::Exit => {
-                        drop(input);
-                        let exit_code = i32::from_be_bytes(f.data[0..4].try_into()?);
+                        let exit_code = codec::read_exit_code(&f.data)?;
                         return Ok(exit_code);
                     }
                     _ => println!("unexpected {:?}", f),
                 }
+            };
+            stop.remaining()?;
+            let err = match self.cfg.timeout.idle {
+                Some(idle) if timeout::is_timeout(&err) => {
+                    err.context(format!("no output from jenkins for {}s", idle))
+                }
+                _ => err,
+            };
+            if !read_only || attempt >= retry.attempts || !retry.is_transient(&err) {
+                return Err(err);
             }
-        });
-        Ok(Response {
-            output,
-            decode_thread,
-        })
+            retry.wait(attempt, &err);
+            attempt += 1;
+            transport = self.start(args, stop)?;
+        }
+    }
+
+    fn write_command<T: Transport>(&self, transport: &mut T, args: &[String]) -> Result<()> {
+        let mut encoder = Encoder::new(transport);
+        for arg in args {
+            encoder.string(Code::Arg, arg)?;
+        }
+        encoder.string(Code::Encoding, self.encoding.name())?;
+        encoder.string(Code::Locale, &self.locale)?;
+        encoder.op(Code::Start)?;
+        transport.close_input()
     }
 }
@@ -0,0 +1,166 @@
+use super::trace::{self, Direction, Record, Redactor};
+use super::{Cli, Code, Frame, Server};
+use crate::jenkins;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Write};
+use std::sync::Mutex;
+
+/// Scheme of the URL of servers replaying a trace recorded by `--trace`,
+/// as in `replay:path/to/trace.jsonl`.
+pub const SCHEME: &str = "replay:";
+
+/// The sessions of a trace not replayed yet.
+pub struct Recording {
+    sessions: Mutex<Vec<VecDeque<Record>>>,
+}
+
+impl Recording {
+    pub fn load(path: &str) -> Result<Recording> {
+        let file = File::open(path).map_err(|err| anyhow!("replay {}: {}", path, err))?;
+        let mut sessions: Vec<(u64, VecDeque<Record>)> = Vec::new();
+        for record in trace::read(BufReader::new(file)) {
+            let record = record?;
+            match sessions.iter_mut().find(|(id, _)| *id == record.session) {
+                Some((_, session)) => session.push_back(record),
+                None => sessions.push((record.session, VecDeque::from(vec![record]))),
+            }
+        }
+        Ok(Recording {
+            sessions: Mutex::new(sessions.into_iter().map(|(_, s)| s).collect()),
+        })
+    }
+
+    /// Takes the first session which starts by sending `sent`.
+    fn take(&self, sent: &[(String, String)]) -> Option<VecDeque<Record>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let index = sessions.iter().position(|session| {
+            session.len() >= sent.len()
+                && session.iter().zip(sent).all(|(record, (op, data))| {
+                    record.dir == Direction::Send && &record.op == op && &record.data == data
+                })
+        })?;
+        let mut session = sessions.remove(index);
+        session.drain(..sent.len());
+        Some(session)
+    }
+}
+
+/// A transport answering the command sent from a recorded session, instead
+/// of jenkins, once the frames of the command match those of the session.
+///
+/// The frames are compared as recorded, secrets being redacted with the
+/// password of the server replaying the session.
+pub struct Transport {
+    cli: Cli,
+    redactor: Redactor,
+    /// Frames sent before the session is chosen, as recorded
+    sent: Vec<(String, String)>,
+    session: Option<VecDeque<Record>>,
+}
+
+impl Transport {
+    pub fn new(cli: &Cli) -> Transport {
+        Transport {
+            cli: cli.clone(),
+            redactor: Redactor::new(&cli.cfg),
+            sent: Vec::new(),
+            session: None,
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut VecDeque<Record>> {
+        if self.session.is_none() {
+            let session = recording(&self.cli)?.take(&self.sent).ok_or_else(|| {
+                let args: Vec<&str> = self
+                    .sent
+                    .iter()
+                    .filter(|(op, _)| op == "Arg")
+                    .map(|(_, data)| data.as_str())
+                    .collect();
+                anyhow!("replay: no recorded session for {:?}", args.join(" "))
+            })?;
+            self.session = Some(session);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+}
+
+impl jenkins::Transport for Transport {
+    fn write_frame(&mut self, f: &Frame) -> Result<()> {
+        let sent = (format!("{:?}", f.op), self.redactor.text(f));
+        if self.session.is_none() {
+            self.sent.push(sent);
+            return Ok(());
+        }
+        match self.session()?.pop_front() {
+            Some(record)
+                if record.dir == Direction::Send
+                    && record.op == sent.0
+                    && record.data == sent.1 =>
+            {
+                Ok(())
+            }
+            record => Err(anyhow!(
+                "replay: sent {} {:?}, recorded {:?}",
+                sent.0,
+                sent.1,
+                record
+            )),
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        match self.session()?.pop_front() {
+            Some(record) if record.dir == Direction::Recv => frame(&record),
+            Some(record) => Err(anyhow!(
+                "replay: recorded {} {:?} not sent",
+                record.op,
+                record.data
+            )),
+            // as when jenkins closes the session
+            None => Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The frame `record` was recorded from.
+fn frame(record: &Record) -> Result<Frame> {
+    let op: Code = record.op.parse()?;
+    let data = match op {
+        Code::Arg | Code::Locale | Code::Encoding => {
+            let mut data = Vec::with_capacity(2 + record.data.len());
+            let len: u16 = record.data.len().try_into()?;
+            data.write_all(&len.to_be_bytes())?;
+            data.write_all(record.data.as_bytes())?;
+            data
+        }
+        Code::Exit => record.data.parse::<i32>()?.to_be_bytes().to_vec(),
+        _ => record.data.as_bytes().to_vec(),
+    };
+    Ok(Frame {
+        op,
+        data: data.into(),
+    })
+}
+
+/// The recording replayed by `cli`, loaded on first use and then shared by
+/// its clones.
+fn recording(cli: &Cli) -> Result<&Recording> {
+    if cli.replay.get().is_none() {
+        let path = &cli.cfg.url[SCHEME.len()..];
+        let _ = cli.replay.set(Recording::load(path)?);
+    }
+    Ok(cli.replay.get().unwrap())
+}
+
+/// Whether `server` replays a trace rather than connecting to jenkins.
+pub fn is_replay(server: &Server) -> bool {
+    server.url.starts_with(SCHEME)
+}
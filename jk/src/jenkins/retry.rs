@@ -0,0 +1,247 @@
+use super::websocket;
+use anyhow::Result;
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::Duration;
+
+/// Commands which change nothing on the server, and can be run again when
+/// their output is cut in the middle.
+const READ_ONLY: &[&str] = &[
+    "console",
+    "get-job",
+    "get-node",
+    "get-view",
+    "help",
+    "list-changes",
+    "list-jobs",
+    "list-plugins",
+    "version",
+    "who-am-i",
+];
+
+/// How to retry commands failing on transient errors, configured per server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    /// How many times a command is tried, 1 to disable retries
+    pub attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled at each retry
+    pub delay: u64,
+    /// HTTP statuses worth retrying
+    pub statuses: Vec<u16>,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 3,
+            delay: 500,
+            statuses: vec![502, 503, 504],
+        }
+    }
+}
+
+impl Retry {
+    /// Runs `f` until it succeeds, fails with an error not worth retrying,
+    /// or has been tried `attempts` times.
+    pub fn run<T, F: FnMut() -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(err) if attempt < self.attempts && self.is_transient(&err) => {
+                    self.wait(attempt, &err);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether `err` may go away when trying again.
+    pub fn is_transient(&self, err: &anyhow::Error) -> bool {
+        err.chain().any(|cause| {
+            if let Some(status) = cause.downcast_ref::<HttpStatus>() {
+                self.statuses.contains(&status.status)
+            } else if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                match err.status() {
+                    Some(status) => self.statuses.contains(&status.as_u16()),
+                    None => {
+                        err.is_connect() || err.is_timeout() || err.is_request() || err.is_body()
+                    }
+                }
+            } else if let Some(err) = cause.downcast_ref::<tungstenite::Error>() {
+                use tungstenite::error::ProtocolError;
+                match err {
+                    tungstenite::Error::Http(resp) => {
+                        self.statuses.contains(&resp.status().as_u16())
+                    }
+                    tungstenite::Error::ConnectionClosed
+                    | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+                    | tungstenite::Error::Protocol(ProtocolError::HandshakeIncomplete) => true,
+                    _ => false,
+                }
+            } else if let Some(closed) = cause.downcast_ref::<websocket::Closed>() {
+                closed.is_transient()
+            } else if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                matches!(
+                    err.kind(),
+                    ErrorKind::ConnectionRefused
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::UnexpectedEof
+                        | ErrorKind::TimedOut
+                )
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Sleeps before trying for the `attempt + 1`th time, after `err`.
+    pub fn wait(&self, attempt: u32, err: &anyhow::Error) {
+        let delay = self.backoff(attempt);
+        warn!(
+            "attempt {}/{} failed, retrying in {:?}: {}",
+            attempt, self.attempts, delay, err
+        );
+        thread::sleep(delay);
+    }
+
+    /// The delay before trying for the `attempt + 1`th time.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = self.delay.saturating_mul(1 << (attempt - 1).min(16));
+        // half of the delay is random, so that clients failing together
+        // do not retry together
+        Duration::from_millis(max / 2 + rand::thread_rng().gen_range(0..=max / 2))
+    }
+}
+
+/// Whether the command `args` can be run again without side effect.
+pub fn is_read_only(args: &[String]) -> bool {
+    args.first()
+        .is_some_and(|command| READ_ONLY.contains(&command.as_str()))
+}
+
+/// A request answered with an unexpected HTTP status.
+#[derive(Debug)]
+pub struct HttpStatus {
+    /// The side of the session, `RECV` or `SEND`
+    pub side: &'static str,
+    pub url: String,
+    pub status: u16,
+}
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.side, self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::io::Error;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn retries_only_read_only_commands() {
+        assert!(is_read_only(&args(&["list-jobs"])));
+        assert!(is_read_only(&args(&["get-job", "app"])));
+        for command in [
+            &["build", "app"][..],
+            &["delete-job", "app"],
+            &["groovy", "="],
+            &[],
+        ] {
+            assert!(!is_read_only(&args(command)), "{:?}", command);
+        }
+    }
+
+    fn status(status: u16) -> anyhow::Error {
+        HttpStatus {
+            side: "RECV",
+            url: "http://jenkins/cli".to_string(),
+            status,
+        }
+        .into()
+    }
+
+    #[test]
+    fn retries_configured_statuses() {
+        let retry = Retry::default();
+        assert!(retry.is_transient(&status(503)));
+        assert!(retry.is_transient(&status(502).context("opening session")));
+        assert!(!retry.is_transient(&status(500)));
+        assert!(!retry.is_transient(&status(403)));
+        let retry = Retry {
+            statuses: vec![500],
+            ..Retry::default()
+        };
+        assert!(retry.is_transient(&status(500)));
+        assert!(!retry.is_transient(&status(503)));
+    }
+
+    #[test]
+    fn retries_lost_connections() {
+        let retry = Retry::default();
+        for kind in [
+            ErrorKind::ConnectionRefused,
+            ErrorKind::ConnectionReset,
+            ErrorKind::UnexpectedEof,
+            ErrorKind::TimedOut,
+        ] {
+            assert!(retry.is_transient(&Error::from(kind).into()), "{:?}", kind);
+        }
+        assert!(!retry.is_transient(&Error::from(ErrorKind::PermissionDenied).into()));
+        assert!(!retry.is_transient(&anyhow!("unknown command")));
+    }
+
+    #[test]
+    fn stops_on_errors_not_transient() {
+        let retry = Retry {
+            delay: 1,
+            ..Retry::default()
+        };
+        let mut tries = 0;
+        let res: Result<()> = retry.run(|| {
+            tries += 1;
+            Err(anyhow!("unknown command"))
+        });
+        assert!(res.is_err());
+        assert_eq!(tries, 1);
+        let mut tries = 0;
+        let res: Result<()> = retry.run(|| {
+            tries += 1;
+            Err(status(503))
+        });
+        assert!(res.is_err());
+        assert_eq!(tries, retry.attempts);
+    }
+
+    #[test]
+    fn doubles_delay_with_jitter() {
+        let retry = Retry::default();
+        for attempt in 1..=4 {
+            let max = Duration::from_millis(retry.delay << (attempt - 1));
+            for _ in 0..100 {
+                let delay = retry.backoff(attempt);
+                assert!(delay >= max / 2 && delay <= max, "{}: {:?}", attempt, delay);
+            }
+        }
+        // the delay stops growing
+        let max = Duration::from_millis(retry.delay << 16);
+        assert!(retry.backoff(40) <= max);
+        assert!(retry.backoff(40) >= max / 2);
+    }
+}
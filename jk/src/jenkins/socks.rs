@@ -0,0 +1,176 @@
+use super::Server;
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Port of SOCKS proxies when their url has none.
+const DEFAULT_PORT: u16 = 1080;
+
+/// The proxy of `server` when it is a SOCKS5 one: `socks5://` to resolve
+/// hosts locally, `socks5h://` to leave it to the proxy.
+pub fn proxy(server: &Server) -> Result<Option<Url>> {
+    match &server.proxy {
+        Some(proxy) => {
+            let url = Url::parse(proxy)?;
+            Ok(match url.scheme() {
+                "socks5" | "socks5h" => Some(url),
+                _ => None,
+            })
+        }
+        None => Ok(None),
+    }
+}
+
+/// Opens a connection to `host` and `port` through the SOCKS5 `proxy`,
+/// authenticating with the username and password of its url if any, all
+/// within `timeout`.
+pub fn connect(
+    proxy: &Url,
+    host: &str,
+    port: u16,
+    server: &Server,
+    timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let proxy_host = proxy
+        .host_str()
+        .ok_or_else(|| anyhow!("no host in proxy {}", proxy))?;
+    let proxy_addr = (proxy_host, proxy.port().unwrap_or(DEFAULT_PORT))
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("no address found for proxy {}", proxy_host))?;
+    let mut tcp = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&proxy_addr, timeout)?,
+        None => TcpStream::connect(proxy_addr)?,
+    };
+    tcp.set_read_timeout(timeout)?;
+    tcp.set_write_timeout(timeout)?;
+
+    let username = decode(proxy.username())?;
+    let password = decode(proxy.password().unwrap_or_default())?;
+    let method = if username.is_empty() { 0 } else { 2 };
+    tcp.write_all(&[5, 1, method])?;
+    let mut answer = [0; 2];
+    tcp.read_exact(&mut answer)?;
+    if answer[0] != 5 {
+        return Err(anyhow!("{} is not a SOCKS5 proxy", proxy_addr));
+    }
+    if answer[1] != method {
+        return Err(anyhow!(
+            "SOCKS5 proxy {} refused authentication",
+            proxy_addr
+        ));
+    }
+    if method == 2 {
+        let mut auth = vec![1];
+        push_string(&mut auth, &username)?;
+        push_string(&mut auth, &password)?;
+        tcp.write_all(&auth)?;
+        tcp.read_exact(&mut answer)?;
+        if answer[1] != 0 {
+            return Err(anyhow!(
+                "SOCKS5 proxy {} refused credentials of {}",
+                proxy_addr,
+                username
+            ));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    if proxy.scheme() == "socks5h" {
+        request.push(3);
+        push_string(&mut request, host)?;
+    } else {
+        let addr = match server.resolved(host, port)? {
+            Some(addr) => addr,
+            None => (host, port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow!("no address found for {}", host))?
+                .ip(),
+        };
+        match addr {
+            IpAddr::V4(ip) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    tcp.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    tcp.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(anyhow!(
+            "SOCKS5 proxy {} cannot connect to {}:{}: {}",
+            proxy_addr,
+            host,
+            port,
+            reason(reply[1])
+        ));
+    }
+    // the address the proxy connected from
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            tcp.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(anyhow!("SOCKS5 proxy {}: invalid reply", proxy_addr)),
+    };
+    let mut bound = vec![0; len + 2];
+    tcp.read_exact(&mut bound)?;
+    Ok(tcp)
+}
+
+/// A username or password of a proxy url, which are percent-encoded.
+pub fn decode(component: &str) -> Result<String> {
+    Ok(percent_encoding::percent_decode_str(component)
+        .decode_utf8()
+        .with_context(|| format!("SOCKS5: invalid credentials {}", component))?
+        .into_owned())
+}
+
+/// Appends `s` preceded by its length on a byte.
+fn push_string(buf: &mut Vec<u8>, s: &str) -> Result<()> {
+    let len = u8::try_from(s.len()).map_err(|_| anyhow!("SOCKS5: {} too long", s))?;
+    buf.push(len);
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+/// The meaning of a reply code of RFC 1928.
+fn reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_credentials() {
+        assert_eq!(decode("dev").unwrap(), "dev");
+        assert_eq!(decode("s3cr%40t%3A").unwrap(), "s3cr@t:");
+        assert!(decode("%ff").is_err());
+    }
+}
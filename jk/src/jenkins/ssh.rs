@@ -0,0 +1,270 @@
+use super::codec;
+use super::interrupt::{self, Stop};
+use super::timeout;
+use super::Cli;
+use crate::jenkins;
+use crate::jenkins::{Code, Frame, Server};
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use reqwest::Url;
+use ssh2::{Channel, CheckResult, KnownHostFileKind, Session, EXTENDED_DATA_STDERR};
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Port of the SSH server of jenkins when the url has none. Jenkins picks a
+/// random one by default, given by the `X-SSH-Endpoint` header.
+const DEFAULT_PORT: u16 = 22;
+
+/// Size of the reads of the output.
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Id of the stdout of the command among the streams of its channel.
+const STDOUT: i32 = 0;
+
+/// The CLI over the SSH server of jenkins, for `ssh://HOST:PORT` urls. The
+/// command is run as `ssh -p PORT USER@HOST ARGS...`, so the locale and
+/// encoding are those of jenkins.
+pub struct Transport {
+    session: Session,
+    /// Arguments of the command, run once `Start` is written
+    args: Vec<String>,
+    channel: Option<Channel>,
+    input_closed: bool,
+    /// Whether stdout is over, stderr may still be pending
+    stdout_done: bool,
+    /// Bound of the steps of the session other than reading the output
+    timeout: Option<Duration>,
+    idle: Option<Duration>,
+    stop: Stop,
+    /// When the channel was closed for the command to stop
+    closed: Option<Instant>,
+    /// Where the output is read
+    buf: Vec<u8>,
+}
+
+impl Transport {
+    pub fn new(cli: &Cli, stop: &Stop) -> Result<Transport> {
+        let url = Url::parse(&cli.cfg.url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("no host in {}", url))?;
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let timeout = timeout::shortest(cli.cfg.timeout.connect(), stop.remaining()?);
+        let conn = cli.connect_host(host, port, timeout)?;
+        conn.set_nodelay(true)?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(conn);
+        session.set_timeout(millis(timeout));
+        session.handshake()?;
+        check_host_key(&session, host, port)?;
+        authenticate(&session, &cli.cfg)?;
+        if let Some(keepalive) = cli.cfg.timeout.keepalive() {
+            session.set_keepalive(true, keepalive.as_secs().max(1) as u32);
+        }
+        Ok(Transport {
+            session,
+            args: Vec::new(),
+            channel: None,
+            input_closed: false,
+            stdout_done: false,
+            timeout,
+            idle: cli.cfg.timeout.idle(),
+            stop: stop.clone(),
+            closed: None,
+            buf: vec![0; BUF_SIZE],
+        })
+    }
+
+    fn channel(&mut self) -> Result<&mut Channel> {
+        self.channel
+            .as_mut()
+            .ok_or_else(|| anyhow!("SSH: the command is not started"))
+    }
+
+    /// Reads the stream `id` of the channel, stdout or stderr, into `buf`.
+    fn read(&mut self, id: i32) -> Result<std::io::Result<usize>> {
+        let channel = self
+            .channel
+            .as_mut()
+            .ok_or_else(|| anyhow!("SSH: the command is not started"))?;
+        Ok(channel.stream(id).read(&mut self.buf))
+    }
+
+    /// Checks, while nothing is read, whether the command must stop, closing
+    /// the channel for jenkins to abort it, or whether it is idle.
+    fn check(&mut self, last_read: Instant) -> Result<()> {
+        let (session, channel) = (&self.session, &mut self.channel);
+        let stopping = self.stop.check(&mut self.closed, || {
+            session.set_blocking(false);
+            if let Some(channel) = channel {
+                let _ = channel.close();
+            }
+            session.set_blocking(true);
+            Ok(())
+        })?;
+        if stopping {
+            // waiting for the exit code
+        } else if self.idle.is_some_and(|idle| last_read.elapsed() >= idle) {
+            return Err(std::io::Error::from(ErrorKind::TimedOut).into());
+        } else {
+            // does nothing unless keepalives are due
+            self.session.keepalive_send()?;
+        }
+        Ok(())
+    }
+
+    /// The exit code, once the output is over.
+    fn exit(&mut self) -> Result<Frame> {
+        self.session.set_timeout(millis(self.timeout));
+        let channel = self.channel()?;
+        channel.wait_close()?;
+        let code = channel.exit_status()?;
+        Ok(Frame {
+            op: Code::Exit,
+            data: Bytes::copy_from_slice(&code.to_be_bytes()),
+        })
+    }
+}
+
+impl jenkins::Transport for Transport {
+    fn write_frame(&mut self, f: &Frame) -> Result<()> {
+        match f.op {
+            Code::Arg => self.args.push(codec::read_string(&f.data)?),
+            Code::Start => {
+                self.session.set_timeout(millis(self.timeout));
+                let mut channel = self.session.channel_session()?;
+                channel.exec(&shell_words::join(&self.args))?;
+                self.channel = Some(channel);
+            }
+            Code::Stdin => {
+                // jenkins may take its time to read the input
+                self.session.set_timeout(0);
+                self.channel()?.write_all(&f.data)?;
+            }
+            Code::EndStdin => self.close_input()?,
+            // the locale and encoding over SSH are those of jenkins
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        let last_read = Instant::now();
+        loop {
+            if !self.stdout_done {
+                // stderr is read between the waits for stdout, which wake up
+                // regularly to check whether the command must stop
+                self.session.set_blocking(false);
+                let stderr = self.read(EXTENDED_DATA_STDERR)?;
+                self.session.set_blocking(true);
+                match stderr {
+                    Ok(n) if n > 0 => return Ok(frame(Code::Stderr, &self.buf[..n])),
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            // once stdout is over, stderr is read up to the end of the
+            // channel, before the exit code
+            let (id, code) = if self.stdout_done {
+                (EXTENDED_DATA_STDERR, Code::Stderr)
+            } else {
+                (STDOUT, Code::Stdout)
+            };
+            self.session.set_timeout(interrupt::POLL.as_millis() as u32);
+            match self.read(id)? {
+                Ok(0) if self.stdout_done => return self.exit(),
+                Ok(0) => self.stdout_done = true,
+                Ok(n) => return Ok(frame(code, &self.buf[..n])),
+                Err(err) if err.kind() == ErrorKind::TimedOut => self.check(last_read)?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        if !self.input_closed {
+            self.session.set_timeout(millis(self.timeout));
+            self.channel()?.send_eof()?;
+            self.input_closed = true;
+        }
+        Ok(())
+    }
+}
+
+fn frame(op: Code, data: &[u8]) -> Frame {
+    Frame {
+        op,
+        data: Bytes::copy_from_slice(data),
+    }
+}
+
+/// `timeout` as set on a session, where 0 waits forever.
+fn millis(timeout: Option<Duration>) -> u32 {
+    timeout.map_or(0, |timeout| timeout.as_millis().max(1) as u32)
+}
+
+/// Checks the key of jenkins against `~/.ssh/known_hosts`, as ssh does
+/// with `StrictHostKeyChecking`.
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("SSH: no host key from {}", host))?;
+    let path = known_hosts()?;
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("SSH: cannot read {}", path.display()))?;
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "SSH: the host key of {}:{} does not match the one in {}",
+            host,
+            port,
+            path.display()
+        )),
+        CheckResult::NotFound | CheckResult::Failure => Err(anyhow!(
+            "SSH: the host key of {}:{} is unknown, add it to {} with ssh-keyscan -p {} {}",
+            host,
+            port,
+            path.display(),
+            port,
+            host
+        )),
+    }
+}
+
+fn known_hosts() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("no home directory"))?;
+    Ok(home.join(".ssh").join("known_hosts"))
+}
+
+/// Authenticates with the key file `identity` of `server`, unencrypted, or
+/// else with the keys of ssh-agent.
+fn authenticate(session: &Session, server: &Server) -> Result<()> {
+    let user = &server.username;
+    match &server.identity {
+        Some(identity) => {
+            let path = match identity.strip_prefix("~/") {
+                Some(rest) => dirs::home_dir()
+                    .ok_or_else(|| anyhow!("no home directory"))?
+                    .join(rest),
+                None => PathBuf::from(identity),
+            };
+            session
+                .userauth_pubkey_file(user, None, &path, None)
+                .with_context(|| format!("SSH: cannot authenticate {} with {}", user, identity))?
+        }
+        None => session
+            .userauth_agent(user)
+            .with_context(|| format!("SSH: cannot authenticate {} with ssh-agent", user))?,
+    }
+    if session.authenticated() {
+        Ok(())
+    } else {
+        Err(anyhow!("SSH: {} not authenticated", user))
+    }
+}
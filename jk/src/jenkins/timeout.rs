@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+/// Exit code of a command which timed out, as with `timeout(1)`.
+pub const EXIT_CODE: i32 = 124;
+
+/// Limits on the time taken by commands, in seconds, configured per server.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timeout {
+    /// To connect to the server
+    pub connect: Option<u64>,
+    /// Waiting for output from the server
+    pub idle: Option<u64>,
+    /// For the whole command, after which the session is closed so that
+    /// jenkins aborts the command
+    pub total: Option<u64>,
+    /// Between the pings sent over websockets while waiting for output, so
+    /// that proxies keep the connection, 30 by default and 0 to disable. A
+    /// connection silent for as long after a ping is considered lost.
+    pub keepalive: Option<u64>,
+}
+
+/// Default of `Timeout::keepalive`.
+const KEEPALIVE: u64 = 30;
+
+impl Timeout {
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect.map(Duration::from_secs)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle.map(Duration::from_secs)
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        match self.keepalive.unwrap_or(KEEPALIVE) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// When a command started now must be over.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.total.map(|t| Instant::now() + Duration::from_secs(t))
+    }
+
+    /// Overrides the limits given in `other`.
+    pub fn merge(&mut self, other: &Timeout) {
+        self.connect = other.connect.or(self.connect);
+        self.idle = other.idle.or(self.idle);
+        self.total = other.total.or(self.total);
+        self.keepalive = other.keepalive.or(self.keepalive);
+    }
+}
+
+/// The shorter of two optional timeouts.
+pub fn shortest(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A command running past its total timeout.
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Whether `err` comes from a timeout, whichever it is.
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if cause.is::<TimedOut>() {
+            true
+        } else if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            err.is_timeout()
+        } else if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+        } else {
+            false
+        }
+    })
+}
@@ -0,0 +1,249 @@
+use super::{codec, Code, Frame, Server};
+use crate::jenkins;
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// How many characters of the data of a frame are printed when decoding.
+pub const PREVIEW: usize = 80;
+
+const REDACTED: &str = "***";
+
+/// Names of arguments whose values are redacted, `KEY=value` being
+/// redacted when `KEY` contains one of them.
+const SENSITIVE: &[&str] = &["PASSWORD", "PASSWD", "SECRET", "TOKEN"];
+
+static TRACE: OnceLock<Mutex<File>> = OnceLock::new();
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// A frame recorded by `--trace`.
+///
+/// A trace is a JSON lines file holding one record per frame, such as:
+///
+/// ```text
+/// {"time":1700000000.123,"session":1,"dir":"send","op":"Arg","len":11,"data":"list-jobs"}
+/// ```
+///
+/// The password of the server, and the values of arguments such as
+/// `API_TOKEN=value`, are replaced by `***`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// When the frame was sent or received, in seconds since the epoch
+    pub time: f64,
+    /// The transport the frame went through, numbered from 1 in the order
+    /// they were opened. A command retried uses a new one
+    pub session: u64,
+    pub dir: Direction,
+    /// The name of the `Code` of the frame
+    pub op: String,
+    /// The length of the data of the frame, in bytes
+    pub len: usize,
+    /// The string of `Arg`, `Locale` and `Encoding` frames, the code of
+    /// `Exit` frames, the data of the others as text
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Sent to jenkins
+    Send,
+    /// Received from jenkins
+    Recv,
+}
+
+/// Records the frames of the commands sent from now on to `path`.
+pub fn start(path: &Path) -> Result<()> {
+    let file = File::create(path)?;
+    let _ = TRACE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Wraps `transport` for its frames to be recorded, when tracing.
+pub fn wrap(
+    transport: Box<dyn jenkins::Transport + Send>,
+    server: &Server,
+) -> Box<dyn jenkins::Transport + Send> {
+    if TRACE.get().is_none() {
+        return transport;
+    }
+    Box::new(Transport {
+        inner: transport,
+        session: SESSIONS.fetch_add(1, Ordering::SeqCst) + 1,
+        redactor: Redactor::new(server),
+    })
+}
+
+/// Reads the records of a trace.
+pub fn read<R: BufRead>(r: R) -> impl Iterator<Item = Result<Record>> {
+    r.lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+}
+
+/// The start of `text`, as printed when decoding.
+pub fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// Gives the data of the frames of a session as recorded.
+pub struct Redactor {
+    /// The password of the server, and the values of the sensitive
+    /// arguments sent so far, which jenkins may echo
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new(server: &Server) -> Redactor {
+        Redactor {
+            secrets: vec![server.password.clone()],
+        }
+    }
+
+    /// The data of `f` as text, without secrets.
+    pub fn text(&mut self, f: &Frame) -> String {
+        let mut text = match f.op {
+            Code::Arg | Code::Locale | Code::Encoding => codec::read_string(&f.data)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&f.data).into_owned()),
+            Code::Exit => match f.data[..].try_into() {
+                Ok(code) => i32::from_be_bytes(code).to_string(),
+                Err(_) => String::from_utf8_lossy(&f.data).into_owned(),
+            },
+            _ => String::from_utf8_lossy(&f.data).into_owned(),
+        };
+        if let (Code::Arg, Some((key, value))) = (f.op, text.split_once('=')) {
+            if !value.is_empty() && SENSITIVE.iter().any(|s| key.to_uppercase().contains(s)) {
+                self.secrets.push(value.to_string());
+            }
+        }
+        for secret in self.secrets.iter().filter(|s| !s.is_empty()) {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        text
+    }
+}
+
+/// A transport recording the frames going through it.
+struct Transport<T> {
+    inner: T,
+    session: u64,
+    redactor: Redactor,
+}
+
+impl<T> Transport<T> {
+    fn record(&mut self, dir: Direction, f: &Frame) {
+        let trace = match TRACE.get() {
+            Some(trace) => trace,
+            None => return,
+        };
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let record = Record {
+            time,
+            session: self.session,
+            dir,
+            op: format!("{:?}", f.op),
+            len: f.data.len(),
+            data: self.redactor.text(f),
+        };
+        let result = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(trace.lock().unwrap(), "{}", line)?));
+        if let Err(err) = result {
+            warn!("cannot record frame: {:#}", err);
+        }
+    }
+}
+
+impl<T: jenkins::Transport> jenkins::Transport for Transport<T> {
+    fn write_frame(&mut self, f: &Frame) -> Result<()> {
+        self.record(Direction::Send, f);
+        self.inner.write_frame(f)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        let f = self.inner.read_frame()?;
+        self.record(Direction::Recv, &f);
+        Ok(f)
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        self.inner.close_input()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn redactor() -> Redactor {
+        let server: Server = toml::from_str(
+            r#"
+            url = "http://jenkins.invalid"
+            username = "u"
+            password = "hunter2"
+            "#,
+        )
+        .unwrap();
+        Redactor::new(&server)
+    }
+
+    fn arg(text: &str) -> Frame {
+        let mut data = (text.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        Frame {
+            op: Code::Arg,
+            data: Bytes::from(data),
+        }
+    }
+
+    fn stdout(text: &str) -> Frame {
+        Frame {
+            op: Code::Stdout,
+            data: Bytes::copy_from_slice(text.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn redacts_password() {
+        let mut redactor = redactor();
+        assert_eq!(redactor.text(&arg("hunter2")), "***");
+        assert_eq!(redactor.text(&stdout("token hunter2\n")), "token ***\n");
+    }
+
+    #[test]
+    fn redacts_sensitive_arguments() {
+        let mut redactor = redactor();
+        let cases = [
+            ("-p", "-p"),
+            ("DB_PASSWORD=s3cr3t", "DB_PASSWORD=***"),
+            ("api_secret=0xdead", "api_secret=***"),
+            ("GITHUB_TOKEN=ghp_1", "GITHUB_TOKEN=***"),
+            ("passwd=pw", "passwd=***"),
+            // not sensitive, or without a value
+            ("BRANCH=main", "BRANCH=main"),
+            ("TOKEN=", "TOKEN="),
+        ];
+        for (sent, recorded) in cases.iter() {
+            assert_eq!(redactor.text(&arg(sent)), *recorded, "{}", sent);
+        }
+        // values are redacted once sent, wherever jenkins echoes them
+        assert_eq!(
+            redactor.text(&stdout("s3cr3t 0xdead ghp_1 pw main\n")),
+            "*** *** *** *** main\n"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the command has to exit once the connection is closed, before
+/// it is killed.
+const EXIT_GRACE: Duration = Duration::from_secs(1);
+
+/// A connection through a process of `proxy_command`, run like the
+/// `ProxyCommand` of ssh: its stdin and stdout are one end of a socket
+/// pair, the tunnel is the other.
+#[derive(Debug)]
+pub struct Tunnel {
+    stream: UnixStream,
+    child: Child,
+}
+
+impl Tunnel {
+    /// Runs `command` with `%h` and `%p` replaced by `host` and `port`.
+    pub fn open(command: &str, host: &str, port: u16) -> Result<Tunnel> {
+        let command = expand(command, host, port)?;
+        let (stream, end) = UnixStream::pair()?;
+        let child = shell(&command)
+            .stdin(Stdio::from(OwnedFd::from(end.try_clone()?)))
+            .stdout(Stdio::from(OwnedFd::from(end)))
+            .spawn()
+            .with_context(|| format!("proxy_command: cannot run {}", command))?;
+        Ok(Tunnel { stream, child })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+}
+
+impl Read for Tunnel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Tunnel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsRawFd for Tunnel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        // the command reads the end of its input
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Err(err) = reap(&mut self.child) {
+            warn!("proxy_command: {:#}", err);
+        }
+    }
+}
+
+/// `command` with `%h` and `%p` replaced by `host` and `port`, and `%%` by
+/// `%`.
+fn expand(command: &str, host: &str, port: u16) -> Result<String> {
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(host),
+            Some('p') => expanded.push_str(&port.to_string()),
+            Some('%') => expanded.push('%'),
+            _ => return Err(anyhow!("proxy_command: unknown escape in {}", command)),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Waits for `child` to exit, killing it after `EXIT_GRACE`.
+fn reap(child: &mut Child) -> Result<()> {
+    let deadline = Instant::now() + EXIT_GRACE;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_host_and_port() {
+        assert_eq!(
+            expand("ssh bastion nc %h %p # 100%%", "jenkins", 443).unwrap(),
+            "ssh bastion nc jenkins 443 # 100%"
+        );
+        assert!(expand("nc %x", "jenkins", 443).is_err());
+    }
+
+    #[test]
+    fn forwards_through_command() {
+        let mut tunnel = Tunnel::open("cat", "jenkins", 80).unwrap();
+        tunnel.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut echo = [0; 18];
+        tunnel.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn kills_command_left_running() {
+        let tunnel = Tunnel::open("sleep 30", "jenkins", 80).unwrap();
+        let start = Instant::now();
+        drop(tunnel);
+        assert!(start.elapsed() < EXIT_GRACE * 3);
+    }
+}
@@ -1,41 +1,175 @@
-use super::Cli;
+use super::codec;
+use super::interrupt::{self, Stop};
+use super::timeout;
+use super::{Cli, Stream};
 use crate::jenkins;
 use crate::jenkins::Frame;
 use anyhow::{anyhow, Result};
-use std::convert::TryInto;
-use std::io::Write;
-use tungstenite::client::AutoStream;
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+use tungstenite::handshake::HandshakeError;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
 use tungstenite::{client, handshake};
 use tungstenite::{Message, WebSocket};
 
 pub struct Transport {
-    socket: WebSocket<AutoStream>,
+    socket: WebSocket<Stream>,
+    idle: Option<Duration>,
+    keepalive: Option<Duration>,
+    stop: Stop,
+    /// When the session was closed for the command to stop
+    closed: Option<Instant>,
+    last_ping: Instant,
+    /// When a ping was sent without anything received since
+    pinged: Option<Instant>,
 }
 
+/// The websocket closed by jenkins before the end of the command.
+#[derive(Debug)]
+pub struct Closed {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Closed {
+    fn new(frame: Option<CloseFrame>) -> Closed {
+        match frame {
+            Some(frame) => Closed {
+                code: frame.code.into(),
+                reason: frame.reason.into_owned(),
+            },
+            None => Closed {
+                code: CloseCode::Status.into(),
+                reason: String::new(),
+            },
+        }
+    }
+
+    /// Whether the command may succeed when sent again: the connection was
+    /// closed normally or by a server going away, failing or restarting.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            CloseCode::from(self.code),
+            CloseCode::Normal
+                | CloseCode::Away
+                | CloseCode::Status
+                | CloseCode::Abnormal
+                | CloseCode::Error
+                | CloseCode::Restart
+                | CloseCode::Again
+        )
+    }
+}
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "websocket closed by jenkins with code {}", self.code)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Closed {}
+
 impl Transport {
-    pub fn new(cli: &Cli) -> Result<Transport> {
-        let socket = websocket(cli)?;
-        Ok(Transport { socket })
+    pub fn new(cli: &Cli, stop: &Stop) -> Result<Transport> {
+        let socket = websocket(cli, stop)?;
+        let conn = super::connection(socket.get_ref());
+        // reads wake up regularly to check whether the command must stop
+        conn.set_read_timeout(Some(interrupt::POLL))?;
+        // the connect timeout bounds the handshake only: jenkins may take its
+        // time to read the input
+        conn.set_write_timeout(None)?;
+        Ok(Transport {
+            socket,
+            idle: cli.cfg.timeout.idle(),
+            keepalive: cli.cfg.timeout.keepalive(),
+            stop: stop.clone(),
+            closed: None,
+            last_ping: Instant::now(),
+            pinged: None,
+        })
+    }
+
+    /// Checks, while nothing is read, whether the command must stop, closing
+    /// the session for jenkins to abort it, or whether it is idle.
+    fn check(&mut self, last_read: Instant) -> Result<()> {
+        let socket = &mut self.socket;
+        let stopping = self.stop.check(&mut self.closed, || {
+            let _ = socket.close(None);
+            let _ = socket.write_pending();
+            Ok(())
+        })?;
+        if stopping {
+            // waiting for the exit code
+        } else if self.idle.is_some_and(|idle| last_read.elapsed() >= idle) {
+            return Err(std::io::Error::from(ErrorKind::TimedOut).into());
+        } else if let Some(keepalive) = self.keepalive {
+            self.keep_alive(keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Pings jenkins every `keepalive` while waiting, so that proxies do not
+    /// close the connection, which is lost once a ping is not answered
+    /// within `keepalive`.
+    fn keep_alive(&mut self, keepalive: Duration) -> Result<()> {
+        if self
+            .pinged
+            .is_some_and(|pinged| pinged.elapsed() >= keepalive)
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!(
+                    "no answer from jenkins to ping for {}s",
+                    keepalive.as_secs()
+                ),
+            )
+            .into());
+        }
+        if self.last_ping.elapsed() >= keepalive {
+            self.socket.write_message(Message::Ping(Vec::new()))?;
+            self.last_ping = Instant::now();
+            self.pinged.get_or_insert(self.last_ping);
+        }
+        Ok(())
     }
 }
 
 impl jenkins::Transport for Transport {
     fn write_frame(&mut self, f: &Frame) -> Result<()> {
-        let mut buf = Vec::with_capacity(f.data.len() + 1);
-        buf.write_all(&(f.op as u8).to_be_bytes())?;
-        buf.write_all(&f.data)?;
-        self.socket.write_message(Message::Binary(buf))?;
+        self.socket
+            .write_message(Message::Binary(codec::encode_message(f)))?;
         Ok(())
     }
 
     fn read_frame(&mut self) -> Result<Frame> {
+        let last_read = Instant::now();
         loop {
-            let m = self.socket.read_message()?;
-            if let Message::Binary(buf) = m {
-                let op = buf[0].try_into()?;
-
-                let data = buf[1..].to_vec();
-                return Ok(Frame { op, data });
+            let message = self.socket.read_message();
+            if message.is_ok() {
+                self.pinged = None;
+            }
+            match message {
+                Ok(Message::Binary(buf)) => return codec::decode_message(buf.into()),
+                Ok(Message::Close(frame)) => {
+                    // sends back the close frame queued by tungstenite
+                    let _ = self.socket.write_pending();
+                    return Err(Closed::new(frame).into());
+                }
+                // pings are answered by tungstenite on the next read, and
+                // only output counts against the idle timeout
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    self.check(last_read)?
+                }
+                Err(err) => return Err(err.into()),
             }
         }
     }
@@ -46,9 +180,9 @@ impl jenkins::Transport for Transport {
     }
 }
 
-fn websocket(clt: &Cli) -> Result<WebSocket<AutoStream>> {
+fn websocket(clt: &Cli, stop: &Stop) -> Result<WebSocket<Stream>> {
     let url = reqwest::Url::parse(&format!("{}/{}", clt.cfg.url, "cli/ws"))?;
-    let req = handshake::client::Request::builder()
+    let mut req = handshake::client::Request::builder()
         .uri(url.to_string())
         .header(
             "Authorization",
@@ -56,9 +190,17 @@ fn websocket(clt: &Cli) -> Result<WebSocket<AutoStream>> {
                 "Basic {}",
                 base64::encode(format!("{}:{}", clt.cfg.username, clt.cfg.password))
             ),
-        )
-        .body(())?;
-    let (ws, resp) = client::connect(req)?;
+        );
+    for (name, value) in &clt.cfg.headers {
+        req = req.header(name, value);
+    }
+    let req = req.body(())?;
+    let timeout = timeout::shortest(clt.cfg.timeout.connect(), stop.remaining()?);
+    let stream = clt.open(&url, &native_tls::TlsConnector::new()?, timeout)?;
+    let (ws, resp) = client::client(req, stream).map_err(|err| match err {
+        HandshakeError::Failure(err) => anyhow!(err),
+        HandshakeError::Interrupted(_) => anyhow!("websocket handshake interrupted"),
+    })?;
 
     if resp.status().is_client_error() || resp.status().is_server_error() {
         Err(anyhow!("error while establishing ws: {}", resp.status()))
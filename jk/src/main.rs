@@ -1,155 +1,217 @@
 use anyhow::{anyhow, Result};
-use bytes::{Buf, BufMut, BytesMut};
-use clap::Parser;
-use pretty_env_logger;
+use clap::{AppSettings, Parser};
+use jk::jenkins;
+use output::{Format, Output};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
-use std::io::BufRead;
-use std::path::Path;
-
-mod jenkins;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+mod alias;
+mod cache;
+mod catalog;
+mod complete;
+mod daemon;
+mod debug;
+mod each;
+mod envelope;
+mod output;
+mod plugin;
+mod servers;
+mod shell;
+mod tree;
 
 #[derive(Parser)]
 #[clap(version = "1.0", author = "Guillaume Leroi")]
+#[clap(setting = AppSettings::TrailingVarArg)]
 struct Opts {
-    /// Select the jenkins instance to run against
+    /// Select the jenkins instances to run against: a comma separated list of
+    /// servers or groups, or "all"
     #[clap(short, long)]
     jenkins: Option<String>,
     /// path to config file, default to "~/.config/jk/jenkins.toml"
     #[clap(short, long)]
     config: Option<String>,
+    /// Seconds allowed to connect to jenkins
+    #[clap(long)]
+    connect_timeout: Option<u64>,
+    /// Seconds allowed without output from jenkins
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+    /// Seconds allowed for the whole command, after which it is aborted
+    #[clap(long)]
+    timeout: Option<u64>,
+    /// Locale of the messages of jenkins, "fr_FR" for example, default to
+    /// that of the environment
+    #[clap(long)]
+    locale: Option<String>,
+    /// Encoding jenkins writes output in, default to that of the terminal
+    #[clap(long)]
+    encoding: Option<String>,
+    /// Record the frames exchanged with jenkins to this file, to be read
+    /// with "debug decode"
+    #[clap(long)]
+    trace: Option<String>,
+    /// "json" to write a JSON document with the output, exit code and
+    /// duration of the command, a line per server when there are several;
+    /// "jsonl" to write a JSON line per chunk of output as it arrives, then
+    /// the document without the output
+    #[clap(long, default_value = "text", possible_values = &["text", "json", "jsonl"])]
+    output: Format,
     args: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
     default: String,
+    /// Named lists of servers, usable with `--jenkins`
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    /// User defined commands
+    #[serde(default)]
+    alias: HashMap<String, alias::Alias>,
     #[serde(flatten)]
     servers: HashMap<String, jenkins::Server>,
 }
 
-fn main() -> Result<()> {
-    pretty_env_logger::init();
-
-    let opts = Opts::parse();
-    let config = if let Some(ref path) = opts.config {
-        Path::new(path).to_path_buf()
-    } else {
-        let mut home = dirs::home_dir().ok_or_else(|| anyhow!("no HOME dir found"))?;
-        home.push(".config/jk/jenkins.toml");
-        home
-    };
-    let config = read_file(config)?;
-
-    let server = opts.jenkins.unwrap_or(config.default);
-    let cfg = config
-        .servers
-        .get(&server)
-        .ok_or_else(|| anyhow!("no server {} found", server))?;
-
-    let code = run_jenkins(cfg, &opts.args)?;
-    std::process::exit(code);
+/// What commands run against: a server of the configuration.
+#[derive(Clone)]
+struct Context {
+    name: String,
+    cli: jenkins::Cli,
+    config: Arc<Config>,
 }
 
-fn run_jenkins(cfg: &jenkins::Server, args: &[String]) -> Result<i32> {
-    let cli = jenkins::Cli::new(cfg.clone())?;
-    if args[0] == "tree" {
-        run_tree_cmd(cli, &args[1..])
-    } else {
-        let mut resp = cli.send(args)?;
-        std::io::copy(resp.output(), &mut std::io::stdout())?;
-        resp.wait_exit_code()
+impl Context {
+    fn new(config: &Arc<Config>, name: &str) -> Result<Context> {
+        let cfg = config
+            .servers
+            .get(name)
+            .ok_or_else(|| anyhow!("no server {} found", name))?;
+        Ok(Context {
+            name: name.to_string(),
+            cli: jenkins::Cli::new(cfg.clone())?,
+            config: config.clone(),
+        })
     }
 }
 
-fn run_tree_cmd(cli: jenkins::Cli, args: &[String]) -> Result<i32> {
-    let mut folder = None;
-    if !args.is_empty() {
-        folder = Some(args[0].as_str())
-    }
-    let mut lines = list_jobs(&cli, folder)?;
-    lines.reverse();
-    let mut stack = Vec::with_capacity(lines.len());
-    stack.append(&mut lines);
-
-    while !stack.is_empty() {
-        let folder = stack.pop().unwrap();
-        let content = list_jobs(&cli, Some(folder.as_str()));
+fn main() {
+    pretty_env_logger::init();
 
-        match content {
-            Ok(mut subitems) => {
-                subitems.reverse();
-                stack.append(&mut subitems)
-            }
-            Err(err) => match err {
-                ListJobError::NotFolder { path, code: _ } => println!("{}", path),
-                ListJobError::Other(inner_err) => return Err(inner_err),
-            },
+    let code = run(Opts::parse()).unwrap_or_else(|err| {
+        eprintln!("Error: {:?}", err);
+        if jenkins::timeout::is_timeout(&err) {
+            jenkins::timeout::EXIT_CODE
+        } else {
+            1
         }
+    });
+    if jenkins::interrupt::is_interrupted() {
+        std::process::exit(jenkins::interrupt::EXIT_CODE);
     }
-    Ok(0)
-}
-
-#[derive(Debug)]
-enum ListJobError {
-    NotFolder { path: String, code: i32 },
-    Other(anyhow::Error),
+    std::process::exit(code);
 }
 
-impl std::fmt::Display for ListJobError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ListJobError::NotFolder { path, code } => {
-                write!(f, "cannot list job on {} (code {})", path, code)
-            }
-            ListJobError::Other(err) => write!(f, "{}", err),
+fn run(opts: Opts) -> Result<i32> {
+    jenkins::interrupt::install_handler()?;
+    if let Some(path) = &opts.trace {
+        jenkins::trace::start(Path::new(path))?;
+    }
+    match opts.args.first().map(String::as_str) {
+        Some("completions") => return complete::run_completions_cmd(&opts.args[1..]),
+        Some("__complete") => return complete::run_complete_cmd(&opts.args[1..]),
+        Some("daemon") => return daemon::run_daemon_cmd(&opts.args[1..]),
+        Some("debug") => return debug::run_debug_cmd(&opts.args[1..]),
+        _ => {}
+    }
+    let mut config = read_file(config_path(opts.config.as_deref())?)?;
+    let timeout = jenkins::timeout::Timeout {
+        connect: opts.connect_timeout,
+        idle: opts.idle_timeout,
+        total: opts.timeout,
+        keepalive: None,
+    };
+    for server in config.servers.values_mut() {
+        server.timeout.merge(&timeout);
+        if opts.locale.is_some() {
+            server.locale = opts.locale.clone();
+        }
+        if opts.encoding.is_some() {
+            server.encoding = opts.encoding.clone();
         }
     }
+    let config = Arc::new(config);
+
+    let selection = opts.jenkins.as_ref().unwrap_or(&config.default);
+    let names = servers::resolve(&config, selection)?;
+    let code = if opts.args.first().map(String::as_str) == Some("shell") {
+        if opts.output != Format::Text {
+            return Err(anyhow!("shell only writes text output"));
+        }
+        match &names[..] {
+            [server] => shell::run_shell(&config, server)?,
+            _ => return Err(anyhow!("shell runs against a single server")),
+        }
+    } else if opts.output != Format::Text {
+        let stream = opts.output == Format::JsonLines;
+        servers::run_json(&config, &names, &opts.args, stream, &Output::stdio())?
+    } else if let [server] = &names[..] {
+        run_command(
+            &Context::new(&config, server)?,
+            &opts.args,
+            &Output::stdio(),
+        )?
+    } else {
+        servers::run_on_servers(&config, &names, &opts.args, &Output::stdio())?
+    };
+    Ok(code)
 }
-impl std::error::Error for ListJobError {}
 
-fn list_jobs(
-    cli: &jenkins::Cli,
-    folder: Option<&str>,
-) -> std::result::Result<Vec<String>, ListJobError> {
-    let mut list_args = Vec::with_capacity(2);
-    list_args.push("list-jobs".to_string());
-    if let Some(str) = folder {
-        list_args.push(str.to_string());
-    }
-    let mut resp = cli.send(&list_args[..]).map_err(ListJobError::Other)?;
-    let mut output = BytesMut::new().writer();
-    std::io::copy(resp.output(), &mut output).map_err(|err| ListJobError::Other(err.into()))?;
+/// Runs an alias, a built-in command, or sends `args` to the jenkins CLI.
+fn run_command(ctx: &Context, args: &[String], output: &Output) -> Result<i32> {
+    alias::run_command(ctx, args, &[], output)
+}
 
-    let code = resp.wait_exit_code().map_err(ListJobError::Other)?;
-    let folder_base = folder.map_or("", |l| l);
-    if code != 0 {
-        return Err(ListJobError::NotFolder {
-            path: folder_base.to_string(),
-            code,
-        });
+/// Runs a built-in command, a plugin, or sends `args` to the jenkins CLI.
+fn dispatch(ctx: &Context, args: &[String], output: &Output) -> Result<i32> {
+    let cli = &ctx.cli;
+    if args[0] == "tree" {
+        tree::run_tree_cmd(cli, &args[1..], output)
+    } else if args[0] == "each" {
+        each::run_each_cmd(ctx, &args[1..], output)
+    } else if args[0] == "help" {
+        catalog::run_help_cmd(ctx, &args[1..], output)
+    } else if let Some(path) = plugin::find(&args[0]) {
+        plugin::run_plugin(ctx, &path, &args[1..], output)
+    } else {
+        catalog::check(cli, args)?;
+        let (mut out, mut err) = (output.out(), output.jenkins_err());
+        cli.send(args)?.for_each_chunk(|chunk| {
+            match chunk.op {
+                jenkins::Code::Stderr => err.write_all(&chunk.data)?,
+                _ => out.write_all(&chunk.data)?,
+            }
+            Ok(())
+        })
     }
-
-    let input = output.get_mut().reader();
-    Ok(input
-        .lines()
-        .filter(|l| l.is_ok())
-        .map(|l| l.unwrap())
-        .map(|l| append_path(folder_base, &l))
-        .collect())
 }
 
-fn append_path(root: &str, end: &str) -> String {
-    if root.ends_with('/') {
-        format!("{}{}", root, end)
+fn config_path(path: Option<&str>) -> Result<PathBuf> {
+    if let Some(path) = path {
+        Ok(Path::new(path).to_path_buf())
     } else {
-        format!("{}/{}", root, end)
+        let mut home = dirs::home_dir().ok_or_else(|| anyhow!("no HOME dir found"))?;
+        home.push(".config/jk/jenkins.toml");
+        Ok(home)
     }
 }
 
 fn read_file<P: AsRef<Path>>(filepath: P) -> Result<Config> {
     let content = fs::read_to_string(filepath)?;
     let cfg = toml::from_str(&content)?;
+    servers::check_names(&cfg)?;
     Ok(cfg)
 }
@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Result};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A shared destination for command output.
+pub type Sink = Arc<Mutex<dyn Write + Send>>;
+
+/// How the results of commands are written, chosen with `--output`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Their output as it arrives
+    Text,
+    /// A JSON document per command, once it is over
+    Json,
+    /// A JSON line per chunk of output as it arrives, then one per command
+    /// once it is over
+    JsonLines,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::JsonLines),
+            _ => Err(anyhow!(
+                "unknown output format {}, expected text, json or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// Where a command writes its standard and error output.
+///
+/// An `Output` is cheap to clone and can be handed to worker threads; writes
+/// from several threads are serialized on the underlying sinks.
+#[derive(Clone)]
+pub struct Output {
+    out: Sink,
+    err: Sink,
+    /// Where the stderr of jenkins commands goes, with their stdout unless
+    /// captured
+    jenkins_err: Option<Sink>,
+}
+
+impl Output {
+    /// Output going to the process stdout and stderr.
+    pub fn stdio() -> Output {
+        Output {
+            out: Arc::new(Mutex::new(std::io::stdout())),
+            err: Arc::new(Mutex::new(std::io::stderr())),
+            jenkins_err: None,
+        }
+    }
+
+    /// Output collected in memory, both streams interleaved in one buffer.
+    #[cfg(test)]
+    pub fn buffer() -> (Output, Arc<Mutex<Vec<u8>>>) {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let output = Output {
+            out: buf.clone(),
+            err: buf.clone(),
+            jenkins_err: None,
+        };
+        (output, buf)
+    }
+
+    /// Output collected in memory, each stream apart, the stderr of jenkins
+    /// commands included.
+    pub fn capture() -> (Output, Capture) {
+        let capture = Capture {
+            out: Arc::new(Mutex::new(Vec::new())),
+            err: Arc::new(Mutex::new(Vec::new())),
+        };
+        let output = Output::split(capture.out.clone(), capture.err.clone());
+        (output, capture)
+    }
+
+    /// Output collected in memory until written to `self`, each stream
+    /// apart, the stderr of jenkins commands going with their stdout as it
+    /// does in `self`.
+    pub fn hold(&self) -> (Output, Capture) {
+        let capture = Capture {
+            out: Arc::new(Mutex::new(Vec::new())),
+            err: Arc::new(Mutex::new(Vec::new())),
+        };
+        let output = Output {
+            out: capture.out.clone(),
+            err: capture.err.clone(),
+            jenkins_err: self
+                .jenkins_err
+                .as_ref()
+                .map(|_| -> Sink { capture.err.clone() }),
+        };
+        (output, capture)
+    }
+
+    /// Output going to `out` and `err`, the stderr of jenkins commands
+    /// included.
+    pub fn split(out: Sink, err: Sink) -> Output {
+        Output {
+            out,
+            jenkins_err: Some(err.clone()),
+            err,
+        }
+    }
+
+    /// Output writing to the same sinks, with each line prefixed by `prefix`.
+    pub fn prefixed(&self, prefix: &str) -> Output {
+        Output {
+            out: Arc::new(Mutex::new(Prefixer::new(prefix, self.out.clone()))),
+            err: Arc::new(Mutex::new(Prefixer::new(prefix, self.err.clone()))),
+            jenkins_err: self
+                .jenkins_err
+                .as_ref()
+                .map(|sink| -> Sink { Arc::new(Mutex::new(Prefixer::new(prefix, sink.clone()))) }),
+        }
+    }
+
+    /// Runs a local command, forwarding its output, and returns its exit code.
+    pub fn run(&self, command: &mut Command) -> Result<i32> {
+        let mut child = command
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = forward(child.stdout.take().unwrap(), self.out());
+        let stderr = forward(child.stderr.take().unwrap(), self.err());
+        let status = child.wait()?;
+        let _ = stdout.join();
+        let _ = stderr.join();
+        Ok(status.code().unwrap_or(1))
+    }
+
+    pub fn out(&self) -> Writer {
+        Writer(self.out.clone())
+    }
+
+    pub fn err(&self) -> Writer {
+        Writer(self.err.clone())
+    }
+
+    /// The stream the stderr of jenkins commands is written to.
+    pub fn jenkins_err(&self) -> Writer {
+        Writer(self.jenkins_err.as_ref().unwrap_or(&self.out).clone())
+    }
+}
+
+/// The streams collected by an `Output` from `Output::capture`.
+pub struct Capture {
+    out: Arc<Mutex<Vec<u8>>>,
+    err: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Capture {
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.out.lock().unwrap()).into_owned()
+    }
+
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.err.lock().unwrap()).into_owned()
+    }
+
+    /// Writes the streams collected to those of `output`.
+    pub fn write_to(&self, output: &Output) -> std::io::Result<()> {
+        output.out().write_all(&self.out.lock().unwrap())?;
+        output.err().write_all(&self.err.lock().unwrap())
+    }
+}
+
+fn forward<R: Read + Send + 'static>(mut from: R, mut to: Writer) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut from, &mut to);
+    })
+}
+
+/// A `Write` handle on one of the streams of an `Output`.
+pub struct Writer(Sink);
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Buffers written data until a full line is available, then writes it to
+/// the inner sink preceded by a prefix, so that lines coming from several
+/// writers are never mixed.
+struct Prefixer {
+    prefix: String,
+    inner: Sink,
+    line: Vec<u8>,
+}
+
+impl Prefixer {
+    fn new(prefix: &str, inner: Sink) -> Prefixer {
+        Prefixer {
+            prefix: prefix.to_string(),
+            inner,
+            line: Vec::new(),
+        }
+    }
+
+    fn write_line(&mut self, end: usize) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.write_all(self.prefix.as_bytes())?;
+        inner.write_all(&self.line[..end])?;
+        self.line.drain(..end);
+        Ok(())
+    }
+}
+
+impl Write for Prefixer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(pos) = self.line.iter().position(|b| *b == b'\n') {
+            self.write_line(pos + 1)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+impl Drop for Prefixer {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            let end = self.line.len();
+            let _ = self.write_line(end);
+        }
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,121 @@
+use crate::output::Output;
+use crate::Context;
+use anyhow::Result;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const PREFIX: &str = "jk-";
+
+/// Finds the executable `jk-<name>` on `PATH`.
+pub fn find(name: &str) -> Option<PathBuf> {
+    find_in(&std::env::var_os("PATH")?, name)
+}
+
+/// Finds the executable `jk-<name>` in the directories of `paths`, the
+/// first one having it.
+fn find_in(paths: &OsStr, name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains(std::path::is_separator) {
+        return None;
+    }
+    std::env::split_paths(paths)
+        .map(|dir| dir.join(format!("{}{}", PREFIX, name)))
+        .find(|path| is_executable(path))
+}
+
+/// Names of the plugins found on `PATH`.
+pub fn list() -> Vec<String> {
+    match std::env::var_os("PATH") {
+        Some(paths) => list_in(&paths),
+        None => Vec::new(),
+    }
+}
+
+/// Names of the plugins found in the directories of `paths`.
+fn list_in(paths: &OsStr) -> Vec<String> {
+    let mut names: Vec<String> = std::env::split_paths(paths)
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_executable(&entry.path()))
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_prefix(PREFIX).map(|n| n.to_string())
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Runs the plugin at `path` with `args`.
+///
+/// The selected server is described to the plugin by the environment:
+/// `JK_SERVER` (its name in the config), `JK_URL`, `JK_USERNAME`, `JK_TOKEN`
+/// and, when set, `JK_PROXY`.
+pub fn run_plugin(ctx: &Context, path: &Path, args: &[String], output: &Output) -> Result<i32> {
+    let server = ctx.cli.server();
+    let mut command = Command::new(path);
+    command
+        .args(args)
+        .env("JK_SERVER", &ctx.name)
+        .env("JK_URL", &server.url)
+        .env("JK_USERNAME", &server.username)
+        .env("JK_TOKEN", &server.password);
+    match &server.proxy {
+        Some(proxy) => command.env("JK_PROXY", proxy),
+        None => command.env_remove("JK_PROXY"),
+    };
+    output.run(&mut command)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Writes a script at `path`, executable or not.
+    fn script(path: &Path, executable: bool) {
+        fs::write(path, "#!/bin/sh\n").unwrap();
+        let mode = if executable { 0o755 } else { 0o644 };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn finds_plugins_on_path() {
+        let root = std::env::temp_dir().join(format!("jk-plugin-{}", std::process::id()));
+        let (first, second) = (root.join("first"), root.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        script(&first.join("jk-a"), true);
+        script(&first.join("jk-b"), false);
+        script(&first.join("other"), true);
+        script(&second.join("jk-a"), true);
+        script(&second.join("jk-c"), true);
+        let paths = std::env::join_paths([&first, &root.join("missing"), &second]).unwrap();
+
+        // the first directory of the path having it
+        assert_eq!(find_in(&paths, "a"), Some(first.join("jk-a")));
+        assert_eq!(find_in(&paths, "c"), Some(second.join("jk-c")));
+        // not executable
+        assert_eq!(find_in(&paths, "b"), None);
+        assert_eq!(find_in(&paths, ""), None);
+        assert_eq!(find_in(&paths, "../first/jk-a"), None);
+        assert_eq!(list_in(&paths), ["a", "c"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
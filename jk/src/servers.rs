@@ -0,0 +1,283 @@
+use crate::envelope::Envelope;
+use crate::output::Output;
+use crate::{Config, Context};
+use anyhow::{anyhow, Result};
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+
+/// Expands a server selection into server names.
+///
+/// The selection is a comma separated list where each item is a server name,
+/// a group from the `groups` table of the config, or `all`.
+pub fn resolve(config: &Config, selection: &str) -> Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for item in selection
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let expanded = if item == "all" {
+            let mut all: Vec<String> = config.servers.keys().cloned().collect();
+            all.sort();
+            all
+        } else if let Some(members) = config.groups.get(item) {
+            members.clone()
+        } else {
+            vec![item.to_string()]
+        };
+        for name in expanded {
+            if !config.servers.contains_key(&name) {
+                return Err(anyhow!("no server {} found", name));
+            }
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    if names.is_empty() {
+        return Err(anyhow!("no server selected by {:?}", selection));
+    }
+    Ok(names)
+}
+
+/// Checks that the names of servers are not shadowed in selections, by
+/// `all` or by groups.
+pub fn check_names(config: &Config) -> Result<()> {
+    if config.servers.contains_key("all") || config.groups.contains_key("all") {
+        return Err(anyhow!(
+            "all cannot name a server or a group, it selects every server"
+        ));
+    }
+    let mut shadowed: Vec<&String> = config
+        .groups
+        .keys()
+        .filter(|name| config.servers.contains_key(*name))
+        .collect();
+    shadowed.sort();
+    match shadowed.first() {
+        Some(name) => Err(anyhow!("{} names both a server and a group", name)),
+        None => Ok(()),
+    }
+}
+
+/// Runs the same command on several servers in parallel.
+///
+/// Output lines are prefixed by the server name, and a table of exit status
+/// is written to stderr once every server is done.
+pub fn run_on_servers(
+    config: &Arc<Config>,
+    names: &[String],
+    args: &[String],
+    output: &Output,
+) -> Result<i32> {
+    let handles: Vec<_> = names
+        .iter()
+        .map(|name| {
+            let config = config.clone();
+            let name = name.clone();
+            let args = args.to_vec();
+            let output = output.prefixed(&format!("[{}] ", name));
+            thread::spawn(move || -> Result<i32> {
+                let ctx = Context::new(&config, &name)?;
+                crate::run_command(&ctx, &args, &output)
+            })
+        })
+        .collect();
+
+    let results: Vec<Result<i32>> = handles
+        .into_iter()
+        .map(|h| {
+            h.join()
+                .unwrap_or_else(|err| Err(anyhow!("panic while running command: {:?}", err)))
+        })
+        .collect();
+
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0).max(6);
+    let mut err = output.err();
+    writeln!(err, "{:width$}  EXIT", "SERVER", width = width)?;
+    let mut code = 0;
+    for (name, res) in names.iter().zip(results) {
+        match res {
+            Ok(c) => {
+                writeln!(err, "{:width$}  {}", name, c, width = width)?;
+                if c != 0 {
+                    code = 1;
+                }
+            }
+            Err(e) => {
+                writeln!(err, "{:width$}  error: {}", name, e, width = width)?;
+                code = 1;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// Runs the same command on several servers in parallel, writing a JSON
+/// envelope of its result on a line for each server as it is done: a single
+/// JSON document with one server. With `stream`, the output is written
+/// before, as a JSON line for each chunk.
+pub fn run_json(
+    config: &Arc<Config>,
+    names: &[String],
+    args: &[String],
+    stream: bool,
+    output: &Output,
+) -> Result<i32> {
+    let handles: Vec<_> = names
+        .iter()
+        .map(|name| {
+            let config = config.clone();
+            let name = name.clone();
+            let args = args.to_vec();
+            let output = output.clone();
+            thread::spawn(move || -> Result<i32> {
+                let envelope = if stream {
+                    Envelope::stream(&config, &name, &args, &output)
+                } else {
+                    Envelope::run(&config, &name, &args)
+                };
+                let mut out = output.out();
+                // a single write, so that lines are not mixed
+                out.write_all(envelope.to_line().as_bytes())?;
+                Ok(envelope.code())
+            })
+        })
+        .collect();
+
+    let codes = handles
+        .into_iter()
+        .map(|h| {
+            h.join()
+                .unwrap_or_else(|err| Err(anyhow!("panic while running command: {:?}", err)))
+        })
+        .collect::<Result<Vec<i32>>>()?;
+    // as without --output json
+    Ok(match codes[..] {
+        [code] => code,
+        _ => codes.iter().any(|code| *code != 0) as i32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            "default = \"a\"\n\
+             {}\n\
+             [alias]\n\
+             code = \"!echo on $JK_SERVER; [ $JK_SERVER = a ] || exit 3\"\n\
+             [a]\n\
+             url = \"http://a\"\n\
+             username = \"u\"\n\
+             [b]\n\
+             url = \"http://b\"\n\
+             username = \"u\"\n\
+             [c]\n\
+             url = \"http://c\"\n\
+             username = \"u\"\n\
+             proxy = \"http://proxy\"\n\
+             proxy_command = \"nc %h %p\"\n",
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolves_selections() {
+        let config = config("[groups]\nbc = [\"b\", \"c\"]\nba = [\"b\", \"a\"]");
+        let cases: &[(&str, &[&str])] = &[
+            ("a", &["a"]),
+            ("b, a", &["b", "a"]),
+            ("bc", &["b", "c"]),
+            ("a,bc", &["a", "b", "c"]),
+            // each server once, in the order of the selection
+            ("ba,bc,a", &["b", "a", "c"]),
+            ("all", &["a", "b", "c"]),
+            ("c,all", &["c", "a", "b"]),
+        ];
+        for (selection, expected) in cases {
+            assert_eq!(
+                &resolve(&config, selection).unwrap(),
+                expected,
+                "{}",
+                selection
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_selections() {
+        let config = config("[groups]\nbx = [\"b\", \"x\"]");
+        let cases = [
+            ("x", "no server x found"),
+            ("a,x", "no server x found"),
+            ("bx", "no server x found"),
+            ("", "no server selected by \"\""),
+            (" , ", "no server selected by \" , \""),
+        ];
+        for (selection, message) in cases {
+            let err = resolve(&config, selection).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", selection);
+        }
+    }
+
+    #[test]
+    fn rejects_shadowed_names() {
+        assert!(check_names(&config("[groups]\nab = [\"a\", \"b\"]")).is_ok());
+        let cases = [
+            (
+                "[groups]\nall = [\"a\"]",
+                "all cannot name a server or a group, it selects every server",
+            ),
+            (
+                "[groups]\na = [\"a\", \"b\"]",
+                "a names both a server and a group",
+            ),
+            (
+                "[all]\nurl = \"http://all\"\nusername = \"u\"",
+                "all cannot name a server or a group, it selects every server",
+            ),
+        ];
+        for (extra, message) in cases {
+            let err = check_names(&config(extra)).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", extra);
+        }
+    }
+
+    fn run(names: &[&str]) -> (i32, String, String) {
+        let config = Arc::new(config(""));
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let (output, capture) = Output::capture();
+        let code = run_on_servers(&config, &names, &["code".to_string()], &output).unwrap();
+        (code, capture.stdout(), capture.stderr())
+    }
+
+    #[test]
+    fn prefixes_output_by_server() {
+        let (code, stdout, _) = run(&["a", "b"]);
+        assert_eq!(code, 1);
+        let mut lines: Vec<&str> = stdout.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["[a] on a", "[b] on b"]);
+    }
+
+    #[test]
+    fn writes_table_of_exit_codes() {
+        let (code, _, stderr) = run(&["a", "b", "c"]);
+        assert_eq!(code, 1);
+        assert_eq!(
+            stderr,
+            "SERVER  EXIT\n\
+             a       0\n\
+             b       3\n\
+             c       error: proxy and proxy_command cannot be both set\n"
+        );
+        let (code, _, stderr) = run(&["a"]);
+        assert_eq!(code, 0);
+        assert_eq!(stderr, "SERVER  EXIT\na       0\n");
+    }
+}
@@ -0,0 +1,352 @@
+use crate::cache::Cache;
+use crate::complete;
+use crate::jenkins::interrupt;
+use crate::output::Output;
+use crate::tree;
+use crate::{Config, Context};
+use anyhow::{anyhow, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const BUILTINS: &[&str] = &["cd", "each", "exit", "help", "pwd", "quit", "tree", "use"];
+
+/// Commands whose leading positional arguments are job names, with the
+/// number of such arguments (`usize::MAX` when every positional is a job).
+const JOB_ARGS: &[(&str, usize)] = &[
+    ("build", 1),
+    ("console", 1),
+    ("copy-job", 2),
+    ("delete-builds", 1),
+    ("delete-job", usize::MAX),
+    ("disable-job", usize::MAX),
+    ("each", 1),
+    ("enable-job", usize::MAX),
+    ("get-job", 1),
+    ("list-changes", 1),
+    ("list-jobs", 1),
+    ("reload-job", usize::MAX),
+    ("set-build-description", 1),
+    ("set-build-display-name", 1),
+    ("stop-builds", usize::MAX),
+    ("tree", 1),
+    ("update-job", 1),
+];
+
+/// Commands listing the current folder when given no folder.
+const FOLDER_COMMANDS: &[&str] = &["list-jobs", "tree"];
+
+/// Options taking a value, whose value must not be taken for a job name.
+const VALUE_OPTIONS: &[&str] = &["-p", "-r", "-P", "--parallel"];
+
+/// Runs an interactive shell bound to `server`.
+///
+/// Built-ins: `cd <folder>` changes the folder relative job names are
+/// resolved against, `pwd` prints it, `use <server>` switches server and
+/// `exit` leaves the shell. Any other line is run as a jk command.
+pub fn run_shell(config: &Arc<Config>, server: &str) -> Result<i32> {
+    let mut shell = Shell {
+        ctx: Context::new(config, server)?,
+        cwd: String::new(),
+    };
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ShellHelper::new(shell.ctx.clone())));
+    let history = history_file();
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+
+    let output = Output::stdio();
+    loop {
+        let prompt = format!("{}:/{}> ", shell.ctx.name, shell.cwd);
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        let args = match shell_words::split(line) {
+            Ok(args) => args,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                continue;
+            }
+        };
+        match shell.run(&args, &output) {
+            Ok(Action::Exit) => break,
+            Ok(Action::Switched) => {
+                save_cache(editor.helper());
+                editor.set_helper(Some(ShellHelper::new(shell.ctx.clone())));
+            }
+            Ok(Action::Continue) => {}
+            Err(err) => eprintln!("error: {}", err),
+        }
+        // the interrupted command is over, back to the prompt
+        interrupt::reset();
+        if let Some(helper) = editor.helper() {
+            helper.cwd.replace(shell.cwd.clone());
+        }
+    }
+
+    save_cache(editor.helper());
+    if let Some(ref path) = history {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        editor.save_history(path)?;
+    }
+    Ok(0)
+}
+
+enum Action {
+    Continue,
+    Switched,
+    Exit,
+}
+
+struct Shell {
+    ctx: Context,
+    cwd: String,
+}
+
+impl Shell {
+    fn switch(&mut self, server: &str) -> Result<()> {
+        self.ctx = Context::new(&self.ctx.config, server)?;
+        self.cwd = String::new();
+        Ok(())
+    }
+
+    fn run(&mut self, args: &[String], output: &Output) -> Result<Action> {
+        // the total timeout is that of each command
+        self.ctx.cli = self.ctx.cli.restarted();
+        match args[0].as_str() {
+            "exit" | "quit" => return Ok(Action::Exit),
+            "pwd" => writeln!(output.out(), "/{}", self.cwd)?,
+            "use" => {
+                let server = args.get(1).ok_or_else(|| anyhow!("usage: use <server>"))?;
+                self.switch(server)?;
+                return Ok(Action::Switched);
+            }
+            "cd" => {
+                let folder = match args.get(1) {
+                    Some(folder) => resolve_path(&self.cwd, folder),
+                    None => String::new(),
+                };
+                if tree::list_folder(&self.ctx.cli, &folder)?.is_none() {
+                    return Err(anyhow!("{} is not a folder", folder));
+                }
+                self.cwd = folder;
+            }
+            _ => {
+                let args = resolve_args(&self.cwd, args);
+                let code = crate::run_command(&self.ctx, &args, output)?;
+                if code != 0 {
+                    eprintln!("exit code {}", code);
+                }
+            }
+        }
+        Ok(Action::Continue)
+    }
+}
+
+/// Resolves the job names in `args` against the folder `cwd`.
+fn resolve_args(cwd: &str, args: &[String]) -> Vec<String> {
+    let count = JOB_ARGS
+        .iter()
+        .find(|(cmd, _)| *cmd == args[0])
+        .map_or(0, |(_, count)| *count);
+    let mut resolved = Vec::with_capacity(args.len());
+    resolved.push(args[0].clone());
+    let mut remaining = count;
+    let mut option_value = false;
+    for arg in &args[1..] {
+        if option_value {
+            option_value = false;
+            resolved.push(arg.clone());
+        } else if arg.starts_with('-') {
+            option_value = VALUE_OPTIONS.contains(&arg.as_str());
+            resolved.push(arg.clone());
+        } else if remaining > 0 {
+            remaining -= 1;
+            resolved.push(resolve_path(cwd, arg));
+        } else {
+            resolved.push(arg.clone());
+        }
+    }
+    if remaining == count && !cwd.is_empty() && FOLDER_COMMANDS.contains(&args[0].as_str()) {
+        // first, where tree takes it
+        resolved.insert(1, cwd.to_string());
+    }
+    resolved
+}
+
+/// Resolves `path` against `cwd`; paths starting with `/` are absolute.
+pub fn resolve_path(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        cwd.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn save_cache(helper: Option<&ShellHelper>) {
+    if let Some(helper) = helper {
+        if let Err(err) = helper.cache.borrow_mut().save() {
+            eprintln!("error: cannot save cache: {}", err);
+        }
+    }
+}
+
+fn history_file() -> Option<PathBuf> {
+    let mut path = dirs::home_dir()?;
+    path.push(".config/jk/history");
+    Some(path)
+}
+
+/// Completes command names on the first word and job names on the others.
+struct ShellHelper {
+    ctx: Context,
+    cwd: RefCell<String>,
+    cache: RefCell<Cache>,
+}
+
+impl ShellHelper {
+    fn new(ctx: Context) -> ShellHelper {
+        ShellHelper {
+            cache: RefCell::new(Cache::load(&ctx.cli)),
+            ctx,
+            cwd: RefCell::new(String::new()),
+        }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let mut cache = self.cache.borrow_mut();
+        let cli = self.ctx.cli.restarted();
+        let candidates = if before[..start].trim().is_empty() {
+            let local = complete::local_commands(BUILTINS, &self.ctx.config);
+            complete::complete_command(&mut cache, &cli, word, &local)
+        } else {
+            complete::complete_job(&mut cache, &cli, &self.cwd.borrow(), word)
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_paths() {
+        let cases = [
+            ("", "app", "app"),
+            ("f", "app", "f/app"),
+            ("f/g", "app", "f/g/app"),
+            ("f/g", "./app", "f/g/app"),
+            ("f/g", "../app", "f/app"),
+            ("f/g", "../../app", "app"),
+            // no further than the root
+            ("f", "../../app", "app"),
+            ("f/g", "..", "f"),
+            ("f/g", "h/../app", "f/g/app"),
+            ("f/g", "/app", "app"),
+            ("f/g", "/h/app", "h/app"),
+            ("f/g", "/", ""),
+            ("f", "app/", "f/app"),
+            ("f", "a//b", "f/a/b"),
+        ];
+        for (cwd, path, expected) in cases.iter() {
+            assert_eq!(resolve_path(cwd, path), *expected, "{} in {}", path, cwd);
+        }
+    }
+
+    fn resolve(cwd: &str, line: &str) -> String {
+        let args: Vec<String> = line.split(' ').map(str::to_string).collect();
+        resolve_args(cwd, &args).join(" ")
+    }
+
+    #[test]
+    fn resolves_job_arguments() {
+        let cases = [
+            ("f", "build app", "build f/app"),
+            ("f", "build /app", "build app"),
+            ("f", "build ../app", "build app"),
+            // only the leading positional arguments are jobs
+            ("f", "build app extra", "build f/app extra"),
+            ("f", "copy-job a b c", "copy-job f/a f/b c"),
+            ("f", "delete-job a b c", "delete-job f/a f/b f/c"),
+            ("f", "who-am-i app", "who-am-i app"),
+            // the values of options are not jobs
+            ("f", "build -p A=1 app", "build -p A=1 f/app"),
+            ("f", "build -s app", "build -s f/app"),
+            (
+                "f",
+                "each -P 2 app/* -- build",
+                "each -P 2 f/app/* -- build",
+            ),
+            (
+                "f",
+                "each --parallel 2 * -- build",
+                "each --parallel 2 f/* -- build",
+            ),
+            ("f", "console -r 1 app", "console -r 1 f/app"),
+            // folder commands list the current folder by default
+            ("f", "list-jobs", "list-jobs f"),
+            ("f", "tree", "tree f"),
+            ("f", "tree -x", "tree f -x"),
+            ("f", "list-jobs -x", "list-jobs f -x"),
+            ("", "list-jobs", "list-jobs"),
+            ("f", "list-jobs g", "list-jobs f/g"),
+        ];
+        for (cwd, line, expected) in cases.iter() {
+            assert_eq!(resolve(cwd, line), *expected, "{} in {}", line, cwd);
+        }
+    }
+}
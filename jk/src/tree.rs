@@ -0,0 +1,251 @@
+use crate::jenkins;
+use crate::output::Output;
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::{BufRead, Write};
+
+pub fn run_tree_cmd(cli: &jenkins::Cli, args: &[String], output: &Output) -> Result<i32> {
+    let folder = args.first().map(|f| f.as_str());
+    let mut out = output.out();
+    walk(cli, folder, |path| {
+        writeln!(out, "{}", path)?;
+        Ok(())
+    })?;
+    Ok(0)
+}
+
+/// Walks the job tree below `folder` depth-first, calling `visit` with the
+/// path of every job that is not a folder.
+pub fn walk<F>(cli: &jenkins::Cli, folder: Option<&str>, mut visit: F) -> Result<()>
+where
+    F: FnMut(&str) -> Result<()>,
+{
+    let mut lines = list_jobs(cli, folder).map_err(ListJobError::into_inner)?;
+    lines.reverse();
+    let mut stack = Vec::with_capacity(lines.len());
+    stack.append(&mut lines);
+
+    while let Some(folder) = stack.pop() {
+        let content = list_jobs(cli, Some(folder.as_str()));
+
+        match content {
+            Ok(mut subitems) => {
+                subitems.reverse();
+                stack.append(&mut subitems)
+            }
+            Err(err) => match err {
+                ListJobError::NotFolder { path, code: _ } => visit(&path)?,
+                ListJobError::Other(inner_err) => return Err(inner_err),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Lists the names of the items in `folder`, or returns `None` when `folder`
+/// is not a folder.
+pub fn list_folder(cli: &jenkins::Cli, folder: &str) -> Result<Option<Vec<String>>> {
+    let folder = if folder.is_empty() {
+        None
+    } else {
+        Some(folder)
+    };
+    match list_jobs(cli, folder) {
+        Ok(paths) => Ok(Some(
+            paths
+                .iter()
+                .map(|p| p.rsplit('/').next().unwrap_or(p).to_string())
+                .collect(),
+        )),
+        Err(ListJobError::NotFolder { .. }) => Ok(None),
+        Err(ListJobError::Other(err)) => Err(err),
+    }
+}
+
+#[derive(Debug)]
+enum ListJobError {
+    NotFolder { path: String, code: i32 },
+    Other(anyhow::Error),
+}
+
+impl ListJobError {
+    fn into_inner(self) -> anyhow::Error {
+        match self {
+            ListJobError::Other(err) => err,
+            err => err.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ListJobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListJobError::NotFolder { path, code } => {
+                write!(f, "cannot list job on {} (code {})", path, code)
+            }
+            ListJobError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+impl std::error::Error for ListJobError {}
+
+fn list_jobs(
+    cli: &jenkins::Cli,
+    folder: Option<&str>,
+) -> std::result::Result<Vec<String>, ListJobError> {
+    let mut list_args = Vec::with_capacity(2);
+    list_args.push("list-jobs".to_string());
+    if let Some(str) = folder {
+        list_args.push(str.to_string());
+    }
+    let mut resp = cli.send(&list_args[..]).map_err(ListJobError::Other)?;
+    let mut output = BytesMut::new().writer();
+    std::io::copy(resp.output(), &mut output).map_err(|err| ListJobError::Other(err.into()))?;
+
+    let code = resp.wait_exit_code().map_err(ListJobError::Other)?;
+    let folder_base = folder.map_or("", |l| l);
+    if code != 0 {
+        return Err(ListJobError::NotFolder {
+            path: folder_base.to_string(),
+            code,
+        });
+    }
+
+    let input = output.get_mut().reader();
+    Ok(input
+        .lines()
+        .map_while(Result::ok)
+        .map(|l| append_path(folder_base, &l))
+        .collect())
+}
+
+fn append_path(root: &str, end: &str) -> String {
+    if root.ends_with('/') {
+        format!("{}{}", root, end)
+    } else {
+        format!("{}/{}", root, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jenkins::loopback;
+    use crate::jenkins::replay;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn server(url: &str) -> Result<jenkins::Server> {
+        Ok(toml::from_str(&format!(
+            r#"
+            url = "{}"
+            username = "u"
+            password = "s3cr3t"
+            locale = "en"
+            encoding = "UTF-8"
+            [retry]
+            delay = 0
+            "#,
+            url
+        ))?)
+    }
+
+    /// A `Cli` replaying the trace `tests/data/<name>`, recorded with the
+    /// password `s3cr3t` and the locale `en`.
+    fn replay(name: &str) -> Result<jenkins::Cli> {
+        jenkins::Cli::new(server(&format!(
+            "{}{}/tests/data/{}",
+            replay::SCHEME,
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))?)
+    }
+
+    /// A `Cli` whose `list-jobs` lists the children of `folder` in `tree`,
+    /// failing with 3 on jobs.
+    fn loopback(tree: &'static [(&'static str, &'static [&'static str])]) -> jenkins::Cli {
+        let connector = loopback::connector(move |command, controller| {
+            let folder = command.args.get(1).map_or("", String::as_str);
+            match tree.iter().find(|(path, _)| *path == folder) {
+                Some((_, children)) => {
+                    for child in children.iter() {
+                        controller.stdout(format!("{}\n", child).as_bytes())?;
+                    }
+                    controller.exit(0)
+                }
+                None => {
+                    controller.stderr(b"ERROR: not a folder\n")?;
+                    controller.exit(3)
+                }
+            }
+        });
+        jenkins::Cli::with_connector(server("http://jenkins.invalid").unwrap(), connector).unwrap()
+    }
+
+    fn tree(args: &[&str]) -> Result<(i32, String)> {
+        let cli = replay("tree.jsonl")?;
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let (output, buf) = Output::buffer();
+        let code = run_tree_cmd(&cli, &args, &output)?;
+        let text = String::from_utf8(buf.lock().unwrap().clone())?;
+        Ok((code, text))
+    }
+
+    #[test]
+    fn lists_jobs_below_root() {
+        let (code, text) = tree(&[]).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(text, "/a\n/f/app-1\n/f/app-2\n");
+    }
+
+    #[test]
+    fn lists_jobs_below_folder() {
+        let (code, text) = tree(&["/f"]).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(text, "/f/app-1\n/f/app-2\n");
+    }
+
+    #[test]
+    fn fails_on_command_not_recorded() {
+        let err = tree(&["/g"]).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("no recorded session for \"list-jobs /g\""),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn lists_folder() {
+        let cli = loopback(&[("", &["a", "f"]), ("/f", &["app-1", "lib"])]);
+        assert_eq!(
+            list_folder(&cli, "/f").unwrap(),
+            Some(vec!["app-1".to_string(), "lib".to_string()])
+        );
+        assert_eq!(list_folder(&cli, "/a").unwrap(), None);
+    }
+
+    #[test]
+    fn fails_walk_when_connection_is_cut() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let count = attempts.clone();
+        let connector = loopback::connector(move |_, controller| {
+            count.fetch_add(1, Ordering::SeqCst);
+            controller.stdout(b"a\n")
+        });
+        let cli =
+            jenkins::Cli::with_connector(server("http://jenkins.invalid").unwrap(), connector)
+                .unwrap();
+        let mut visited = Vec::new();
+        let err = walk(&cli, None, |path| {
+            visited.push(path.to_string());
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(visited.is_empty());
+        let cause = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(cause.kind(), std::io::ErrorKind::UnexpectedEof);
+        // list-jobs is read-only, so it is tried as often as configured
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,929 @@
+use jenkins_mock::{Fault, Invocation, MockServer, Reply, SocksProxy, SshKey};
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const BUILD_USAGE: &str = "java -jar jenkins-cli.jar build JOB [-s]
+Starts a build.
+ JOB : Name of the job to build
+ -s  : Wait until the completion of the build.
+";
+
+fn mock() -> MockServer {
+    MockServer::builder()
+        .user("u", "p")
+        .user("guest", "g")
+        .forbid("guest")
+        .command("who-am-i", "Reports your credential.", |inv| {
+            Reply::ok(format!("Authenticated as: {}\n", inv.user))
+        })
+        .command("list-jobs", "Lists jobs.", |_| Reply::ok("a\nb\nc\n"))
+        .command("build", "Builds a job.", |inv| {
+            Reply::ok(format!("Started {}\n", inv.args.last().unwrap()))
+        })
+        .usage("build", BUILD_USAGE)
+        .command("fail", "Fails.", |_| Reply::error(5, "failing\n"))
+        .command("slow", "Sleeps.", |_| {
+            Reply::ok("done\n").after(Duration::from_secs(5))
+        })
+        .command("wait", "Waits a little.", |_| {
+            Reply::ok("done\n").after(Duration::from_millis(2500))
+        })
+        .start()
+        .unwrap()
+}
+
+/// jk configured against one server, with a home of its own.
+struct Jk {
+    home: PathBuf,
+}
+
+impl Jk {
+    fn new(url: &str, username: &str, password: &str) -> Jk {
+        Jk::with_config(url, username, password, "")
+    }
+
+    /// jk with `config` added to the section of the server.
+    fn with_config(url: &str, username: &str, password: &str, config: &str) -> Jk {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let home = std::env::temp_dir().join(format!(
+            "jk-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&home).unwrap();
+        fs::write(
+            home.join("jenkins.toml"),
+            format!(
+                "default = \"mock\"\n\
+                 [mock]\n\
+                 url = \"{}\"\n\
+                 username = \"{}\"\n\
+                 password = \"{}\"\n\
+                 {}\n\
+                 [mock.retry]\n\
+                 delay = 10\n",
+                url, username, password, config
+            ),
+        )
+        .unwrap();
+        Jk { home }
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_jk"));
+        command
+            .arg("--config")
+            .arg(self.home.join("jenkins.toml"))
+            .args(args)
+            .env("HOME", &self.home)
+            .env("XDG_RUNTIME_DIR", self.home.join("run"))
+            .env_remove("XDG_CACHE_HOME")
+            .env("LC_ALL", "C.UTF-8")
+            .env_remove("SSH_AUTH_SOCK");
+        command
+    }
+}
+
+impl Drop for Jk {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// The invocations of `command` received by `mock`.
+fn invocations(mock: &MockServer, command: &str) -> Vec<Invocation> {
+    mock.invocations()
+        .into_iter()
+        .filter(|inv| inv.args.first().map(String::as_str) == Some(command))
+        .collect()
+}
+
+#[test]
+fn runs_command_over_http() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let output = jk.run(&["who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Authenticated as: u\n");
+    let sent = invocations(&mock, "who-am-i");
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].endpoint, "http");
+}
+
+#[test]
+fn runs_command_over_websocket() {
+    let mock = mock();
+    let jk = Jk::new(&mock.ws_url(), "u", "p");
+    let output = jk.run(&["build", "-s", "app"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Started app\n");
+    let sent = invocations(&mock, "build");
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].endpoint, "ws");
+    assert_eq!(sent[0].args, ["build", "-s", "app"]);
+}
+
+#[test]
+fn forwards_exit_code_and_stderr() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let output = jk.run(&["fail"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(stdout(&output), "failing\n");
+}
+
+#[test]
+fn sends_locale_and_encoding() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let output = jk.run(&["--locale", "fr_FR", "--encoding", "ISO-8859-1", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let sent = invocations(&mock, "who-am-i");
+    assert_eq!(sent[0].locale.as_deref(), Some("fr_FR"));
+    assert_eq!(sent[0].encoding.as_deref(), Some("windows-1252"));
+}
+
+#[test]
+fn rejects_unknown_command_locally() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let output = jk.run(&["who-am-j"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("unknown command who-am-j, did you mean who-am-i?"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(invocations(&mock, "who-am-j").is_empty());
+}
+
+#[test]
+fn fetches_catalog_missing_command_again() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["who-am-i"]);
+    // list-jobs came after the catalog was cached
+    let cache = fs::read_dir(jk.home.join(".cache/jk"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut content: toml::Value = fs::read_to_string(&cache).unwrap().parse().unwrap();
+    content["catalog"]["commands"]
+        .as_table_mut()
+        .unwrap()
+        .remove("list-jobs");
+    fs::write(&cache, content.to_string()).unwrap();
+
+    let output = jk.run(&["list-jobs"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "a\nb\nc\n");
+    let catalogs = invocations(&mock, "help")
+        .into_iter()
+        .filter(|inv| inv.args.len() == 1)
+        .count();
+    assert_eq!(catalogs, 2);
+}
+
+#[test]
+fn fetches_catalog_without_retries() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let jk = Jk::new(&format!("http://{}", addr), "u", "p");
+    let output = jk
+        .command(&["who-am-i"])
+        .env("RUST_LOG", "warn")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    // only the command is retried, twice
+    assert_eq!(
+        stderr(&output).matches("retrying in").count(),
+        2,
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn rejects_wrong_password() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "wrong");
+    let output = jk.run(&["who-am-i"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("401"), "{}", stderr(&output));
+    assert!(mock.invocations().is_empty());
+}
+
+#[test]
+fn rejects_forbidden_user() {
+    let mock = mock();
+    let jk = Jk::new(&mock.ws_url(), "guest", "g");
+    let output = jk.run(&["who-am-i"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("403"), "{}", stderr(&output));
+    assert!(mock.invocations().is_empty());
+}
+
+#[test]
+fn retries_unavailable_server() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["list-jobs"]);
+    mock.inject(Fault::Status(502));
+    let output = jk.run(&["list-jobs"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "a\nb\nc\n");
+}
+
+#[test]
+fn retries_truncated_read_only_command() {
+    let mock = mock();
+    for url in &[mock.url(), mock.ws_url()] {
+        let jk = Jk::new(url, "u", "p");
+        jk.run(&["list-jobs"]);
+        let before = invocations(&mock, "list-jobs").len();
+        mock.inject(Fault::Truncate(7));
+        let output = jk.run(&["list-jobs"]);
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{}: {}",
+            url,
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), "a\nb\nc\n", "{}", url);
+        assert_eq!(invocations(&mock, "list-jobs").len(), before + 2, "{}", url);
+    }
+}
+
+#[test]
+fn does_not_retry_truncated_build() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["build", "app"]);
+    mock.inject(Fault::Truncate(7));
+    let output = jk.run(&["build", "app"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert_eq!(invocations(&mock, "build").len(), 2);
+}
+
+#[test]
+fn saves_cache_only_when_fetched() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["who-am-i"]);
+    let cache = fs::read_dir(jk.home.join(".cache/jk"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let saved = fs::metadata(&cache).unwrap().modified().unwrap();
+    let output = jk.run(&["who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(invocations(&mock, "help").len(), 2);
+    assert_eq!(fs::metadata(&cache).unwrap().modified().unwrap(), saved);
+}
+
+#[test]
+fn caches_catalog_per_locale() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["who-am-i"]);
+    // help is translated
+    let output = jk.run(&["--locale", "fr_FR", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(invocations(&mock, "help").len(), 4);
+    jk.run(&["who-am-i"]);
+    jk.run(&["--locale", "fr_FR", "who-am-i"]);
+    assert_eq!(invocations(&mock, "help").len(), 4);
+}
+
+#[test]
+fn aborts_slow_command_after_timeout() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["help"]);
+    let start = Instant::now();
+    let output = jk.run(&["--timeout", "1", "slow"]);
+    assert_eq!(output.status.code(), Some(124), "{}", stderr(&output));
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn times_out_over_all_commands_sent() {
+    let mock = mock();
+    let jk = Jk::with_config(
+        &mock.url(),
+        "u",
+        "p",
+        "[alias]\nwaits = [\"wait\", \"wait\"]\n",
+    );
+    let start = Instant::now();
+    // each wait is within the timeout, not both
+    let output = jk.run(&["--timeout", "4", "waits"]);
+    assert_eq!(output.status.code(), Some(124), "{}", stderr(&output));
+    assert!(start.elapsed() < Duration::from_secs(6));
+    assert_eq!(invocations(&mock, "wait").len(), 2);
+}
+
+#[test]
+fn stops_command_on_sigint() {
+    for ws in [false, true].iter() {
+        let mock = mock();
+        let url = if *ws { mock.ws_url() } else { mock.url() };
+        let jk = Jk::new(&url, "u", "p");
+        jk.run(&["help"]);
+        let mut child = jk
+            .command(&["slow"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let start = Instant::now();
+        while invocations(&mock, "slow").is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "slow not sent");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let interrupted = Instant::now();
+        let kill = Command::new("kill")
+            .arg("-INT")
+            .arg(child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(kill.success());
+        let status = child.wait().unwrap();
+        assert_eq!(status.code(), Some(130), "{}", url);
+        // within the grace period, before the command is over
+        assert!(
+            interrupted.elapsed() < Duration::from_millis(3500),
+            "{}",
+            url
+        );
+    }
+}
+
+#[test]
+fn waits_for_delayed_output() {
+    let mock = mock();
+    let jk = Jk::new(&mock.ws_url(), "u", "p");
+    jk.run(&["who-am-i"]);
+    mock.inject(Fault::Delay(Duration::from_millis(500)));
+    let output = jk.run(&["--idle-timeout", "5", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Authenticated as: u\n");
+}
+
+#[test]
+fn pings_during_long_command() {
+    let mock = mock();
+    let jk = Jk::with_config(&mock.ws_url(), "u", "p", "[mock.timeout]\nkeepalive = 1\n");
+    jk.run(&["help"]);
+    let output = jk.run(&["wait"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "done\n");
+    assert!(mock.pings() >= 1);
+}
+
+#[test]
+fn detects_silent_connection() {
+    let mock = mock();
+    let jk = Jk::with_config(&mock.ws_url(), "u", "p", "[mock.timeout]\nkeepalive = 1\n");
+    jk.run(&["help", "slow"]);
+    mock.inject(Fault::Silent);
+    let start = Instant::now();
+    let output = jk.run(&["slow"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("no answer from jenkins to ping for 1s"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn reports_websocket_close() {
+    let mock = mock();
+    let jk = Jk::new(&mock.ws_url(), "u", "p");
+    jk.run(&["build", "app"]);
+    mock.inject(Fault::Close(1008));
+    let output = jk.run(&["build", "app"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("websocket closed by jenkins with code 1008: fault injected"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn retries_read_only_command_on_websocket_restart() {
+    let mock = mock();
+    let jk = Jk::new(&mock.ws_url(), "u", "p");
+    jk.run(&["list-jobs"]);
+    let before = invocations(&mock, "list-jobs").len();
+    mock.inject(Fault::Close(1012));
+    let output = jk.run(&["list-jobs"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "a\nb\nc\n");
+    assert_eq!(invocations(&mock, "list-jobs").len(), before + 2);
+}
+
+#[test]
+fn sends_configured_headers() {
+    let mock = MockServer::builder()
+        .user("u", "p")
+        .require_header("X-Gateway", "s3cr3t")
+        .command("who-am-i", "Reports your credential.", |inv| {
+            Reply::ok(format!("Authenticated as: {}\n", inv.user))
+        })
+        .start()
+        .unwrap();
+    for url in &[mock.url(), mock.ws_url()] {
+        let output = Jk::new(url, "u", "p").run(&["who-am-i"]);
+        assert_eq!(output.status.code(), Some(1), "{}", url);
+        assert!(stderr(&output).contains("403"), "{}", stderr(&output));
+
+        let jk = Jk::with_config(url, "u", "p", "[mock.headers]\nX-Gateway = \"s3cr3t\"\n");
+        let output = jk.run(&["who-am-i"]);
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{}: {}",
+            url,
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), "Authenticated as: u\n");
+    }
+}
+
+#[test]
+fn connects_to_resolved_address() {
+    let mock = mock();
+    let port = mock.addr().port();
+    for scheme in &["http", "ws"] {
+        let url = format!("{}://jenkins.invalid:{}", scheme, port);
+        let resolve = format!("resolve = [\"jenkins.invalid:{}:127.0.0.1\"]", port);
+        let jk = Jk::with_config(&url, "u", "p", &resolve);
+        let output = jk.run(&["who-am-i"]);
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{}: {}",
+            url,
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), "Authenticated as: u\n");
+    }
+}
+
+#[test]
+fn connects_through_proxy_command() {
+    let mock = mock();
+    let port = mock.addr().port();
+    // the host is only known to the command
+    let command =
+        r#"proxy_command = "bash -c 'exec 3<>/dev/tcp/127.0.0.1/%p; cat <&3 & cat >&3; kill $!'""#;
+    for scheme in &["http", "ws"] {
+        let url = format!("{}://jenkins.invalid:{}", scheme, port);
+        let jk = Jk::with_config(&url, "u", "p", command);
+        let output = jk.run(&["who-am-i"]);
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{}: {}",
+            url,
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), "Authenticated as: u\n");
+    }
+}
+
+#[test]
+fn rejects_proxy_with_proxy_command() {
+    let mock = mock();
+    let port = mock.addr().port();
+    let proxy = SocksProxy::start().unwrap();
+    let config = format!(
+        "proxy = \"socks5://{}\"\nproxy_command = \"nc %h %p\"",
+        proxy.addr()
+    );
+    for scheme in &["http", "ws", "ssh"] {
+        let url = format!("{}://127.0.0.1:{}", scheme, port);
+        let output = Jk::with_config(&url, "u", "p", &config).run(&["who-am-i"]);
+        assert_eq!(output.status.code(), Some(1), "{}", url);
+        assert!(
+            stderr(&output).contains("proxy and proxy_command cannot be both set"),
+            "{}: {}",
+            url,
+            stderr(&output)
+        );
+    }
+}
+
+#[test]
+fn connects_through_socks_proxy() {
+    let mock = mock();
+    let port = mock.addr().port();
+    let proxy = SocksProxy::start().unwrap();
+    for (socks, host) in &[("socks5", "127.0.0.1"), ("socks5h", "localhost")] {
+        for scheme in &["http", "ws"] {
+            let url = format!("{}://{}:{}", scheme, host, port);
+            let config = format!("proxy = \"{}://{}\"", socks, proxy.addr());
+            let jk = Jk::with_config(&url, "u", "p", &config);
+            let output = jk.run(&["who-am-i"]);
+            assert_eq!(
+                output.status.code(),
+                Some(0),
+                "{} through {}: {}",
+                url,
+                socks,
+                stderr(&output)
+            );
+            assert_eq!(stdout(&output), "Authenticated as: u\n");
+            // the host is resolved by the proxy with socks5h
+            let target = format!("{}:{}", host, port);
+            assert_eq!(proxy.targets().last(), Some(&target));
+        }
+    }
+}
+
+#[test]
+fn authenticates_to_socks_proxy() {
+    let mock = mock();
+    let proxy = SocksProxy::with_password("dev", "s3cr@t").unwrap();
+    for scheme in &["http", "ws"] {
+        let url = format!("{}://{}", scheme, mock.addr());
+        let config = format!("proxy = \"socks5://dev:wrong@{}\"", proxy.addr());
+        let output = Jk::with_config(&url, "u", "p", &config).run(&["who-am-i"]);
+        assert_ne!(output.status.code(), Some(0), "{}", url);
+        let config = format!("proxy = \"socks5://dev:s3cr%40t@{}\"", proxy.addr());
+        let output = Jk::with_config(&url, "u", "p", &config).run(&["who-am-i"]);
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{}: {}",
+            url,
+            stderr(&output)
+        );
+    }
+}
+
+/// A mock authorizing `key` for `u` over SSH, with a job in a folder.
+fn ssh_mock(key: &SshKey) -> MockServer {
+    MockServer::builder()
+        .ssh_key("u", &key.public())
+        .command("who-am-i", "Reports your credential.", |inv| {
+            Reply::ok(format!("Authenticated as: {}\n", inv.user))
+        })
+        .command("list-jobs", "Lists jobs.", |inv| {
+            match inv.args.get(1).map(|f| f.trim_start_matches('/')) {
+                None => Reply::ok("folder\njob\n"),
+                Some("folder") => Reply::ok("inner\n"),
+                Some(_) => Reply::error(3, "not a folder\n"),
+            }
+        })
+        .command("fail", "Fails.", |_| Reply::error(5, "failing\n"))
+        .command("warn", "Warns a lot after its output.", |_| {
+            Reply::ok("done\n").with_stderr("warning\n".repeat(8 * 1024))
+        })
+        .start()
+        .unwrap()
+}
+
+/// jk reaching `mock` over SSH with `config`, which knows its host key and
+/// has `key` in `~/.ssh/id_ed25519`.
+fn ssh_jk(mock: &MockServer, key: &SshKey, config: &str) -> Jk {
+    let jk = Jk::with_config(&mock.ssh_url(), "u", "", config);
+    let ssh = jk.home.join(".ssh");
+    fs::create_dir_all(&ssh).unwrap();
+    fs::write(
+        ssh.join("known_hosts"),
+        format!(
+            "[127.0.0.1]:{} {}\n",
+            mock.ssh_addr().port(),
+            mock.ssh_host_key()
+        ),
+    )
+    .unwrap();
+    fs::write(ssh.join("id_ed25519"), key.private()).unwrap();
+    jk
+}
+
+#[test]
+fn runs_command_over_ssh() {
+    let key = SshKey::generate();
+    let mock = ssh_mock(&key);
+    let jk = ssh_jk(&mock, &key, "identity = \"~/.ssh/id_ed25519\"");
+    let output = jk.run(&["who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Authenticated as: u\n");
+    let sent = invocations(&mock, "who-am-i");
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].endpoint, "ssh");
+
+    let output = jk.run(&["fail"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(stdout(&output), "failing\n");
+
+    // stderr is read to its end after stdout
+    let output = jk.run(&["--output", "json", "warn"]);
+    let envelope = &json_lines(&output)[0];
+    assert_eq!(envelope["exit_code"], 0, "{}", stderr(&output));
+    assert_eq!(envelope["stdout"], "done\n");
+    assert_eq!(envelope["stderr"], "warning\n".repeat(8 * 1024));
+
+    let output = jk.run(&["tree"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "/folder/inner\n/job\n");
+}
+
+#[test]
+fn rejects_unknown_ssh_host_or_key() {
+    let key = SshKey::generate();
+    let mock = ssh_mock(&key);
+    let config = "identity = \"~/.ssh/id_ed25519\"";
+    let jk = ssh_jk(&mock, &key, config);
+    fs::remove_file(jk.home.join(".ssh/known_hosts")).unwrap();
+    let output = jk.run(&["who-am-i"]);
+    assert_ne!(output.status.code(), Some(0));
+    assert!(stderr(&output).contains("unknown"), "{}", stderr(&output));
+
+    let jk = ssh_jk(&mock, &SshKey::generate(), config);
+    let output = jk.run(&["who-am-i"]);
+    assert_ne!(output.status.code(), Some(0));
+    assert!(
+        stderr(&output).contains("authenticate"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(invocations(&mock, "who-am-i").is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn authenticates_over_ssh_with_agent() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let key = SshKey::generate();
+    let mock = ssh_mock(&key);
+    let jk = ssh_jk(&mock, &key, "");
+    let identity = jk.home.join(".ssh/id_ed25519");
+    fs::set_permissions(&identity, fs::Permissions::from_mode(0o600)).unwrap();
+    let socket = jk.home.join("agent.sock");
+    let mut agent = Command::new("ssh-agent")
+        .arg("-D")
+        .arg("-a")
+        .arg(&socket)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    while !socket.exists() && start.elapsed() < Duration::from_secs(5) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let added = Command::new("ssh-add")
+        .arg(&identity)
+        .env("SSH_AUTH_SOCK", &socket)
+        .output()
+        .unwrap();
+    let output = jk
+        .command(&["who-am-i"])
+        .env("SSH_AUTH_SOCK", &socket)
+        .output()
+        .unwrap();
+    let _ = agent.kill();
+    let _ = agent.wait();
+    assert!(added.status.success(), "{}", stderr(&added));
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Authenticated as: u\n");
+}
+
+/// The JSON document written by jk on each line of `output`.
+#[test]
+fn expands_aliases_within_aliases() {
+    let mock = mock();
+    let jk = Jk::with_config(
+        &mock.url(),
+        "u",
+        "p",
+        "[alias]\n\
+         me = \"whoami\"\n\
+         whoami = \"who-am-i\"\n\
+         who-am-i = [\"who-am-i\", \"hello\"]\n\
+         hello = \"!echo hello\"\n",
+    );
+    let output = jk.run(&["me"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    // who-am-i is not expanded within itself: it runs the command it is
+    // named after
+    assert_eq!(stdout(&output), "Authenticated as: u\nhello\n");
+    assert_eq!(invocations(&mock, "who-am-i").len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_plugin_before_remote_command() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mock = mock();
+    let jk = Jk::with_config(&mock.url(), "u", "p", "proxy = \"http://proxy:3128\"");
+    let bin = jk.home.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    let plugin = bin.join("jk-who-am-i");
+    fs::write(
+        &plugin,
+        "#!/bin/sh\n\
+         echo \"$JK_SERVER $JK_URL $JK_USERNAME $JK_TOKEN $JK_PROXY\"\n\
+         echo \"$@\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = std::env::join_paths(
+        std::iter::once(bin).chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+
+    let output = jk
+        .command(&["who-am-i", "a", "b c"])
+        .env("PATH", &path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        format!("mock {} u p http://proxy:3128\na b c\n", mock.url())
+    );
+    assert!(invocations(&mock, "who-am-i").is_empty());
+
+    // the catalog is not fetched through the proxy
+    let direct = Jk::new(&mock.url(), "u", "p");
+    let output = direct
+        .command(&["help"])
+        .env("PATH", &path)
+        .output()
+        .unwrap();
+    assert!(
+        stdout(&output).ends_with("\nPlugins:\n  who-am-i\n"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn groups_output_of_each_job_apart_from_errors() {
+    let mock = mock();
+    let jk = Jk::with_config(
+        &mock.url(),
+        "u",
+        "p",
+        "[alias]\nboth = \"!echo out $1; echo err $1 >&2\"\n",
+    );
+    let output = jk.run(&["each", "--group", "a/b", "--", "both"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "==> a/b <==\nout a/b\n");
+    assert_eq!(stderr(&output), "err a/b\n");
+}
+
+fn json_lines(output: &Output) -> Vec<serde_json::Value> {
+    stdout(output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn writes_json_envelope() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let output = jk.run(&["--output", "json", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let envelope: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(envelope["server"], "mock");
+    assert_eq!(envelope["args"], serde_json::json!(["who-am-i"]));
+    assert_eq!(envelope["stdout"], "Authenticated as: u\n");
+    assert_eq!(envelope["stderr"], "");
+    assert_eq!(envelope["exit_code"], 0);
+    assert!(envelope["duration"].as_f64().unwrap() > 0.0);
+    assert!(envelope["error"].is_null());
+
+    // the stderr of jenkins is kept apart
+    let output = jk.run(&["--output", "json", "fail"]);
+    assert_eq!(output.status.code(), Some(5));
+    let envelope: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(envelope["stdout"], "");
+    assert_eq!(envelope["stderr"], "failing\n");
+    assert_eq!(envelope["exit_code"], 5);
+}
+
+#[test]
+fn classifies_errors_in_json_envelope() {
+    let mock = mock();
+    let output = Jk::new(&mock.url(), "guest", "g").run(&["--output", "json", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(1));
+    let envelope = &json_lines(&output)[0];
+    assert!(envelope["exit_code"].is_null());
+    assert_eq!(envelope["error"]["kind"], "http", "{}", envelope);
+    assert_eq!(envelope["error"]["status"], 403);
+
+    let jk = Jk::new(&mock.url(), "u", "p");
+    jk.run(&["help"]);
+    let output = jk.run(&["--output", "json", "--timeout", "1", "slow"]);
+    assert_eq!(output.status.code(), Some(124));
+    assert_eq!(json_lines(&output)[0]["error"]["kind"], "timeout");
+
+    // nothing listens on the port of a dropped mock
+    let url = {
+        let gone = MockServer::builder().start().unwrap();
+        gone.url()
+    };
+    let output = Jk::new(&url, "u", "p").run(&["--output", "json", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(1));
+    let envelope = &json_lines(&output)[0];
+    assert_eq!(envelope["error"]["kind"], "transient", "{}", envelope);
+    assert!(!envelope["error"]["message"].as_str().unwrap().is_empty());
+}
+
+#[test]
+fn writes_json_line_per_server() {
+    let mock = mock();
+    let other = format!(
+        "[other]\nurl = \"{}\"\nusername = \"guest\"\npassword = \"g\"",
+        mock.ws_url()
+    );
+    let jk = Jk::with_config(&mock.url(), "u", "p", &other);
+    let output = jk.run(&["--jenkins", "mock,other", "--output", "json", "who-am-i"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    let mut lines = json_lines(&output);
+    lines.sort_by_key(|line| line["server"].as_str().unwrap().to_string());
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["server"], "mock");
+    assert_eq!(lines[0]["stdout"], "Authenticated as: u\n");
+    assert_eq!(lines[1]["server"], "other");
+    assert_eq!(lines[1]["error"]["kind"], "http");
+}
+
+#[test]
+fn streams_json_lines() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let output = jk.run(&["--output", "jsonl", "fail"]);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    let lines = json_lines(&output);
+    let (envelope, chunks) = lines.split_last().unwrap();
+    let data: String = chunks
+        .iter()
+        .map(|chunk| {
+            assert_eq!(chunk["server"], "mock");
+            assert_eq!(chunk["stream"], "stderr", "{}", chunk);
+            chunk["data"].as_str().unwrap()
+        })
+        .collect();
+    assert_eq!(data, "failing\n");
+    // the output is not repeated
+    assert!(envelope.get("stdout").is_none(), "{}", envelope);
+    assert!(envelope.get("stderr").is_none(), "{}", envelope);
+    assert_eq!(envelope["exit_code"], 5);
+}
+
+#[test]
+fn completes_after_output_flag() {
+    let mock = mock();
+    let jk = Jk::new(&mock.url(), "u", "p");
+    let config = jk.home.join("jenkins.toml");
+    let config = config.to_str().unwrap();
+    let output = jk.run(&["__complete", "--", "--out"]);
+    assert_eq!(stdout(&output), "--output\n");
+    // the value of --output is not the command
+    let output = jk.run(&[
+        "__complete",
+        "--",
+        "--config",
+        config,
+        "--output",
+        "json",
+        "wh",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "who-am-i\n");
+}